@@ -0,0 +1,504 @@
+use lex::{self, LexErr};
+use parse::{Node, NodeType, NTermType, ParseErr};
+use token::{Span, Token, TokenType};
+
+type LowerRes<T> = Result<T, ParseErr>;
+
+/// A type, as written in a declaration
+#[derive(Eq, PartialEq, Debug, Clone, Hash)]
+pub enum Type {
+    Int, Float, String, Bool,
+    /// The 'type' of a call to a function which returns nothing. Can't be
+    /// written in source.
+    Void,
+    /// An array of the given element type, e.g. `int[]`
+    Array(Box<Type>),
+    /// A struct, by name
+    Struct(String),
+}
+
+impl Type {
+    pub fn name(&self) -> String {
+        match *self {
+            Type::Int => "int".to_owned(),
+            Type::Float => "float".to_owned(),
+            Type::String => "string".to_owned(),
+            Type::Bool => "bool".to_owned(),
+            Type::Void => "void".to_owned(),
+            Type::Array(ref elem) => format!("{}[]", elem.name()),
+            Type::Struct(ref name) => name.clone(),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
+pub enum BinOp {
+    Mul, Div, Mod,
+    Add, Sub,
+    Eq, Ne, Gt, Lt, Ge, Le,
+    /// `&&` and `||` short circuit: every backend must only evaluate the rhs
+    /// if the lhs doesn't decide the result
+    And, Or,
+}
+
+impl BinOp {
+    fn from_str(val: &str) -> Option<BinOp> {
+        Some(match val {
+            "*" => BinOp::Mul,
+            "/" => BinOp::Div,
+            "%" => BinOp::Mod,
+            "+" => BinOp::Add,
+            "-" => BinOp::Sub,
+            "==" => BinOp::Eq,
+            "!=" => BinOp::Ne,
+            ">" => BinOp::Gt,
+            "<" => BinOp::Lt,
+            ">=" => BinOp::Ge,
+            "<=" => BinOp::Le,
+            "&&" => BinOp::And,
+            "||" => BinOp::Or,
+            _ => return None,
+        })
+    }
+
+    /// The operator as written in source
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Gt => ">",
+            BinOp::Lt => "<",
+            BinOp::Ge => ">=",
+            BinOp::Le => "<=",
+            BinOp::And => "&&",
+            BinOp::Or => "||",
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum UnOp {
+    Neg, Not,
+}
+
+impl UnOp {
+    fn from_str(val: &str) -> Option<UnOp> {
+        Some(match val {
+            "-" => UnOp::Neg,
+            "!" => UnOp::Not,
+            _ => return None,
+        })
+    }
+
+    /// The operator as written in source
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            UnOp::Neg => "-",
+            UnOp::Not => "!",
+        }
+    }
+}
+
+/// A name, along with where it was written
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Ident {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Lit {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Expr {
+    Var { name: Ident },
+    Lit { val: Lit, span: Span },
+    Unary { op: UnOp, op_span: Span, operand: Box<Expr>, span: Span },
+    Binary { op: BinOp, op_span: Span, lhs: Box<Expr>, rhs: Box<Expr>, span: Span },
+    Call { callee: Ident, args: Vec<Expr>, span: Span },
+    /// An array literal, e.g. `[1, 2, 3]`
+    Array { elems: Vec<Expr>, span: Span },
+    /// An element of an array, e.g. `a[i]`
+    Index { base: Box<Expr>, index: Box<Expr>, span: Span },
+    /// A struct literal, e.g. `Point { x: 1, y: 2 }`. Fields are in source
+    /// order.
+    StructLit { name: Ident, fields: Vec<(Ident, Expr)>, span: Span },
+    /// A field of a struct, e.g. `p.x`
+    Field { base: Box<Expr>, field: Ident, span: Span },
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match *self {
+            Expr::Var { ref name } => name.span,
+            Expr::Lit { span, .. } | Expr::Unary { span, .. } |
+            Expr::Binary { span, .. } | Expr::Call { span, .. } |
+            Expr::Array { span, .. } | Expr::Index { span, .. } |
+            Expr::StructLit { span, .. } | Expr::Field { span, .. } => span,
+        }
+    }
+}
+
+pub type Block = Vec<Stmt>;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Param {
+    pub ty: Type,
+    pub name: Ident,
+}
+
+/// A function definition. `ret` is Void if no return type was given.
+#[derive(PartialEq, Debug, Clone)]
+pub struct FnDef {
+    pub name: Ident,
+    pub params: Vec<Param>,
+    pub ret: Type,
+    pub body: Block,
+    pub span: Span,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct FieldDef {
+    pub ty: Type,
+    pub name: Ident,
+}
+
+/// A struct definition. Fields are laid out in the order they're declared.
+#[derive(PartialEq, Debug, Clone)]
+pub struct StructDef {
+    pub name: Ident,
+    pub fields: Vec<FieldDef>,
+    pub span: Span,
+}
+
+/// One `if (cond) { body }` or `else if (cond) { body }` in an if chain
+#[derive(PartialEq, Debug, Clone)]
+pub struct IfArm {
+    pub cond: Expr,
+    pub body: Block,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Stmt {
+    Declare { ty: Type, name: Ident, init: Expr, span: Span },
+    /// Assigns to target, which is a Var, Index or Field. val is evaluated
+    /// first.
+    Assign { target: Expr, val: Expr, span: Span },
+    /// An expression evaluated for its side effects, i.e. a function call
+    Expr { expr: Expr, span: Span },
+    /// An if, followed by any number of else ifs, then an optional else. Arms
+    /// are tried in order.
+    If { arms: Vec<IfArm>, else_body: Option<Block>, span: Span },
+    While { cond: Expr, body: Block, span: Span },
+    /// A C-style for loop. A missing cond is always true. init is scoped to
+    /// the loop.
+    For {
+        init: Option<Box<Stmt>>,
+        cond: Option<Expr>,
+        step: Option<Box<Stmt>>,
+        body: Block,
+        span: Span,
+    },
+    Break { span: Span },
+    Continue { span: Span },
+    Fn { def: FnDef },
+    Struct { def: StructDef },
+    Return { val: Option<Expr>, span: Span },
+}
+
+impl Stmt {
+    pub fn span(&self) -> Span {
+        match *self {
+            Stmt::Declare { span, .. } | Stmt::Assign { span, .. } | Stmt::Expr { span, .. } |
+            Stmt::If { span, .. } | Stmt::While { span, .. } | Stmt::For { span, .. } |
+            Stmt::Break { span } | Stmt::Continue { span } | Stmt::Return { span, .. } => span,
+            Stmt::Fn { ref def } => def.span,
+            Stmt::Struct { ref def } => def.span,
+        }
+    }
+}
+
+/// Returns the token of a terminal node. Panics if the node is a nonterminal,
+/// which can only happen if the parser produced a malformed tree.
+fn tok_of(node: &Node) -> Token {
+    match node.node_type {
+        NodeType::Term(tok) => tok,
+        NodeType::NTerm(ref t) => panic!("Expected terminal, got {:?}", t),
+    }
+}
+
+/// Returns the nonterminal type of a node, or None for terminals
+fn nterm_of(node: &Node) -> Option<&NTermType> {
+    match node.node_type {
+        NodeType::NTerm(ref t) => Some(t),
+        NodeType::Term(_) => None,
+    }
+}
+
+fn ident(tok: Token, src: &str) -> Ident {
+    Ident { name: tok.val(src).to_owned(), span: tok.span() }
+}
+
+fn lower_type(node: &Node, src: &str) -> Type {
+    let base = tok_of(&node.children[0]);
+    let core = match base.val(src) {
+        _ if base.token_type == TokenType::Ident => Type::Struct(base.val(src).to_owned()),
+        "int" => Type::Int,
+        "float" => Type::Float,
+        "string" => Type::String,
+        "bool" => Type::Bool,
+        t => panic!("Unknown core type {}", t),
+    };
+    // Each `[]` is 2 children
+    (0..(node.children.len() - 1) / 2).fold(core, |ty, _| Type::Array(Box::new(ty)))
+}
+
+/// Bad literals are reported by the lexer, so decoding them again while
+/// lowering only fails if lexing errors were ignored
+fn lex_err_to_parse_err(e: LexErr) -> ParseErr {
+    match e {
+        LexErr::Raw(msg) => ParseErr::Raw(msg),
+        LexErr::Point(msg, span) => ParseErr::Point(msg, span),
+    }
+}
+
+fn lower_lit(tok: Token, src: &str) -> LowerRes<Expr> {
+    let val = tok.val(src);
+    let lit = match tok.token_type {
+        TokenType::StringLit => Lit::Str(lex::decode_string_lit(val, tok.start.0)
+                                         .map_err(lex_err_to_parse_err)?),
+        TokenType::IntLit => Lit::Int(lex::decode_int_lit(val, tok.start.0)
+                                      .map_err(lex_err_to_parse_err)?),
+        TokenType::FloatLit => Lit::Float(lex::decode_float_lit(val, tok.start.0)
+                                          .map_err(lex_err_to_parse_err)?),
+        TokenType::BoolLit => Lit::Bool(val == "true"),
+        t => panic!("Unexpected literal token {:?}", t),
+    };
+    Ok(Expr::Lit { val: lit, span: tok.span() })
+}
+
+/// Lowers the expressions in a ParameterList, skipping the commas
+fn lower_expr_list(node: &Node, src: &str) -> LowerRes<Vec<Expr>> {
+    node.children.iter()
+        .filter(|c| nterm_of(c).is_some())
+        .map(|c| lower_expr(c, src))
+        .collect()
+}
+
+fn lower_call(node: &Node, src: &str) -> LowerRes<Expr> {
+    let callee = ident(tok_of(&node.children[0]), src);
+    let args = lower_expr_list(&node.children[2], src)?;
+    let span = callee.span.to(tok_of(&node.children[3]).span());
+    Ok(Expr::Call { callee, args, span })
+}
+
+fn lower_expr(node: &Node, src: &str) -> LowerRes<Expr> {
+    match nterm_of(node) {
+        Some(&NTermType::Term0) | Some(&NTermType::Term1) |
+        Some(&NTermType::Term2) | Some(&NTermType::Term3) | Some(&NTermType::Expression) => {
+            let lhs = lower_expr(&node.children[0], src)?;
+            if node.children.len() == 1 {
+                return Ok(lhs);
+            }
+            let op_tok = tok_of(&node.children[1]);
+            let rhs = lower_expr(&node.children[2], src)?;
+            Ok(Expr::Binary {
+                op: BinOp::from_str(op_tok.val(src)).expect("Unknown binary operator"),
+                op_span: op_tok.span(),
+                span: lhs.span().to(rhs.span()),
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            })
+        }
+        Some(&NTermType::Unary) if node.children.len() == 2 => {
+            let op_tok = tok_of(&node.children[0]);
+            let operand = lower_expr(&node.children[1], src)?;
+            Ok(Expr::Unary {
+                op: UnOp::from_str(op_tok.val(src)).expect("Unknown unary operator"),
+                op_span: op_tok.span(),
+                span: op_tok.span().to(operand.span()),
+                operand: Box::new(operand),
+            })
+        }
+        // Parenthesised expression
+        Some(&NTermType::Atom) if node.children.len() == 3 => lower_expr(&node.children[1], src),
+        Some(&NTermType::Unary) | Some(&NTermType::Atom) => lower_expr(&node.children[0], src),
+        Some(&NTermType::FunctionCall) => lower_call(node, src),
+        Some(&NTermType::ArrayLiteral) => Ok(Expr::Array {
+            elems: lower_expr_list(&node.children[1], src)?,
+            span: tok_of(&node.children[0]).span().to(tok_of(&node.children[2]).span()),
+        }),
+        Some(&NTermType::StructLiteral) => {
+            // Children are `{`, then `field : value ,` repeated, then `}`
+            let fields = node.children[2..node.children.len() - 1].chunks(4)
+                .map(|f| Ok((ident(tok_of(&f[0]), src), lower_expr(&f[2], src)?)))
+                .collect::<LowerRes<_>>()?;
+            Ok(Expr::StructLit {
+                name: ident(tok_of(&node.children[0]), src),
+                fields,
+                span: tok_of(&node.children[0]).span()
+                    .to(tok_of(node.children.last().unwrap()).span()),
+            })
+        }
+        Some(&NTermType::Field) => {
+            let base = lower_expr(&node.children[0], src)?;
+            let field = ident(tok_of(&node.children[2]), src);
+            Ok(Expr::Field { span: base.span().to(field.span), base: Box::new(base), field })
+        }
+        Some(&NTermType::Index) => {
+            let base = lower_expr(&node.children[0], src)?;
+            Ok(Expr::Index {
+                span: base.span().to(tok_of(&node.children[3]).span()),
+                base: Box::new(base),
+                index: Box::new(lower_expr(&node.children[2], src)?),
+            })
+        }
+        None => {
+            let tok = tok_of(node);
+            match tok.token_type {
+                TokenType::Ident => Ok(Expr::Var { name: ident(tok, src) }),
+                _ => lower_lit(tok, src),
+            }
+        }
+        t => panic!("Unexpected node in expression: {:?}", t),
+    }
+}
+
+fn lower_stmt(node: &Node, src: &str) -> LowerRes<Stmt> {
+    let first = &node.children[0];
+    let last = node.children.last().unwrap();
+    match nterm_of(node) {
+        Some(&NTermType::Stmt) => lower_stmt(first, src),
+        Some(&NTermType::Declaration) => {
+            let init = lower_expr(&node.children[3], src)?;
+            Ok(Stmt::Declare {
+                ty: lower_type(first, src),
+                name: ident(tok_of(&node.children[1]), src),
+                span: tok_of(&first.children[0]).span().to(init.span()),
+                init,
+            })
+        }
+        Some(&NTermType::Assignment) => {
+            let target = lower_expr(first, src)?;
+            let val = lower_expr(&node.children[2], src)?;
+            Ok(Stmt::Assign { span: target.span().to(val.span()), target, val })
+        }
+        Some(&NTermType::FunctionCall) => {
+            let expr = lower_call(node, src)?;
+            Ok(Stmt::Expr { span: expr.span(), expr })
+        }
+        Some(&NTermType::If) => {
+            // Each condition is followed by its body. A body with no
+            // condition is the final else.
+            let mut arms = Vec::new();
+            let mut else_body = None;
+            let mut cond = None;
+            for child in &node.children {
+                match nterm_of(child) {
+                    Some(&NTermType::Program) => match cond.take() {
+                        Some(cond) => arms.push(IfArm { cond, body: lower_block(child, src)? }),
+                        None => else_body = Some(lower_block(child, src)?),
+                    },
+                    Some(_) => cond = Some(lower_expr(child, src)?),
+                    None => (),
+                }
+            }
+            Ok(Stmt::If { arms, else_body, span: tok_of(first).span().to(tok_of(last).span()) })
+        }
+        Some(&NTermType::FunctionDef) => {
+            let mut params = Vec::new();
+            // Children are `Type Ident ,` repeated, without the final comma
+            for param in node.children[3].children.chunks(3) {
+                params.push(Param {
+                    ty: lower_type(&param[0], src),
+                    name: ident(tok_of(&param[1]), src),
+                });
+            }
+            let ret = match node.children[5].node_type {
+                NodeType::Term(tok) if tok.val(src) == "->" =>
+                    lower_type(&node.children[6], src),
+                _ => Type::Void,
+            };
+            Ok(Stmt::Fn { def: FnDef {
+                name: ident(tok_of(&node.children[1]), src),
+                params,
+                ret,
+                body: lower_block(&node.children[node.children.len() - 2], src)?,
+                span: tok_of(first).span().to(tok_of(last).span()),
+            }})
+        }
+        Some(&NTermType::StructDef) => {
+            // Children are `Type field ;` repeated between the braces
+            let fields = node.children[3..node.children.len() - 1].chunks(3)
+                .map(|f| FieldDef { ty: lower_type(&f[0], src), name: ident(tok_of(&f[1]), src) })
+                .collect();
+            Ok(Stmt::Struct { def: StructDef {
+                name: ident(tok_of(&node.children[1]), src),
+                fields,
+                span: tok_of(first).span().to(tok_of(last).span()),
+            }})
+        }
+        Some(&NTermType::Return) => {
+            let val = match node.children.get(1) {
+                Some(val) => Some(lower_expr(val, src)?),
+                None => None,
+            };
+            Ok(Stmt::Return {
+                span: val.as_ref().map_or(tok_of(first).span(), |v| tok_of(first).span().to(v.span())),
+                val,
+            })
+        }
+        Some(&NTermType::While) => Ok(Stmt::While {
+            cond: lower_expr(&node.children[2], src)?,
+            body: lower_block(&node.children[5], src)?,
+            span: tok_of(first).span().to(tok_of(last).span()),
+        }),
+        Some(&NTermType::For) => {
+            // Which part of the header we're in is given by how many `;` we've
+            // passed, since every part is optional
+            let (mut init, mut cond, mut step, mut body) = (None, None, None, Vec::new());
+            let mut semicolons = 0;
+            for child in &node.children {
+                match (nterm_of(child), semicolons) {
+                    (None, _) if tok_of(child).val(src) == ";" => semicolons += 1,
+                    (None, _) => (),
+                    (Some(&NTermType::Program), _) => body = lower_block(child, src)?,
+                    (Some(_), 0) => init = Some(Box::new(lower_stmt(child, src)?)),
+                    (Some(_), 1) => cond = Some(lower_expr(child, src)?),
+                    (Some(_), _) => step = Some(Box::new(lower_stmt(child, src)?)),
+                }
+            }
+            Ok(Stmt::For { init, cond, step, body,
+                           span: tok_of(first).span().to(tok_of(last).span()) })
+        }
+        Some(&NTermType::LoopControl) => {
+            let span = tok_of(first).span();
+            Ok(if tok_of(first).val(src) == "break" {
+                Stmt::Break { span }
+            } else {
+                Stmt::Continue { span }
+            })
+        }
+        t => panic!("Unexpected node in program: {:?}", t),
+    }
+}
+
+fn lower_block(node: &Node, src: &str) -> LowerRes<Block> {
+    debug_assert_eq!(nterm_of(node), Some(&NTermType::Program));
+    node.children.iter().map(|c| lower_stmt(c, src)).collect()
+}
+
+/// Lowers a parse tree (rooted at a Program node) into an AST
+pub fn lower(program: &Node, src: &str) -> LowerRes<Block> {
+    lower_block(program, src)
+}
@@ -0,0 +1,174 @@
+use ast::{BinOp, UnOp};
+use interpret::Value;
+use source::SourceMap;
+use token::Span;
+use std::fmt::Write;
+
+/// A single VM instruction. Operands are indices into the enclosing
+/// function's constants or locals, the program's functions or struct layouts,
+/// or instruction offsets for jumps.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Op {
+    /// Push a constant
+    Const(u32),
+    /// Discard the top of the stack
+    Pop,
+    /// Push a copy of the top of the stack
+    Dup,
+    /// Push the value of a local slot
+    GetLocal(u16),
+    /// Pop a value into a local slot
+    SetLocal(u16),
+    Unary(UnOp),
+    /// Pop rhs then lhs, and push the result. `&&` and `||` are compiled to
+    /// jumps instead, to short circuit.
+    Binary(BinOp),
+    /// Jump to an instruction offset
+    Jump(u32),
+    /// Pop a bool, and jump if it's false
+    JumpIfFalse(u32),
+    /// Pop a bool, and jump if it's true
+    JumpIfTrue(u32),
+    /// Call a function with the given number of arguments on the stack
+    Call(u32, u32),
+    /// Call a builtin with the given number of arguments on the stack
+    CallBuiltin(Builtin, u32),
+    /// Pop the return value, drop the frame, and push the return value
+    Return,
+    /// Pop the given number of elements, and push an array of them
+    MakeArray(u32),
+    /// Pop an index and an array, and push the element
+    Index,
+    /// Pop an index, an array and a value, and set the element to the value
+    SetIndex,
+    /// Pop the fields of a struct literal, and push the struct
+    MakeStruct(u32),
+    /// Pop a struct, and push the field named by a string constant
+    GetField(u32),
+    /// Pop a struct and a value, and set the field named by a string constant
+    SetField(u32),
+}
+
+/// Functions provided by the runtime
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Builtin {
+    Print,
+    Println,
+    Len,
+}
+
+impl Builtin {
+    pub fn from_str(name: &str) -> Option<Builtin> {
+        match name {
+            "print" => Some(Builtin::Print),
+            "println" => Some(Builtin::Println),
+            "len" => Some(Builtin::Len),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Builtin::Print => "print",
+            Builtin::Println => "println",
+            Builtin::Len => "len",
+        }
+    }
+}
+
+/// A compiled function. The top level of the program is compiled to a
+/// function too.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    /// The number of parameters, which take the first local slots
+    pub arity: usize,
+    /// The number of local slots, including parameters
+    pub locals: usize,
+    pub code: Vec<Op>,
+    /// The source each instruction was compiled from, for runtime errors
+    pub spans: Vec<Span>,
+    pub constants: Vec<Value>,
+}
+
+impl Function {
+    pub fn new(name: String, arity: usize) -> Function {
+        Function {
+            name, arity, locals: 0, code: Vec::new(), spans: Vec::new(), constants: Vec::new(),
+        }
+    }
+}
+
+/// How to build a struct from a literal. The literal's fields are pushed in
+/// the order they're written, which may not be the order the struct declares
+/// them.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct StructLayout {
+    pub name: String,
+    /// Field names, in the order the struct declares them
+    pub fields: Vec<String>,
+    /// For each value pushed by the literal, the index of its field
+    pub order: Vec<usize>,
+}
+
+/// A whole compiled program. Execution starts at the first function.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub layouts: Vec<StructLayout>,
+}
+
+/// Writes a program as human readable assembly, with the source line of each
+/// instruction
+pub fn disassemble(program: &Program, map: &SourceMap) -> String {
+    let mut out = String::new();
+    for (i, f) in program.functions.iter().enumerate() {
+        if i > 0 { out.push('\n'); }
+        writeln!(out, "fn {} ({} params, {} locals)", f.name, f.arity, f.locals).unwrap();
+        let mut prev_line = 0;
+        for (offset, (op, span)) in f.code.iter().zip(&f.spans).enumerate() {
+            let line = map.location(span.start).line;
+            let line_col = if line == prev_line { "   |".to_owned() } else { format!("{:4}", line) };
+            prev_line = line;
+            let (name, operands, comment) = describe(program, f, *op);
+            let mut text = format!("{:04} {} {:<14} {}", offset, line_col, name, operands);
+            if let Some(comment) = comment {
+                text = format!("{:<36} ; {}", text.trim_end(), comment);
+            }
+            writeln!(out, "{}", text.trim_end()).unwrap();
+        }
+    }
+    out
+}
+
+/// The mnemonic, operands and an explanatory comment for an instruction
+fn describe(program: &Program, f: &Function, op: Op) -> (&'static str, String, Option<String>) {
+    let constant = |ix: u32| match f.constants[ix as usize] {
+        Value::Str(ref s) => format!("{:?}", s),
+        ref v => v.to_string(),
+    };
+    match op {
+        Op::Const(ix) => ("const", ix.to_string(), Some(constant(ix))),
+        Op::Pop => ("pop", String::new(), None),
+        Op::Dup => ("dup", String::new(), None),
+        Op::GetLocal(slot) => ("get_local", slot.to_string(), None),
+        Op::SetLocal(slot) => ("set_local", slot.to_string(), None),
+        Op::Unary(op) => ("unary", op.symbol().to_owned(), None),
+        Op::Binary(op) => ("binary", op.symbol().to_owned(), None),
+        Op::Jump(to) => ("jump", format!("{:04}", to), None),
+        Op::JumpIfFalse(to) => ("jump_if_false", format!("{:04}", to), None),
+        Op::JumpIfTrue(to) => ("jump_if_true", format!("{:04}", to), None),
+        Op::Call(ix, argc) => ("call", format!("{} {}", ix, argc),
+                               Some(program.functions[ix as usize].name.clone())),
+        Op::CallBuiltin(builtin, argc) => ("call_builtin", format!("{} {}", builtin.name(), argc),
+                                           None),
+        Op::Return => ("return", String::new(), None),
+        Op::MakeArray(len) => ("make_array", len.to_string(), None),
+        Op::Index => ("index", String::new(), None),
+        Op::SetIndex => ("set_index", String::new(), None),
+        Op::MakeStruct(ix) => ("make_struct", ix.to_string(),
+                               Some(program.layouts[ix as usize].name.clone())),
+        Op::GetField(ix) => ("get_field", ix.to_string(), Some(constant(ix))),
+        Op::SetField(ix) => ("set_field", ix.to_string(), Some(constant(ix))),
+    }
+}
@@ -0,0 +1,352 @@
+use ast::{BinOp, Block, Expr, FnDef, Ident, Lit, Stmt, StructDef};
+use bytecode::{Builtin, Function, Op, Program, StructLayout};
+use interpret::Value;
+use resolve::Bindings;
+use token::{Point, Span};
+use std::collections::HashMap;
+use std::mem;
+
+/// Jumps out of the loop being compiled, to patch once its end is known
+#[derive(Default)]
+struct Loop {
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+/// Compiles a resolved and type checked program to bytecode. Variables are
+/// given local slots up front, so the VM never looks anything up by name
+/// except struct fields.
+struct Compiler<'a> {
+    bindings: &'a Bindings,
+    /// The index of each function, keyed by the span of its name
+    fn_ixs: HashMap<Span, u32>,
+    structs: HashMap<&'a str, &'a StructDef>,
+    layouts: Vec<StructLayout>,
+    /// The function being compiled
+    f: Function,
+    /// The slot of each variable in the function being compiled, keyed by the
+    /// span of its declaration
+    slots: HashMap<Span, u16>,
+    /// The first free slot. Slots are reused once a block ends.
+    next_slot: u16,
+    /// Innermost loop last
+    loops: Vec<Loop>,
+}
+
+impl<'a> Compiler<'a> {
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        self.f.code.push(op);
+        self.f.spans.push(span);
+        self.f.code.len() - 1
+    }
+
+    /// The offset of the next instruction
+    fn here(&self) -> u32 {
+        self.f.code.len() as u32
+    }
+
+    /// Sets the target of the jump at offset
+    fn patch(&mut self, offset: usize, to: u32) {
+        self.f.code[offset] = match self.f.code[offset] {
+            Op::Jump(_) => Op::Jump(to),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(to),
+            Op::JumpIfTrue(_) => Op::JumpIfTrue(to),
+            op => panic!("Can't patch {:?}, it isn't a jump", op),
+        };
+    }
+
+    /// Adds a constant to the pool, unless it's already there
+    fn constant(&mut self, val: Value) -> u32 {
+        match self.f.constants.iter().position(|c| *c == val) {
+            Some(ix) => ix as u32,
+            None => {
+                self.f.constants.push(val);
+                self.f.constants.len() as u32 - 1
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &Ident) -> u16 {
+        let slot = self.next_slot;
+        self.slots.insert(name.span, slot);
+        self.next_slot += 1;
+        self.f.locals = ::std::cmp::max(self.f.locals, self.next_slot as usize);
+        slot
+    }
+
+    fn slot(&self, name: &Ident) -> u16 {
+        self.slots[&self.bindings[&name.span]]
+    }
+
+    fn compile_block(&mut self, block: &Block) {
+        let next_slot = self.next_slot;
+        for stmt in block {
+            self.compile_stmt(stmt);
+        }
+        self.next_slot = next_slot;
+    }
+
+    /// Compiles the body of a loop, returning its breaks and continues to be
+    /// patched
+    fn compile_loop_body(&mut self, body: &Block) -> Loop {
+        self.loops.push(Loop::default());
+        self.compile_block(body);
+        self.loops.pop().unwrap()
+    }
+
+    fn patch_loop(&mut self, jumps: Loop, continue_to: u32, break_to: u32) {
+        for offset in jumps.continues {
+            self.patch(offset, continue_to);
+        }
+        for offset in jumps.breaks {
+            self.patch(offset, break_to);
+        }
+    }
+
+    fn compile_stmt(&mut self, stmt: &Stmt) {
+        match *stmt {
+            Stmt::Declare { ref name, ref init, span, .. } => {
+                self.compile_expr(init);
+                let slot = self.declare(name);
+                self.emit(Op::SetLocal(slot), span);
+            }
+            Stmt::Assign { ref target, ref val, span } => {
+                self.compile_expr(val);
+                match *target {
+                    Expr::Var { ref name } => {
+                        let slot = self.slot(name);
+                        self.emit(Op::SetLocal(slot), span);
+                    }
+                    Expr::Index { ref base, ref index, span } => {
+                        self.compile_expr(base);
+                        self.compile_expr(index);
+                        self.emit(Op::SetIndex, span);
+                    }
+                    Expr::Field { ref base, ref field, .. } => {
+                        self.compile_expr(base);
+                        let name = self.constant(Value::Str(field.name.clone()));
+                        self.emit(Op::SetField(name), field.span);
+                    }
+                    _ => panic!("Invalid assignment target {:?}", target),
+                }
+            }
+            Stmt::Expr { ref expr, span } => {
+                self.compile_expr(expr);
+                self.emit(Op::Pop, span);
+            }
+            Stmt::If { ref arms, ref else_body, span } => {
+                let mut to_end = Vec::new();
+                for (i, arm) in arms.iter().enumerate() {
+                    self.compile_expr(&arm.cond);
+                    let to_next = self.emit(Op::JumpIfFalse(0), arm.cond.span());
+                    self.compile_block(&arm.body);
+                    // The last arm falls through to the end, unless there's
+                    // an else to skip
+                    if i + 1 < arms.len() || else_body.is_some() {
+                        to_end.push(self.emit(Op::Jump(0), span));
+                    }
+                    let next = self.here();
+                    self.patch(to_next, next);
+                }
+                if let Some(ref else_body) = *else_body {
+                    self.compile_block(else_body);
+                }
+                let end = self.here();
+                for offset in to_end {
+                    self.patch(offset, end);
+                }
+            }
+            Stmt::While { ref cond, ref body, span } => {
+                let start = self.here();
+                self.compile_expr(cond);
+                let to_end = self.emit(Op::JumpIfFalse(0), cond.span());
+                let jumps = self.compile_loop_body(body);
+                self.emit(Op::Jump(start), span);
+                let end = self.here();
+                self.patch(to_end, end);
+                self.patch_loop(jumps, start, end);
+            }
+            Stmt::For { ref init, ref cond, ref step, ref body, span } => {
+                // The init is scoped to the loop
+                let next_slot = self.next_slot;
+                if let Some(ref init) = *init {
+                    self.compile_stmt(init);
+                }
+                let start = self.here();
+                let to_end = cond.as_ref().map(|cond| {
+                    self.compile_expr(cond);
+                    self.emit(Op::JumpIfFalse(0), cond.span())
+                });
+                let jumps = self.compile_loop_body(body);
+                let step_start = self.here();
+                if let Some(ref step) = *step {
+                    self.compile_stmt(step);
+                }
+                self.emit(Op::Jump(start), span);
+                let end = self.here();
+                if let Some(to_end) = to_end {
+                    self.patch(to_end, end);
+                }
+                self.patch_loop(jumps, step_start, end);
+                self.next_slot = next_slot;
+            }
+            Stmt::Break { span } => {
+                let offset = self.emit(Op::Jump(0), span);
+                self.loops.last_mut().unwrap().breaks.push(offset);
+            }
+            Stmt::Continue { span } => {
+                let offset = self.emit(Op::Jump(0), span);
+                self.loops.last_mut().unwrap().continues.push(offset);
+            }
+            // Functions are compiled separately, and structs only matter to
+            // their literals
+            Stmt::Fn { .. } | Stmt::Struct { .. } => (),
+            Stmt::Return { ref val, span } => {
+                match *val {
+                    Some(ref val) => self.compile_expr(val),
+                    None => {
+                        let void = self.constant(Value::Void);
+                        self.emit(Op::Const(void), span);
+                    }
+                }
+                self.emit(Op::Return, span);
+            }
+        }
+    }
+
+    fn compile_expr(&mut self, expr: &Expr) {
+        match *expr {
+            Expr::Var { ref name } => {
+                let slot = self.slot(name);
+                self.emit(Op::GetLocal(slot), name.span);
+            }
+            Expr::Lit { ref val, span } => {
+                let ix = self.constant(match *val {
+                    Lit::Int(i) => Value::Int(i),
+                    Lit::Float(x) => Value::Float(x),
+                    Lit::Str(ref s) => Value::Str(s.clone()),
+                    Lit::Bool(b) => Value::Bool(b),
+                });
+                self.emit(Op::Const(ix), span);
+            }
+            Expr::Unary { op, op_span, ref operand, .. } => {
+                self.compile_expr(operand);
+                self.emit(Op::Unary(op), op_span);
+            }
+            // && and || short circuit, leaving lhs as the result if it
+            // decides it
+            Expr::Binary { op: op @ BinOp::And, op_span, ref lhs, ref rhs, .. } |
+            Expr::Binary { op: op @ BinOp::Or, op_span, ref lhs, ref rhs, .. } => {
+                self.compile_expr(lhs);
+                self.emit(Op::Dup, op_span);
+                let to_end = self.emit(if op == BinOp::And { Op::JumpIfFalse(0) }
+                                       else { Op::JumpIfTrue(0) }, op_span);
+                self.emit(Op::Pop, op_span);
+                self.compile_expr(rhs);
+                let end = self.here();
+                self.patch(to_end, end);
+            }
+            Expr::Binary { op, op_span, ref lhs, ref rhs, .. } => {
+                self.compile_expr(lhs);
+                self.compile_expr(rhs);
+                self.emit(Op::Binary(op), op_span);
+            }
+            Expr::Call { ref callee, ref args, span } => {
+                for arg in args {
+                    self.compile_expr(arg);
+                }
+                let argc = args.len() as u32;
+                // User defined functions shadow builtins
+                match self.bindings.get(&callee.span).and_then(|decl| self.fn_ixs.get(decl)) {
+                    Some(&ix) => self.emit(Op::Call(ix, argc), span),
+                    None => {
+                        let builtin = Builtin::from_str(&callee.name)
+                            .unwrap_or_else(|| panic!("Undefined function `{}`", callee.name));
+                        self.emit(Op::CallBuiltin(builtin, argc), span)
+                    }
+                };
+            }
+            Expr::Array { ref elems, span } => {
+                for elem in elems {
+                    self.compile_expr(elem);
+                }
+                self.emit(Op::MakeArray(elems.len() as u32), span);
+            }
+            Expr::Index { ref base, ref index, span } => {
+                self.compile_expr(base);
+                self.compile_expr(index);
+                self.emit(Op::Index, span);
+            }
+            Expr::StructLit { ref name, ref fields, span } => {
+                for (_, val) in fields {
+                    self.compile_expr(val);
+                }
+                let def = self.structs[&name.name[..]];
+                let layout = StructLayout {
+                    name: name.name.clone(),
+                    fields: def.fields.iter().map(|f| f.name.name.clone()).collect(),
+                    order: fields.iter()
+                        .map(|(field, _)| def.fields.iter()
+                             .position(|f| f.name.name == field.name).unwrap())
+                        .collect(),
+                };
+                self.layouts.push(layout);
+                let ix = self.layouts.len() as u32 - 1;
+                self.emit(Op::MakeStruct(ix), span);
+            }
+            Expr::Field { ref base, ref field, .. } => {
+                self.compile_expr(base);
+                let name = self.constant(Value::Str(field.name.clone()));
+                self.emit(Op::GetField(name), field.span);
+            }
+        }
+    }
+
+    /// Compiles a function body, or the top level of the program, ending with
+    /// an implicit `return`
+    fn compile_fn(&mut self, name: String, params: &[Ident], body: &Block, end: Span)
+                  -> Function {
+        self.f = Function::new(name, params.len());
+        self.slots.clear();
+        self.next_slot = 0;
+        for param in params {
+            self.declare(param);
+        }
+        self.compile_block(body);
+        let void = self.constant(Value::Void);
+        self.emit(Op::Const(void), end);
+        self.emit(Op::Return, end);
+        mem::replace(&mut self.f, Function::new(String::new(), 0))
+    }
+}
+
+/// Compiles a program which has passed resolution and type checking. The top
+/// level is compiled to the first function.
+pub fn compile(program: &Block, bindings: &Bindings) -> Program {
+    let mut defs: Vec<&FnDef> = Vec::new();
+    let mut structs = HashMap::new();
+    for stmt in program {
+        match *stmt {
+            Stmt::Fn { ref def } => defs.push(def),
+            Stmt::Struct { ref def } => { structs.insert(&def.name.name[..], def); }
+            _ => (),
+        }
+    }
+    let mut compiler = Compiler {
+        bindings,
+        fn_ixs: defs.iter().enumerate().map(|(i, def)| (def.name.span, i as u32 + 1)).collect(),
+        structs,
+        layouts: Vec::new(),
+        f: Function::new(String::new(), 0),
+        slots: HashMap::new(),
+        next_slot: 0,
+        loops: Vec::new(),
+    };
+    let end = program.last().map_or(Span { start: Point(0), end: Point(0) }, Stmt::span);
+    let mut functions = vec![compiler.compile_fn("<main>".to_owned(), &[], program, end)];
+    for def in defs {
+        let params: Vec<Ident> = def.params.iter().map(|p| p.name.clone()).collect();
+        functions.push(compiler.compile_fn(def.name.name.clone(), &params, &def.body, def.span));
+    }
+    Program { functions, layouts: compiler.layouts }
+}
@@ -0,0 +1,164 @@
+use atty;
+use colored::*;
+use source::SourceMap;
+use token::Span;
+use std::fmt::Write;
+
+/// Extra information pointing at some source
+#[derive(Eq, PartialEq, Debug, Clone)]
+struct Label {
+    span: Span,
+    msg: String,
+    /// Primary labels point at the cause of the error, and are underlined with
+    /// `^`. Secondary labels give context, and are underlined with `-`.
+    primary: bool,
+}
+
+/// An error to be shown to the user, made up of a message, labelled spans of
+/// the source, notes and help. Every stage of the compiler reports errors by
+/// building one of these.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Diagnostic {
+    msg: String,
+    labels: Vec<Label>,
+    notes: Vec<String>,
+    help: Vec<String>,
+}
+
+/// Turns off colour if stderr isn't a terminal. Call before emitting anything.
+pub fn init_colour() {
+    if !atty::is(atty::Stream::Stderr) {
+        ::colored::control::set_override(false);
+    }
+}
+
+impl Diagnostic {
+    /// An error with no location
+    pub fn error(msg: String) -> Diagnostic {
+        Diagnostic { msg, labels: Vec::new(), notes: Vec::new(), help: Vec::new() }
+    }
+
+    /// An error caused by the source at span
+    pub fn spanned(msg: String, span: Span) -> Diagnostic {
+        Diagnostic::error(msg).primary(span, String::new())
+    }
+
+    /// Adds a primary label, pointing at the cause of the error
+    pub fn primary(mut self, span: Span, msg: String) -> Diagnostic {
+        self.labels.push(Label { span, msg, primary: true });
+        self
+    }
+
+    /// Adds a secondary label, giving context for the error
+    pub fn label(mut self, span: Span, msg: String) -> Diagnostic {
+        self.labels.push(Label { span, msg, primary: false });
+        self
+    }
+
+    pub fn note(mut self, msg: String) -> Diagnostic {
+        self.notes.push(msg);
+        self
+    }
+
+    pub fn help(mut self, msg: String) -> Diagnostic {
+        self.help.push(msg);
+        self
+    }
+
+    /// Renders this diagnostic, with a snippet of the source under each label
+    pub fn render(&self, filename: &str, map: &SourceMap) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}{} {}", "error".red().bold(), ":".bold(), self.msg.bold()).unwrap();
+
+        // Labels in source order, so the snippet reads top to bottom
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort_by_key(|l| (l.span.start, !l.primary));
+        let gutter = labels.iter()
+            .map(|l| map.location(l.span.start).line.to_string().len())
+            .max().unwrap_or(0);
+        let pad = " ".repeat(gutter);
+
+        let location = match self.labels.iter().find(|l| l.primary) {
+            Some(l) => format!("{}:{}", filename, map.location(l.span.start)),
+            None => filename.to_owned(),
+        };
+        writeln!(out, "{}{} {}", pad, "-->".blue().bold(), location).unwrap();
+
+        if !labels.is_empty() {
+            writeln!(out, "{} {}", pad, "|".blue().bold()).unwrap();
+        }
+        let mut last_line = None;
+        for label in labels {
+            let start = map.location(label.span.start);
+            let end = map.location(label.span.end);
+            let text = map.line(start.line);
+            if last_line.is_some_and(|last| start.line > last + 1) {
+                writeln!(out, "{}", "...".blue().bold()).unwrap();
+            }
+            if last_line != Some(start.line) {
+                let line_num = format!("{:>width$}", start.line, width = gutter);
+                writeln!(out, "{} {} {}", line_num.blue().bold(), "|".blue().bold(), text).unwrap();
+                last_line = Some(start.line);
+            }
+            // Spans running over multiple lines are underlined to the end of
+            // the first line
+            let end_col = if end.line == start.line { end.col } else {
+                text.chars().count() + 1
+            };
+            let indent: String = text.chars().take(start.col - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+            let width = std::cmp::max(end_col.saturating_sub(start.col), 1);
+            let marker = if label.primary { "^" } else { "-" };
+            let underline = format!("{} {}", marker.repeat(width), label.msg);
+            let underline = if label.primary {
+                underline.trim_end().red().bold()
+            } else {
+                underline.trim_end().blue().bold()
+            };
+            writeln!(out, "{} {} {}{}", pad, "|".blue().bold(), indent, underline).unwrap();
+        }
+
+        for note in &self.notes {
+            writeln!(out, "{} {} {}: {}", pad, "=".blue().bold(), "note".bold(), note).unwrap();
+        }
+        for help in &self.help {
+            writeln!(out, "{} {} {}: {}", pad, "=".blue().bold(), "help".bold(), help).unwrap();
+        }
+        out
+    }
+
+    /// Prints this diagnostic to stderr
+    pub fn emit(&self, filename: &str, map: &SourceMap) {
+        eprintln!("{}", self.render(filename, map));
+    }
+}
+
+/// The number of single char insertions, deletions and substitutions needed to
+/// turn a into b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from a prefix of a to each prefix of b, one row at a time
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let sub = diag + if ca == cb { 0 } else { 1 };
+            diag = row[j + 1];
+            row[j + 1] = std::cmp::min(sub, std::cmp::min(row[j], row[j + 1]) + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Returns the candidate closest to name, for "did you mean" suggestions, if
+/// any is close enough to plausibly be a typo
+pub fn closest_match<'a, I>(name: &str, candidates: I) -> Option<&'a str>
+    where I: IntoIterator<Item = &'a str> {
+    let max_distance = std::cmp::max(1, name.chars().count() / 3);
+    candidates.into_iter()
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|&(d, _)| d <= max_distance)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, c)| c)
+}
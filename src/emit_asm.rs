@@ -0,0 +1,721 @@
+use ast::{BinOp, Block, Expr, FnDef, Ident, Lit, Stmt, StructDef, Type, UnOp};
+use emit_c::{self, c_ident};
+use interpret::MAX_CALL_DEPTH;
+use resolve::Bindings;
+use source::SourceMap;
+use token::Span;
+use typeck::Types;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// The C runtime the assembly is linked with
+const RUNTIME_C: &str = include_str!("runtime/hl2_rt.c");
+
+/// Registers for the first integer and pointer arguments of a call
+const INT_ARG_REGS: &[&str] = &["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9"];
+/// Registers for the first float arguments of a call
+const FLOAT_ARG_REGS: &[&str] = &["%xmm0", "%xmm1", "%xmm2", "%xmm3",
+                                  "%xmm4", "%xmm5", "%xmm6", "%xmm7"];
+
+/// Where the System V calling convention passes each argument of a call
+enum ArgLoc {
+    Reg(&'static str),
+    /// The index of the argument among those passed on the stack
+    Stack(usize),
+}
+
+fn arg_locs<'t, I: Iterator<Item = &'t Type>>(tys: I) -> Vec<ArgLoc> {
+    let (mut ints, mut floats, mut stack) = (0, 0, 0);
+    tys.map(|ty| {
+        if *ty == Type::Float && floats < FLOAT_ARG_REGS.len() {
+            floats += 1;
+            ArgLoc::Reg(FLOAT_ARG_REGS[floats - 1])
+        } else if *ty != Type::Float && ints < INT_ARG_REGS.len() {
+            ints += 1;
+            ArgLoc::Reg(INT_ARG_REGS[ints - 1])
+        } else {
+            stack += 1;
+            ArgLoc::Stack(stack - 1)
+        }
+    }).collect()
+}
+
+/// A loop being generated
+struct Loop {
+    continue_label: String,
+    break_label: String,
+}
+
+/// Generates x86-64 assembly for the System V ABI, in GAS syntax. Every value
+/// is 8 bytes. Expressions leave their value in %rax, pushing intermediate
+/// results, and every variable gets its own slot in the stack frame.
+struct Generator<'a> {
+    bindings: &'a Bindings,
+    types: &'a Types,
+    filename: &'a str,
+    map: &'a SourceMap<'a>,
+    structs: HashMap<&'a str, &'a StructDef>,
+    /// The label of each function, keyed by the span of its name
+    fns: HashMap<Span, String>,
+    /// The return type of each function, keyed by the span of its name
+    fn_rets: HashMap<Span, &'a Type>,
+    /// The offset from %rbp of each variable in the current function, keyed
+    /// by the span of its declaration
+    slots: HashMap<Span, i64>,
+    /// The label the current function returns through
+    ret_label: String,
+    /// The return type of the current function
+    ret_ty: Type,
+    /// How many 8 byte values have been pushed since the frame was set up.
+    /// The stack is 16 byte aligned when this is even.
+    depth: usize,
+    next_label: usize,
+    /// Innermost loop last
+    loops: Vec<Loop>,
+    /// Labels of string constants, keyed by their contents
+    strings: HashMap<String, String>,
+    /// Labels of NUL terminated C strings, for runtime error messages
+    c_strings: HashMap<String, String>,
+    text: String,
+}
+
+/// Writes bytes as the contents of a GAS string
+fn gas_string(s: &str) -> String {
+    let mut lit = String::new();
+    for &b in s.as_bytes() {
+        match b {
+            b'"' | b'\\' => { lit.push('\\'); lit.push(b as char); }
+            0x20..=0x7e => lit.push(b as char),
+            _ => write!(lit, "\\{:03o}", b).unwrap(),
+        }
+    }
+    lit
+}
+
+/// Counts the declarations in a block, including nested blocks
+fn count_decls(block: &Block) -> usize {
+    block.iter().map(stmt_decls).sum()
+}
+
+fn stmt_decls(stmt: &Stmt) -> usize {
+    match *stmt {
+        Stmt::Declare { .. } => 1,
+        Stmt::If { ref arms, ref else_body, .. } =>
+            arms.iter().map(|arm| count_decls(&arm.body)).sum::<usize>()
+                + else_body.as_ref().map_or(0, count_decls),
+        Stmt::While { ref body, .. } => count_decls(body),
+        Stmt::For { ref init, ref body, .. } =>
+            init.as_ref().map_or(0, |init| stmt_decls(init)) + count_decls(body),
+        _ => 0,
+    }
+}
+
+impl<'a> Generator<'a> {
+    fn ins(&mut self, ins: String) {
+        self.text.push_str("    ");
+        self.text.push_str(&ins);
+        self.text.push('\n');
+    }
+
+    fn label(&mut self, label: &str) {
+        self.text.push_str(label);
+        self.text.push_str(":\n");
+    }
+
+    fn new_label(&mut self) -> String {
+        self.next_label += 1;
+        format!(".L{}", self.next_label)
+    }
+
+    fn ty(&self, expr: &Expr) -> &'a Type {
+        let types: &'a Types = self.types;
+        &types[&expr.span()]
+    }
+
+    fn push(&mut self) {
+        self.ins("pushq %rax".to_owned());
+        self.depth += 1;
+    }
+
+    fn pop(&mut self, reg: &str) {
+        self.ins(format!("popq {}", reg));
+        self.depth -= 1;
+    }
+
+    /// Calls a function with the stack aligned, as the ABI requires
+    fn call(&mut self, target: &str) {
+        if self.depth % 2 == 1 {
+            self.ins("subq $8, %rsp".to_owned());
+            self.ins(format!("call {}", target));
+            self.ins("addq $8, %rsp".to_owned());
+        } else {
+            self.ins(format!("call {}", target));
+        }
+    }
+
+    /// Calls a function in the runtime
+    fn call_runtime(&mut self, name: &str) {
+        self.call(&format!("hl2_rt_{}@PLT", name));
+    }
+
+    /// The label of a string constant
+    fn string(&mut self, s: &str) -> String {
+        let next = self.strings.len();
+        self.strings.entry(s.to_owned()).or_insert_with(|| format!(".Lstr{}", next)).clone()
+    }
+
+    /// The label of a NUL terminated C string
+    fn c_string(&mut self, s: String) -> String {
+        let next = self.c_strings.len();
+        self.c_strings.entry(s).or_insert_with(|| format!(".Lcstr{}", next)).clone()
+    }
+
+    /// Loads the address of a C string of where span starts, for runtime
+    /// errors
+    fn load_loc(&mut self, span: Span, reg: &str) {
+        let loc = format!("{}:{}", self.filename, self.map.location(span.start));
+        let label = self.c_string(loc);
+        self.ins(format!("leaq {}(%rip), {}", label, reg));
+    }
+
+    /// Reports integer overflow in op if the overflow flag is set
+    fn check_overflow(&mut self, op: BinOp, span: Span) {
+        let ok = self.new_label();
+        self.ins(format!("jno {}", ok));
+        let op = self.c_string(op.symbol().to_owned());
+        self.ins(format!("leaq {}(%rip), %rdi", op));
+        self.load_loc(span, "%rsi");
+        self.call_runtime("overflow");
+        self.label(&ok);
+    }
+
+    /// Checks the index in %rsi is in bounds for the array in %rdi
+    fn check_bounds(&mut self, span: Span) {
+        let ok = self.new_label();
+        // Negative indices are huge when compared unsigned
+        self.ins("cmpq (%rdi), %rsi".to_owned());
+        self.ins(format!("jb {}", ok));
+        self.ins("movq %rsi, %rax".to_owned());
+        self.ins("movq (%rdi), %rsi".to_owned());
+        self.ins("movq %rax, %rdi".to_owned());
+        self.load_loc(span, "%rdx");
+        self.call_runtime("index_error");
+        self.label(&ok);
+    }
+
+    /// The offset of a field from the start of a struct
+    fn field_offset(&self, base: &Expr, field: &Ident) -> usize {
+        let def = match *self.ty(base) {
+            Type::Struct(ref name) => self.structs[&name[..]],
+            ref ty => panic!("Field of {}", ty.name()),
+        };
+        8 * def.fields.iter().position(|f| f.name.name == field.name).unwrap()
+    }
+
+    fn block(&mut self, block: &Block) {
+        for stmt in block {
+            self.stmt(stmt);
+        }
+    }
+
+    /// Evaluates a condition, jumping to label if it's false
+    fn jump_unless(&mut self, cond: &Expr, label: &str) {
+        self.expr(cond);
+        self.ins("testq %rax, %rax".to_owned());
+        self.ins(format!("jz {}", label));
+    }
+
+    fn loop_body(&mut self, body: &Block, continue_label: &str, break_label: &str) {
+        self.loops.push(Loop {
+            continue_label: continue_label.to_owned(),
+            break_label: break_label.to_owned(),
+        });
+        self.block(body);
+        self.loops.pop();
+    }
+
+    fn declare(&mut self, name: &Ident) -> i64 {
+        let offset = -8 * (self.slots.len() as i64 + 1);
+        self.slots.insert(name.span, offset);
+        offset
+    }
+
+    fn slot(&self, name: &Ident) -> i64 {
+        self.slots[&self.bindings[&name.span]]
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match *stmt {
+            Stmt::Declare { ref name, ref init, .. } => {
+                self.expr(init);
+                let offset = self.declare(name);
+                self.ins(format!("movq %rax, {}(%rbp)", offset));
+            }
+            Stmt::Assign { ref target, ref val, .. } => {
+                self.expr(val);
+                match *target {
+                    Expr::Var { ref name } => {
+                        let offset = self.slot(name);
+                        self.ins(format!("movq %rax, {}(%rbp)", offset));
+                    }
+                    Expr::Index { ref base, ref index, span } => {
+                        self.push();
+                        self.expr(base);
+                        self.push();
+                        self.expr(index);
+                        self.ins("movq %rax, %rsi".to_owned());
+                        self.pop("%rdi");
+                        self.check_bounds(span);
+                        self.pop("%rax");
+                        self.ins("movq %rax, 8(%rdi,%rsi,8)".to_owned());
+                    }
+                    Expr::Field { ref base, ref field, .. } => {
+                        self.push();
+                        self.expr(base);
+                        self.ins("movq %rax, %rcx".to_owned());
+                        self.pop("%rax");
+                        let offset = self.field_offset(base, field);
+                        self.ins(format!("movq %rax, {}(%rcx)", offset));
+                    }
+                    _ => panic!("Invalid assignment target {:?}", target),
+                }
+            }
+            Stmt::Expr { ref expr, .. } => self.expr(expr),
+            Stmt::If { ref arms, ref else_body, .. } => {
+                let end = self.new_label();
+                for arm in arms {
+                    let next = self.new_label();
+                    self.jump_unless(&arm.cond, &next);
+                    self.block(&arm.body);
+                    self.ins(format!("jmp {}", end));
+                    self.label(&next);
+                }
+                if let Some(ref else_body) = *else_body {
+                    self.block(else_body);
+                }
+                self.label(&end);
+            }
+            Stmt::While { ref cond, ref body, .. } => {
+                let (start, end) = (self.new_label(), self.new_label());
+                self.label(&start);
+                self.jump_unless(cond, &end);
+                self.loop_body(body, &start, &end);
+                self.ins(format!("jmp {}", start));
+                self.label(&end);
+            }
+            Stmt::For { ref init, ref cond, ref step, ref body, .. } => {
+                if let Some(ref init) = *init {
+                    self.stmt(init);
+                }
+                let (start, step_label, end) = (self.new_label(), self.new_label(),
+                                                 self.new_label());
+                self.label(&start);
+                if let Some(ref cond) = *cond {
+                    self.jump_unless(cond, &end);
+                }
+                self.loop_body(body, &step_label, &end);
+                self.label(&step_label);
+                if let Some(ref step) = *step {
+                    self.stmt(step);
+                }
+                self.ins(format!("jmp {}", start));
+                self.label(&end);
+            }
+            Stmt::Break { .. } => {
+                let label = self.loops.last().unwrap().break_label.clone();
+                self.ins(format!("jmp {}", label));
+            }
+            Stmt::Continue { .. } => {
+                let label = self.loops.last().unwrap().continue_label.clone();
+                self.ins(format!("jmp {}", label));
+            }
+            // Generated before the code which uses them
+            Stmt::Fn { .. } | Stmt::Struct { .. } => (),
+            Stmt::Return { ref val, .. } => {
+                if let Some(ref val) = *val {
+                    self.expr(val);
+                    if self.ret_ty == Type::Float {
+                        self.ins("movq %rax, %xmm0".to_owned());
+                    }
+                }
+                let label = self.ret_label.clone();
+                self.ins(format!("jmp {}", label));
+            }
+        }
+    }
+
+    /// Calls a user defined function with the arguments evaluated in order
+    fn call_fn(&mut self, callee: &Ident, label: String, args: &[Expr], span: Span) {
+        for arg in args {
+            self.expr(arg);
+            self.push();
+        }
+        let ok = self.new_label();
+        self.ins(format!("cmpq ${}, hl2_call_depth(%rip)", MAX_CALL_DEPTH));
+        self.ins(format!("jne {}", ok));
+        let name = self.c_string(callee.name.clone());
+        self.ins(format!("leaq {}(%rip), %rdi", name));
+        self.load_loc(span, "%rsi");
+        self.call_runtime("stack_overflow");
+        self.label(&ok);
+
+        // Argument i was pushed at (n - 1 - i) * 8 above the stack pointer
+        let n = args.len();
+        let locs = arg_locs(args.iter().map(|arg| self.ty(arg)));
+        let on_stack: Vec<_> = locs.iter().enumerate().filter_map(|(i, loc)| match *loc {
+            ArgLoc::Stack(_) => Some(i),
+            ArgLoc::Reg(_) => None,
+        }).collect();
+        for (i, loc) in locs.iter().enumerate() {
+            if let ArgLoc::Reg(reg) = *loc {
+                self.ins(format!("movq {}(%rsp), {}", 8 * (n - 1 - i), reg));
+            }
+        }
+        // Copy the stack arguments below the pushed ones, last first, so the
+        // stack is aligned at the call
+        let pad = (self.depth + on_stack.len()) % 2;
+        if pad == 1 {
+            self.ins("subq $8, %rsp".to_owned());
+        }
+        for (copied, &i) in on_stack.iter().rev().enumerate() {
+            self.ins(format!("pushq {}(%rsp)", 8 * (n - 1 - i + pad + copied)));
+        }
+        self.ins("incq hl2_call_depth(%rip)".to_owned());
+        self.ins(format!("call {}", label));
+        self.ins("decq hl2_call_depth(%rip)".to_owned());
+        self.ins(format!("addq ${}, %rsp", 8 * (n + pad + on_stack.len())));
+        self.depth -= n;
+        if *self.fn_rets[&self.bindings[&callee.span]] == Type::Float {
+            self.ins("movq %xmm0, %rax".to_owned());
+        }
+    }
+
+    fn call_builtin(&mut self, callee: &Ident, args: &[Expr]) {
+        match &callee.name[..] {
+            name @ "print" | name @ "println" => {
+                // Every argument is evaluated before anything is printed
+                for arg in args {
+                    self.expr(arg);
+                    self.push();
+                }
+                let n = args.len();
+                for (i, arg) in args.iter().enumerate() {
+                    let (reg, print) = match *self.ty(arg) {
+                        Type::Int => ("%rdi", "print_int"),
+                        Type::Float => ("%xmm0", "print_float"),
+                        Type::String => ("%rdi", "print_str"),
+                        Type::Bool => ("%rdi", "print_bool"),
+                        ref ty => panic!("Printing {}", ty.name()),
+                    };
+                    self.ins(format!("movq {}(%rsp), {}", 8 * (n - 1 - i), reg));
+                    self.call_runtime(print);
+                }
+                if name == "println" {
+                    self.call_runtime("newline");
+                }
+                if n > 0 {
+                    self.ins(format!("addq ${}, %rsp", 8 * n));
+                    self.depth -= n;
+                }
+            }
+            "len" => {
+                self.expr(&args[0]);
+                self.ins("movq (%rax), %rax".to_owned());
+            }
+            name => panic!("Undefined function `{}`", name),
+        }
+    }
+
+    /// Evaluates a binary operation on lhs in %rax and rhs in %rcx
+    fn binary_op(&mut self, op: BinOp, operand_ty: &Type, op_span: Span) {
+        let set = |cond: &str| vec![format!("set{} %al", cond), "movzbq %al, %rax".to_owned()];
+        let ins = match (operand_ty, op) {
+            (&Type::Int, BinOp::Add) | (&Type::Int, BinOp::Sub) | (&Type::Int, BinOp::Mul) => {
+                let ins = match op { BinOp::Add => "addq", BinOp::Sub => "subq", _ => "imulq" };
+                self.ins(format!("{} %rcx, %rax", ins));
+                self.check_overflow(op, op_span);
+                return;
+            }
+            (&Type::Int, BinOp::Div) | (&Type::Int, BinOp::Mod) => {
+                self.ins("movq %rax, %rdi".to_owned());
+                self.ins("movq %rcx, %rsi".to_owned());
+                self.load_loc(op_span, "%rdx");
+                self.call_runtime(if op == BinOp::Div { "div" } else { "mod" });
+                return;
+            }
+            (&Type::Float, BinOp::Mod) => {
+                self.ins("movq %rax, %xmm0".to_owned());
+                self.ins("movq %rcx, %xmm1".to_owned());
+                self.call_runtime("fmod");
+                self.ins("movq %xmm0, %rax".to_owned());
+                return;
+            }
+            (&Type::Float, _) => {
+                self.ins("movq %rax, %xmm0".to_owned());
+                self.ins("movq %rcx, %xmm1".to_owned());
+                let arith = match op {
+                    BinOp::Add => Some("addsd"),
+                    BinOp::Sub => Some("subsd"),
+                    BinOp::Mul => Some("mulsd"),
+                    BinOp::Div => Some("divsd"),
+                    _ => None,
+                };
+                if let Some(arith) = arith {
+                    self.ins(format!("{} %xmm1, %xmm0", arith));
+                    self.ins("movq %xmm0, %rax".to_owned());
+                    return;
+                }
+                // Comparisons with NaN are unordered, which sets ZF, PF and
+                // CF, so every comparison but != must be false then
+                match op {
+                    BinOp::Gt | BinOp::Ge => self.ins("ucomisd %xmm1, %xmm0".to_owned()),
+                    BinOp::Lt | BinOp::Le => self.ins("ucomisd %xmm0, %xmm1".to_owned()),
+                    _ => self.ins("ucomisd %xmm1, %xmm0".to_owned()),
+                }
+                match op {
+                    BinOp::Gt | BinOp::Lt => set("a"),
+                    BinOp::Ge | BinOp::Le => set("ae"),
+                    BinOp::Eq => vec!["sete %al".to_owned(), "setnp %cl".to_owned(),
+                                      "andb %cl, %al".to_owned(), "movzbq %al, %rax".to_owned()],
+                    BinOp::Ne => vec!["setne %al".to_owned(), "setp %cl".to_owned(),
+                                      "orb %cl, %al".to_owned(), "movzbq %al, %rax".to_owned()],
+                    _ => panic!("Operator `{}` on floats", op.symbol()),
+                }
+            }
+            (&Type::String, BinOp::Add) | (&Type::String, BinOp::Eq) |
+            (&Type::String, BinOp::Ne) => {
+                self.ins("movq %rax, %rdi".to_owned());
+                self.ins("movq %rcx, %rsi".to_owned());
+                self.call_runtime(if op == BinOp::Add { "str_concat" } else { "str_eq" });
+                if op == BinOp::Ne {
+                    self.ins("xorq $1, %rax".to_owned());
+                }
+                return;
+            }
+            _ => {
+                self.ins("cmpq %rcx, %rax".to_owned());
+                match op {
+                    BinOp::Eq => set("e"),
+                    BinOp::Ne => set("ne"),
+                    BinOp::Gt => set("g"),
+                    BinOp::Lt => set("l"),
+                    BinOp::Ge => set("ge"),
+                    BinOp::Le => set("le"),
+                    _ => panic!("Operator `{}` on {}", op.symbol(), operand_ty.name()),
+                }
+            }
+        };
+        for ins in ins {
+            self.ins(ins);
+        }
+    }
+
+    /// Evaluates expr into %rax
+    fn expr(&mut self, expr: &Expr) {
+        match *expr {
+            Expr::Var { ref name } => {
+                let offset = self.slot(name);
+                self.ins(format!("movq {}(%rbp), %rax", offset));
+            }
+            Expr::Lit { ref val, .. } => match *val {
+                Lit::Int(i) => self.ins(format!("movabsq ${}, %rax", i)),
+                Lit::Float(x) => self.ins(format!("movabsq ${}, %rax", x.to_bits() as i64)),
+                Lit::Str(ref s) => {
+                    let label = self.string(s);
+                    self.ins(format!("leaq {}(%rip), %rax", label));
+                }
+                Lit::Bool(b) => self.ins(format!("movq ${}, %rax", b as u8)),
+            },
+            Expr::Unary { op, op_span, ref operand, .. } => {
+                let ty = self.ty(operand);
+                self.expr(operand);
+                match (op, ty) {
+                    (UnOp::Neg, &Type::Int) => {
+                        self.ins("negq %rax".to_owned());
+                        self.check_overflow(BinOp::Sub, op_span);
+                    }
+                    // Flip the sign bit
+                    (UnOp::Neg, _) => self.ins("btcq $63, %rax".to_owned()),
+                    (UnOp::Not, _) => self.ins("xorq $1, %rax".to_owned()),
+                }
+            }
+            // && and || short circuit, leaving lhs in %rax if it decides the
+            // result
+            Expr::Binary { op: op @ BinOp::And, ref lhs, ref rhs, .. } |
+            Expr::Binary { op: op @ BinOp::Or, ref lhs, ref rhs, .. } => {
+                let end = self.new_label();
+                self.expr(lhs);
+                self.ins("testq %rax, %rax".to_owned());
+                self.ins(format!("{} {}", if op == BinOp::And { "jz" } else { "jnz" }, end));
+                self.expr(rhs);
+                self.label(&end);
+            }
+            Expr::Binary { op, op_span, ref lhs, ref rhs, .. } => {
+                let operand_ty = self.ty(lhs);
+                self.expr(lhs);
+                self.push();
+                self.expr(rhs);
+                self.ins("movq %rax, %rcx".to_owned());
+                self.pop("%rax");
+                self.binary_op(op, operand_ty, op_span);
+            }
+            Expr::Call { ref callee, ref args, span } => {
+                // User defined functions shadow builtins
+                match self.bindings.get(&callee.span).and_then(|decl| self.fns.get(decl)) {
+                    Some(label) => {
+                        let label = label.clone();
+                        self.call_fn(callee, label, args, span);
+                    }
+                    None => self.call_builtin(callee, args),
+                }
+            }
+            Expr::Array { ref elems, .. } => {
+                for elem in elems {
+                    self.expr(elem);
+                    self.push();
+                }
+                self.ins(format!("movq ${}, %rdi", elems.len()));
+                self.call_runtime("array_new");
+                for i in (0..elems.len()).rev() {
+                    self.pop("%rcx");
+                    self.ins(format!("movq %rcx, {}(%rax)", 8 * (i + 1)));
+                }
+            }
+            Expr::Index { ref base, ref index, span } => {
+                self.expr(base);
+                self.push();
+                self.expr(index);
+                self.ins("movq %rax, %rsi".to_owned());
+                self.pop("%rdi");
+                self.check_bounds(span);
+                self.ins("movq 8(%rdi,%rsi,8), %rax".to_owned());
+            }
+            Expr::StructLit { ref name, ref fields, .. } => {
+                for field in fields {
+                    self.expr(&field.1);
+                    self.push();
+                }
+                let def = self.structs[&name.name[..]];
+                self.ins(format!("movq ${}, %rdi", 8 * def.fields.len()));
+                self.call_runtime("alloc");
+                for field in fields.iter().rev() {
+                    let offset = 8 * def.fields.iter()
+                        .position(|f| f.name.name == field.0.name).unwrap();
+                    self.pop("%rcx");
+                    self.ins(format!("movq %rcx, {}(%rax)", offset));
+                }
+            }
+            Expr::Field { ref base, ref field, .. } => {
+                self.expr(base);
+                let offset = self.field_offset(base, field);
+                self.ins(format!("movq {}(%rax), %rax", offset));
+            }
+        }
+    }
+
+    /// Generates a function, or the top level of the program if def is None
+    fn function(&mut self, label: &str, def: Option<&'a FnDef>, body: &Block) {
+        self.slots.clear();
+        self.depth = 0;
+        self.ret_label = self.new_label();
+        self.ret_ty = def.map_or(Type::Void, |def| def.ret.clone());
+        let params = def.map_or(&[][..], |def| &def.params[..]);
+        let slots = params.len() + count_decls(body);
+        // Rounded up to keep the stack 16 byte aligned
+        let frame_size = 8 * (slots + slots % 2);
+
+        self.text.push('\n');
+        self.label(label);
+        self.ins("pushq %rbp".to_owned());
+        self.ins("movq %rsp, %rbp".to_owned());
+        if frame_size > 0 {
+            self.ins(format!("subq ${}, %rsp", frame_size));
+        }
+        let locs = arg_locs(params.iter().map(|p| &p.ty));
+        for (param, loc) in params.iter().zip(locs) {
+            let offset = self.declare(&param.name);
+            match loc {
+                ArgLoc::Reg(reg) => self.ins(format!("movq {}, {}(%rbp)", reg, offset)),
+                ArgLoc::Stack(i) => {
+                    // Above the saved %rbp and return address
+                    self.ins(format!("movq {}(%rbp), %rax", 16 + 8 * i));
+                    self.ins(format!("movq %rax, {}(%rbp)", offset));
+                }
+            }
+        }
+        self.block(body);
+        if def.is_none() {
+            self.ins("xorl %eax, %eax".to_owned());
+        }
+        let ret_label = self.ret_label.clone();
+        self.label(&ret_label);
+        self.ins("leave".to_owned());
+        self.ins("ret".to_owned());
+    }
+}
+
+/// The C runtime to link with the generated assembly, as a single file
+pub fn runtime() -> String {
+    RUNTIME_C.replace("#include \"hl2.h\"\n", &emit_c::runtime())
+}
+
+/// Generates GAS assembly for a program which has passed type checking. It
+/// must be linked with the C runtime from `runtime`, e.g. with
+/// `cc prog.s hl2_rt.c`.
+pub fn emit(program: &Block, bindings: &Bindings, types: &Types, filename: &str,
+            map: &SourceMap) -> String {
+    let mut defs = Vec::new();
+    let mut structs = HashMap::new();
+    for stmt in program {
+        match *stmt {
+            Stmt::Fn { ref def } => defs.push(def),
+            Stmt::Struct { ref def } => { structs.insert(&def.name.name[..], def); }
+            _ => (),
+        }
+    }
+    let mut gen = Generator {
+        bindings, types, filename, map, structs,
+        fns: defs.iter().map(|def| (def.name.span, c_ident("hl2_f_", &def.name.name))).collect(),
+        fn_rets: defs.iter().map(|def| (def.name.span, &def.ret)).collect(),
+        slots: HashMap::new(),
+        ret_label: String::new(),
+        ret_ty: Type::Void,
+        depth: 0,
+        next_label: 0,
+        loops: Vec::new(),
+        strings: HashMap::new(),
+        c_strings: HashMap::new(),
+        text: String::new(),
+    };
+    gen.function("main", None, program);
+    for def in defs {
+        let label = gen.fns[&def.name.span].clone();
+        gen.function(&label, Some(def), &def.body);
+    }
+
+    let mut out = String::new();
+    writeln!(out, "# Compiled from {} by the hl2 compiler. Link with the hl2 runtime, from",
+             filename).unwrap();
+    writeln!(out, "# `--asm-runtime`.").unwrap();
+    writeln!(out, "    .text").unwrap();
+    writeln!(out, "    .globl main").unwrap();
+    out.push_str(&gen.text);
+    writeln!(out, "\n    .bss").unwrap();
+    writeln!(out, "    .balign 8").unwrap();
+    writeln!(out, "hl2_call_depth:\n    .zero 8").unwrap();
+    writeln!(out, "\n    .section .rodata").unwrap();
+    // Sorted by label, so the output is the same every time
+    let mut strings: Vec<_> = gen.strings.iter().collect();
+    strings.sort_by_key(|&(_, label)| label[5..].parse::<usize>().unwrap());
+    for (s, label) in strings {
+        writeln!(out, "    .balign 8\n{}:\n    .quad {}\n    .ascii \"{}\"",
+                 label, s.len(), gas_string(s)).unwrap();
+    }
+    let mut c_strings: Vec<_> = gen.c_strings.iter().collect();
+    c_strings.sort_by_key(|&(_, label)| label[6..].parse::<usize>().unwrap());
+    for (s, label) in c_strings {
+        writeln!(out, "{}:\n    .asciz \"{}\"", label, gas_string(s)).unwrap();
+    }
+    writeln!(out, "\n    .section .note.GNU-stack,\"\",@progbits").unwrap();
+    out
+}
@@ -0,0 +1,526 @@
+use ast::{BinOp, Block, Expr, FnDef, Ident, IfArm, Lit, Stmt, StructDef, Type, UnOp};
+use interpret::MAX_CALL_DEPTH;
+use resolve::Bindings;
+use source::SourceMap;
+use token::Span;
+use typeck::Types;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// The runtime every emitted file starts with
+const RUNTIME: &str = include_str!("runtime/hl2.h");
+
+/// The runtime, preceded by the limits it shares with the interpreter
+pub fn runtime() -> String {
+    format!("#define HL2_MAX_CALL_DEPTH {}\n{}", MAX_CALL_DEPTH, RUNTIME)
+}
+
+/// A loop being emitted
+struct Loop {
+    /// The label before a for loop's step, which `continue` jumps to. None
+    /// for while loops, where C's `continue` does the job.
+    continue_label: Option<String>,
+    /// Whether anything jumped to continue_label
+    continued: bool,
+}
+
+/// Translates a checked program to C. Subexpressions are evaluated into
+/// temporaries, in the same order as the interpreter, since C leaves the
+/// order of evaluation of operands and arguments unspecified.
+struct Emitter<'a> {
+    bindings: &'a Bindings,
+    types: &'a Types,
+    filename: &'a str,
+    map: &'a SourceMap<'a>,
+    vars: VarNames,
+    /// The C name of each function, keyed by the span of its name
+    fns: HashMap<Span, String>,
+    next_temp: usize,
+    next_label: usize,
+    /// Innermost loop last
+    loops: Vec<Loop>,
+    out: String,
+    indent: usize,
+}
+
+/// Turns an hl2 name into a valid C identifier with the given prefix, so it
+/// can't clash with C keywords or the runtime. `_` is doubled, so the result
+/// never has a lone `_` followed by a digit.
+pub fn c_ident(prefix: &str, name: &str) -> String {
+    let mut ident = prefix.to_owned();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            ident.push(c);
+        } else if c == '_' {
+            ident.push_str("__");
+        } else {
+            write!(ident, "_u{:x}_", c as u32).unwrap();
+        }
+    }
+    ident
+}
+
+/// Gives each variable in a function a distinct identifier. A variable
+/// shadowing another of the same name gets a `_<n>` suffix, which `c_ident`
+/// can't produce, so it can't clash with any other variable.
+#[derive(Default)]
+pub struct VarNames {
+    /// The identifier of each variable, keyed by the span of its declaration
+    names: HashMap<Span, String>,
+    /// How many variables have been given each base identifier
+    counts: HashMap<String, usize>,
+}
+
+impl VarNames {
+    /// Forgets every variable, for the start of a new function
+    pub fn clear(&mut self) {
+        self.names.clear();
+        self.counts.clear();
+    }
+
+    /// Names a newly declared variable
+    pub fn declare(&mut self, name: &Ident) -> String {
+        let base = c_ident("v_", &name.name);
+        let count = self.counts.entry(base.clone()).or_insert(0);
+        *count += 1;
+        let ident = if *count == 1 { base } else { format!("{}_{}", base, count) };
+        self.names.insert(name.span, ident.clone());
+        ident
+    }
+
+    /// The identifier of the variable declared at decl
+    pub fn get(&self, decl: Span) -> String {
+        self.names[&decl].clone()
+    }
+}
+
+/// Writes bytes as a C string literal. Anything but printable ASCII is written
+/// as an octal escape, which can't run into the next char like a hex one can.
+fn c_string(s: &str) -> String {
+    let mut lit = "\"".to_owned();
+    for &b in s.as_bytes() {
+        match b {
+            b'"' | b'\\' => { lit.push('\\'); lit.push(b as char); }
+            // Avoid trigraphs
+            b'?' => lit.push_str("\\?"),
+            0x20..=0x7e => lit.push(b as char),
+            _ => write!(lit, "\\{:03o}", b).unwrap(),
+        }
+    }
+    lit.push('"');
+    lit
+}
+
+fn c_type(ty: &Type) -> String {
+    match *ty {
+        Type::Int => "int64_t".to_owned(),
+        Type::Float => "double".to_owned(),
+        Type::String => "hl2_str".to_owned(),
+        Type::Bool => "bool".to_owned(),
+        Type::Void => "void".to_owned(),
+        Type::Array(_) => "hl2_array *".to_owned(),
+        Type::Struct(ref name) => format!("struct {} *", c_ident("s_", name)),
+    }
+}
+
+/// Declares a variable of type ty, without a space after a pointer's `*`
+fn c_decl(ty: &Type, name: &str) -> String {
+    let ty = c_type(ty);
+    if ty.ends_with('*') { format!("{}{}", ty, name) } else { format!("{} {}", ty, name) }
+}
+
+impl<'a> Emitter<'a> {
+    fn line(&mut self, line: String) {
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(&line);
+        self.out.push('\n');
+    }
+
+    fn ty(&self, expr: &Expr) -> &'a Type {
+        self.type_at(expr.span())
+    }
+
+    fn type_at(&self, span: Span) -> &'a Type {
+        let types: &'a Types = self.types;
+        &types[&span]
+    }
+
+    /// A C string literal of where span starts, for runtime errors
+    fn loc(&self, span: Span) -> String {
+        c_string(&format!("{}:{}", self.filename, self.map.location(span.start)))
+    }
+
+    /// Evaluates a C expression into a new temporary, returning its name
+    fn temp(&mut self, ty: &Type, val: String) -> String {
+        let name = format!("t{}", self.next_temp);
+        self.next_temp += 1;
+        let decl = c_decl(ty, &name);
+        self.line(format!("{} = {};", decl, val));
+        name
+    }
+
+    fn var(&self, name: &Ident) -> String {
+        self.vars.get(self.bindings[&name.span])
+    }
+
+    /// Emits statements in a C block, which the caller has opened
+    fn block(&mut self, block: &Block) {
+        self.indent += 1;
+        for stmt in block {
+            self.stmt(stmt);
+        }
+        self.indent -= 1;
+    }
+
+    /// Emits an if chain as nested ifs, since the conditions of else ifs may
+    /// need statements to evaluate them
+    fn if_chain(&mut self, arms: &[IfArm], else_body: &Option<Block>) {
+        let cond = self.expr(&arms[0].cond);
+        self.line(format!("if ({}) {{", cond));
+        self.block(&arms[0].body);
+        if arms.len() > 1 {
+            self.line("} else {".to_owned());
+            self.indent += 1;
+            self.if_chain(&arms[1..], else_body);
+            self.indent -= 1;
+        } else if let Some(ref else_body) = *else_body {
+            self.line("} else {".to_owned());
+            self.block(else_body);
+        }
+        self.line("}".to_owned());
+    }
+
+    /// Emits `if (!cond) break;` at the top of a loop
+    fn loop_cond(&mut self, cond: &Expr) {
+        self.indent += 1;
+        let cond = self.expr(cond);
+        self.line(format!("if (!{}) break;", cond));
+        self.indent -= 1;
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match *stmt {
+            Stmt::Declare { ref ty, ref name, ref init, .. } => {
+                let init = self.expr(init);
+                let name = self.vars.declare(name);
+                let decl = c_decl(ty, &name);
+                self.line(format!("{} = {};", decl, init));
+            }
+            Stmt::Assign { ref target, ref val, .. } => {
+                let val = self.expr(val);
+                let target = match *target {
+                    Expr::Var { ref name } => self.var(name),
+                    Expr::Index { ref base, ref index, span } => self.element(base, index, span),
+                    Expr::Field { ref base, ref field, .. } => {
+                        let base = self.expr(base);
+                        format!("{}->{}", base, c_ident("m_", &field.name))
+                    }
+                    _ => panic!("Invalid assignment target {:?}", target),
+                };
+                self.line(format!("{} = {};", target, val));
+            }
+            Stmt::Expr { expr: Expr::Call { ref callee, ref args, span }, .. } => {
+                self.call(callee, args, span, false);
+            }
+            Stmt::Expr { ref expr, .. } => { self.expr(expr); }
+            Stmt::If { ref arms, ref else_body, .. } => self.if_chain(arms, else_body),
+            Stmt::While { ref cond, ref body, .. } => {
+                self.line("while (1) {".to_owned());
+                self.loop_cond(cond);
+                self.loops.push(Loop { continue_label: None, continued: false });
+                self.block(body);
+                self.loops.pop();
+                self.line("}".to_owned());
+            }
+            Stmt::For { ref init, ref cond, ref step, ref body, .. } => {
+                // The init is scoped to the loop
+                self.line("{".to_owned());
+                self.indent += 1;
+                if let Some(ref init) = *init {
+                    self.stmt(init);
+                }
+                self.line("while (1) {".to_owned());
+                if let Some(ref cond) = *cond {
+                    self.loop_cond(cond);
+                }
+                let label = format!("continue_{}", self.next_label);
+                self.next_label += 1;
+                self.loops.push(Loop { continue_label: Some(label.clone()), continued: false });
+                self.indent += 1;
+                self.line("{".to_owned());
+                self.block(body);
+                self.line("}".to_owned());
+                if self.loops.pop().unwrap().continued {
+                    self.line(format!("{}:;", label));
+                }
+                if let Some(ref step) = *step {
+                    self.stmt(step);
+                }
+                self.indent -= 1;
+                self.line("}".to_owned());
+                self.indent -= 1;
+                self.line("}".to_owned());
+            }
+            Stmt::Break { .. } => self.line("break;".to_owned()),
+            Stmt::Continue { .. } => {
+                let lp = self.loops.last_mut().unwrap();
+                lp.continued = true;
+                let line = match lp.continue_label {
+                    Some(ref label) => format!("goto {};", label),
+                    None => "continue;".to_owned(),
+                };
+                self.line(line);
+            }
+            // Emitted before the code which uses them
+            Stmt::Fn { .. } | Stmt::Struct { .. } => (),
+            Stmt::Return { ref val, .. } => match *val {
+                Some(ref val) => {
+                    let val = self.expr(val);
+                    self.line(format!("return {};", val));
+                }
+                None => self.line("return;".to_owned()),
+            },
+        }
+    }
+
+    /// Evaluates the array and index of `base[index]`, returning the checked
+    /// element as a C lvalue
+    fn element(&mut self, base: &Expr, index: &Expr, span: Span) -> String {
+        let elem_ty = match *self.ty(base) {
+            Type::Array(ref elem_ty) => c_decl(elem_ty, "*"),
+            ref ty => panic!("Indexing into {}", ty.name()),
+        };
+        let base = self.expr(base);
+        let index = self.expr(index);
+        format!("(({}){}->data)[hl2_index({}, {}, {})]",
+                elem_ty, base, base, index, self.loc(span))
+    }
+
+    /// Emits a call, returning the temporary holding its result if it has one
+    /// and it's wanted
+    fn call(&mut self, callee: &Ident, args: &[Expr], span: Span, want_result: bool) -> String {
+        let args: Vec<_> = args.iter().map(|arg| (self.ty(arg), self.expr(arg))).collect();
+        // User defined functions shadow builtins
+        if let Some(c_name) = self.bindings.get(&callee.span).and_then(|decl| self.fns.get(decl)) {
+            let call = format!("{}({})", c_name,
+                               args.iter().map(|a| &a.1[..]).collect::<Vec<_>>().join(", "));
+            let loc = self.loc(span);
+            self.line(format!("hl2_enter({}, {});", c_string(&callee.name), loc));
+            let res = match self.types.get(&span) {
+                Some(ty) if *ty != Type::Void && want_result => self.temp(ty, call),
+                _ => {
+                    self.line(format!("{};", call));
+                    String::new()
+                }
+            };
+            self.line("hl2_leave();".to_owned());
+            return res;
+        }
+        match &callee.name[..] {
+            name @ "print" | name @ "println" => {
+                for (ty, arg) in args {
+                    let print = match *ty {
+                        Type::Int => "hl2_print_int",
+                        Type::Float => "hl2_print_float",
+                        Type::String => "hl2_print_str",
+                        Type::Bool => "hl2_print_bool",
+                        ref ty => panic!("Printing {}", ty.name()),
+                    };
+                    self.line(format!("{}({});", print, arg));
+                }
+                if name == "println" {
+                    self.line("putchar('\\n');".to_owned());
+                }
+                String::new()
+            }
+            "len" => self.temp(&Type::Int, format!("{}->len", args[0].1)),
+            name => panic!("Undefined function `{}`", name),
+        }
+    }
+
+    /// Emits statements evaluating expr, returning a C expression for its
+    /// value. The C expression is a variable, temporary or literal, so using it
+    /// has no side effects.
+    fn expr(&mut self, expr: &Expr) -> String {
+        match *expr {
+            Expr::Var { ref name } => self.var(name),
+            Expr::Lit { ref val, .. } => match *val {
+                Lit::Int(i) => format!("INT64_C({})", i),
+                Lit::Float(x) => format!("{:?}", x),
+                Lit::Str(ref s) => format!("((hl2_str){{ {}, {} }})", c_string(s), s.len()),
+                Lit::Bool(b) => b.to_string(),
+            },
+            Expr::Unary { op, op_span, ref operand, .. } => {
+                let ty = self.ty(operand);
+                let operand = self.expr(operand);
+                let val = match (op, ty) {
+                    (UnOp::Neg, &Type::Int) => format!("hl2_neg({}, {})", operand, self.loc(op_span)),
+                    (UnOp::Neg, _) => format!("-{}", operand),
+                    (UnOp::Not, _) => format!("!{}", operand),
+                };
+                self.temp(ty, val)
+            }
+            // && and || short circuit, only evaluating rhs if lhs doesn't
+            // decide the result
+            Expr::Binary { op: op @ BinOp::And, ref lhs, ref rhs, .. } |
+            Expr::Binary { op: op @ BinOp::Or, ref lhs, ref rhs, .. } => {
+                let lhs = self.expr(lhs);
+                let res = self.temp(&Type::Bool, lhs);
+                let not = if op == BinOp::Or { "!" } else { "" };
+                self.line(format!("if ({}{}) {{", not, res));
+                self.indent += 1;
+                let rhs = self.expr(rhs);
+                self.line(format!("{} = {};", res, rhs));
+                self.indent -= 1;
+                self.line("}".to_owned());
+                res
+            }
+            Expr::Binary { op, op_span, ref lhs, ref rhs, span } => {
+                let operand_ty = self.ty(lhs);
+                let lhs = self.expr(lhs);
+                let rhs = self.expr(rhs);
+                let checked = match op {
+                    BinOp::Add => "hl2_add",
+                    BinOp::Sub => "hl2_sub",
+                    BinOp::Mul => "hl2_mul",
+                    BinOp::Div => "hl2_div",
+                    BinOp::Mod => "hl2_mod",
+                    _ => "",
+                };
+                let val = match (operand_ty, op) {
+                    (&Type::Int, _) if !checked.is_empty() =>
+                        format!("{}({}, {}, {})", checked, lhs, rhs, self.loc(op_span)),
+                    (&Type::Float, BinOp::Mod) => format!("hl2_fmod({}, {})", lhs, rhs),
+                    (&Type::String, BinOp::Add) => format!("hl2_str_concat({}, {})", lhs, rhs),
+                    (&Type::String, BinOp::Eq) => format!("hl2_str_eq({}, {})", lhs, rhs),
+                    (&Type::String, BinOp::Ne) => format!("!hl2_str_eq({}, {})", lhs, rhs),
+                    _ => format!("{} {} {}", lhs, op.symbol(), rhs),
+                };
+                let ty = self.type_at(span);
+                self.temp(ty, val)
+            }
+            Expr::Call { ref callee, ref args, span } => self.call(callee, args, span, true),
+            Expr::Array { ref elems, span } => {
+                let elem_ty = match self.types[&span] {
+                    Type::Array(ref elem_ty) => elem_ty,
+                    ref ty => panic!("Array literal of type {}", ty.name()),
+                };
+                let elems: Vec<_> = elems.iter().map(|elem| self.expr(elem)).collect();
+                let arr = self.temp(self.type_at(span),
+                                    format!("hl2_array_new({}, sizeof({}))",
+                                            elems.len(), c_type(elem_ty)));
+                for (i, elem) in elems.iter().enumerate() {
+                    self.line(format!("(({}){}->data)[{}] = {};",
+                                      c_decl(elem_ty, "*"), arr, i, elem));
+                }
+                arr
+            }
+            Expr::Index { ref base, ref index, span } => {
+                let elem = self.element(base, index, span);
+                self.temp(self.type_at(span), elem)
+            }
+            Expr::StructLit { ref name, ref fields, span } => {
+                let vals: Vec<_> = fields.iter().map(|f| self.expr(&f.1)).collect();
+                let s = c_ident("s_", &name.name);
+                let res = self.temp(self.type_at(span), format!("hl2_alloc(sizeof(struct {}))", s));
+                for (field, val) in fields.iter().zip(vals) {
+                    self.line(format!("{}->{} = {};", res, c_ident("m_", &field.0.name), val));
+                }
+                res
+            }
+            Expr::Field { ref base, ref field, span } => {
+                let base = self.expr(base);
+                self.temp(self.type_at(span), format!("{}->{}", base, c_ident("m_", &field.name)))
+            }
+        }
+    }
+
+    fn signature(&self, def: &FnDef) -> String {
+        let params: Vec<_> = def.params.iter()
+            .map(|p| c_decl(&p.ty, &c_ident("v_", &p.name.name)))
+            .collect();
+        let name = &self.fns[&def.name.span];
+        let params = if params.is_empty() { "void".to_owned() } else { params.join(", ") };
+        format!("static {}({})", c_decl(&def.ret, name), params)
+    }
+
+    fn function(&mut self, def: &FnDef) {
+        self.vars.clear();
+        for param in &def.params {
+            self.vars.declare(&param.name);
+        }
+        let signature = self.signature(def);
+        self.line(format!("{} {{", signature));
+        self.block(&def.body);
+        self.line("}".to_owned());
+    }
+
+    fn struct_def(&mut self, def: &StructDef) {
+        self.line(format!("struct {} {{", c_ident("s_", &def.name.name)));
+        for field in &def.fields {
+            let decl = c_decl(&field.ty, &c_ident("m_", &field.name.name));
+            self.line(format!("    {};", decl));
+        }
+        self.line("};".to_owned());
+    }
+}
+
+/// Translates a program which has passed type checking to a self-contained C99
+/// file. The top level of the program becomes `main`.
+pub fn emit(program: &Block, bindings: &Bindings, types: &Types, filename: &str,
+            map: &SourceMap) -> String {
+    let mut defs = Vec::new();
+    let mut structs = Vec::new();
+    for stmt in program {
+        match *stmt {
+            Stmt::Fn { ref def } => defs.push(def),
+            Stmt::Struct { ref def } => structs.push(def),
+            _ => (),
+        }
+    }
+    let mut emitter = Emitter {
+        bindings, types, filename, map,
+        vars: VarNames::default(),
+        fns: defs.iter().map(|def| (def.name.span, c_ident("f_", &def.name.name))).collect(),
+        next_temp: 0,
+        next_label: 0,
+        loops: Vec::new(),
+        out: String::new(),
+        indent: 0,
+    };
+    emitter.line(format!("/* Compiled from {} by the hl2 compiler. Build with `cc -std=c99`. */",
+                         filename.replace("*/", "* /")));
+    emitter.out.push_str(&runtime());
+    // Structs refer to each other by pointer, so can be defined in any order
+    // once they're all declared
+    if !structs.is_empty() {
+        emitter.line(String::new());
+        for def in &structs {
+            emitter.line(format!("struct {};", c_ident("s_", &def.name.name)));
+        }
+    }
+    for def in &structs {
+        emitter.line(String::new());
+        emitter.struct_def(def);
+    }
+    if !defs.is_empty() {
+        emitter.line(String::new());
+        for def in &defs {
+            let signature = emitter.signature(def);
+            emitter.line(format!("{};", signature));
+        }
+    }
+    for def in &defs {
+        emitter.line(String::new());
+        emitter.function(def);
+    }
+    emitter.line(String::new());
+    emitter.line("int main(void) {".to_owned());
+    emitter.vars.clear();
+    emitter.block(program);
+    emitter.line("    return 0;".to_owned());
+    emitter.line("}".to_owned());
+    emitter.out
+}
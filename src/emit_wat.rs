@@ -0,0 +1,648 @@
+use ast::{BinOp, Block, Expr, FnDef, Ident, IfArm, Lit, Stmt, StructDef, Type, UnOp};
+use emit_c::{c_ident, VarNames};
+use interpret::MAX_CALL_DEPTH;
+use resolve::Bindings;
+use source::SourceMap;
+use token::Span;
+use typeck::Types;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::mem;
+
+/// The imports and helper functions every module needs
+const RUNTIME: &str = include_str!("runtime/hl2_rt.wat");
+
+/// Where the data segment starts. Address 0 is left as a null pointer.
+const DATA_START: u32 = 8;
+
+/// Messages the runtime reports errors with, as the names of the globals
+/// holding their addresses
+const MESSAGES: &[(&str, &str)] = &[
+    ("msg_out_of_memory", "Out of memory"),
+    ("msg_div_zero", "Division by zero"),
+    ("msg_overflow_add", "Integer overflow in `+`"),
+    ("msg_overflow_sub", "Integer overflow in `-`"),
+    ("msg_overflow_mul", "Integer overflow in `*`"),
+    ("msg_overflow_div", "Integer overflow in `/`"),
+    ("msg_overflow_mod", "Integer overflow in `%`"),
+];
+
+/// The labels a loop's break and continue branch to
+struct Loop {
+    break_label: String,
+    continue_label: String,
+}
+
+/// Emits a WebAssembly text module. Expressions leave their value on the
+/// operand stack, and variables are locals of the function declaring them.
+struct Emitter<'a> {
+    bindings: &'a Bindings,
+    types: &'a Types,
+    filename: &'a str,
+    map: &'a SourceMap<'a>,
+    structs: HashMap<&'a str, &'a StructDef>,
+    /// The WAT name of each function, keyed by the span of its name
+    fns: HashMap<Span, String>,
+    vars: VarNames,
+    /// The locals of the current function, in the order they're declared
+    locals: Vec<(String, &'static str)>,
+    next_temp: usize,
+    next_label: usize,
+    /// Innermost loop last
+    loops: Vec<Loop>,
+    /// The address of each string constant in the data segment
+    strings: HashMap<String, u32>,
+    /// The data segment, in address order
+    data: Vec<(u32, Vec<u8>)>,
+    next_addr: u32,
+    out: String,
+    indent: usize,
+}
+
+fn wasm_type(ty: &Type) -> &'static str {
+    match *ty {
+        Type::Int => "i64",
+        Type::Float => "f64",
+        Type::Bool | Type::String | Type::Array(_) | Type::Struct(_) => "i32",
+        Type::Void => panic!("Void has no WebAssembly type"),
+    }
+}
+
+/// A float constant, written so it reads back exactly
+fn wasm_float(x: f64) -> String {
+    if x.is_nan() {
+        "nan".to_owned()
+    } else if x.is_infinite() {
+        if x < 0.0 { "-inf".to_owned() } else { "inf".to_owned() }
+    } else {
+        format!("{:?}", x)
+    }
+}
+
+/// Writes bytes as a WAT string. Anything but printable ASCII is written as a
+/// hex escape.
+fn wasm_string(bytes: &[u8]) -> String {
+    let mut lit = "\"".to_owned();
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => { lit.push('\\'); lit.push(b as char); }
+            0x20..=0x7e => lit.push(b as char),
+            _ => write!(lit, "\\{:02x}", b).unwrap(),
+        }
+    }
+    lit.push('"');
+    lit
+}
+
+impl<'a> Emitter<'a> {
+    fn line(&mut self, line: String) {
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(&line);
+        self.out.push('\n');
+    }
+
+    fn ins(&mut self, ins: &str) {
+        self.line(ins.to_owned());
+    }
+
+    fn ty(&self, expr: &Expr) -> &'a Type {
+        let types: &'a Types = self.types;
+        &types[&expr.span()]
+    }
+
+    /// The address of a string constant, adding it to the data segment if
+    /// it's new
+    fn string(&mut self, s: &str) -> u32 {
+        if let Some(&addr) = self.strings.get(s) {
+            return addr;
+        }
+        let addr = self.next_addr;
+        let mut bytes = (s.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(s.as_bytes());
+        // Keep lengths 4 byte aligned
+        self.next_addr += (bytes.len() as u32 + 3) & !3;
+        self.data.push((addr, bytes));
+        self.strings.insert(s.to_owned(), addr);
+        addr
+    }
+
+    /// Pushes the address of a string of where span starts, for runtime
+    /// errors
+    fn loc(&mut self, span: Span) {
+        let loc = format!("{}:{}", self.filename, self.map.location(span.start));
+        let addr = self.string(&loc);
+        self.line(format!("i32.const {}", addr));
+    }
+
+    fn new_label(&mut self, name: &str) -> String {
+        self.next_label += 1;
+        format!("${}{}", name, self.next_label)
+    }
+
+    /// Pops a value of WebAssembly type ty into a new temporary, returning its
+    /// name
+    fn temp(&mut self, ty: &'static str) -> String {
+        let name = format!("$t{}", self.next_temp);
+        self.next_temp += 1;
+        self.locals.push((name.clone(), ty));
+        self.line(format!("local.set {}", name));
+        name
+    }
+
+    /// Gives a newly declared variable or parameter a name
+    fn declare(&mut self, name: &Ident) -> String {
+        format!("${}", self.vars.declare(name))
+    }
+
+    fn var(&self, name: &Ident) -> String {
+        format!("${}", self.vars.get(self.bindings[&name.span]))
+    }
+
+    /// The offset of a field from the start of a struct
+    fn field_offset(&self, base: &Expr, field: &Ident) -> usize {
+        let def = match *self.ty(base) {
+            Type::Struct(ref name) => self.structs[&name[..]],
+            ref ty => panic!("Field of {}", ty.name()),
+        };
+        8 * def.fields.iter().position(|f| f.name.name == field.name).unwrap()
+    }
+
+    /// Loads or stores a value of type ty at the address on the stack
+    fn memory_op(&mut self, op: &str, ty: &Type, offset: usize) {
+        let ins = format!("{}.{}", wasm_type(ty), op);
+        if offset == 0 {
+            self.line(ins);
+        } else {
+            self.line(format!("{} offset={}", ins, offset));
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.indent += 1;
+        for stmt in block {
+            self.stmt(stmt);
+        }
+        self.indent -= 1;
+    }
+
+    /// Branches to label unless cond is true
+    fn branch_unless(&mut self, cond: &Expr, label: &str) {
+        self.expr(cond);
+        self.ins("i32.eqz");
+        self.line(format!("br_if {}", label));
+    }
+
+    /// Each arm is a block which its condition branches out of, skipping its
+    /// body
+    fn if_chain(&mut self, arms: &[IfArm], else_body: &Option<Block>) {
+        let end = self.new_label("if");
+        self.line(format!("block {}", end));
+        self.indent += 1;
+        for arm in arms {
+            self.ins("block");
+            self.indent += 1;
+            self.branch_unless(&arm.cond, "0");
+            self.indent -= 1;
+            self.block(&arm.body);
+            self.indent += 1;
+            self.line(format!("br {}", end));
+            self.indent -= 1;
+            self.ins("end");
+        }
+        if let Some(ref else_body) = *else_body {
+            self.indent -= 1;
+            self.block(else_body);
+            self.indent += 1;
+        }
+        self.indent -= 1;
+        self.ins("end");
+    }
+
+    fn loop_body(&mut self, body: &Block, break_label: &str, continue_label: &str) {
+        self.loops.push(Loop {
+            break_label: break_label.to_owned(),
+            continue_label: continue_label.to_owned(),
+        });
+        self.block(body);
+        self.loops.pop();
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match *stmt {
+            Stmt::Declare { ref ty, ref name, ref init, .. } => {
+                self.expr(init);
+                let var = self.declare(name);
+                self.locals.push((var.clone(), wasm_type(ty)));
+                self.line(format!("local.set {}", var));
+            }
+            Stmt::Assign { ref target, ref val, .. } => match *target {
+                Expr::Var { ref name } => {
+                    self.expr(val);
+                    let var = self.var(name);
+                    self.line(format!("local.set {}", var));
+                }
+                Expr::Index { ref base, ref index, span } => {
+                    let ty = self.ty(val);
+                    self.expr(val);
+                    let val = self.temp(wasm_type(ty));
+                    self.element(base, index, span);
+                    self.line(format!("local.get {}", val));
+                    self.memory_op("store", ty, 0);
+                }
+                Expr::Field { ref base, ref field, .. } => {
+                    let ty = self.ty(val);
+                    self.expr(val);
+                    let val = self.temp(wasm_type(ty));
+                    self.expr(base);
+                    self.line(format!("local.get {}", val));
+                    let offset = self.field_offset(base, field);
+                    self.memory_op("store", ty, offset);
+                }
+                _ => panic!("Invalid assignment target {:?}", target),
+            },
+            Stmt::Expr { ref expr, .. } => {
+                self.expr(expr);
+                if *self.ty(expr) != Type::Void {
+                    self.ins("drop");
+                }
+            }
+            Stmt::If { ref arms, ref else_body, .. } => self.if_chain(arms, else_body),
+            Stmt::While { ref cond, ref body, .. } => {
+                let (end, start) = (self.new_label("break"), self.new_label("continue"));
+                self.line(format!("block {}", end));
+                self.indent += 1;
+                self.line(format!("loop {}", start));
+                self.indent += 1;
+                self.branch_unless(cond, &end);
+                self.indent -= 1;
+                self.loop_body(body, &end, &start);
+                self.indent += 1;
+                self.line(format!("br {}", start));
+                self.indent -= 1;
+                self.ins("end");
+                self.indent -= 1;
+                self.ins("end");
+            }
+            Stmt::For { ref init, ref cond, ref step, ref body, .. } => {
+                if let Some(ref init) = *init {
+                    self.stmt(init);
+                }
+                let (end, start, next) = (self.new_label("break"), self.new_label("loop"),
+                                          self.new_label("continue"));
+                self.line(format!("block {}", end));
+                self.indent += 1;
+                self.line(format!("loop {}", start));
+                self.indent += 1;
+                if let Some(ref cond) = *cond {
+                    self.branch_unless(cond, &end);
+                }
+                // continue branches out of the body, to the step
+                self.line(format!("block {}", next));
+                self.loop_body(body, &end, &next);
+                self.ins("end");
+                if let Some(ref step) = *step {
+                    self.stmt(step);
+                }
+                self.line(format!("br {}", start));
+                self.indent -= 1;
+                self.ins("end");
+                self.indent -= 1;
+                self.ins("end");
+            }
+            Stmt::Break { .. } => {
+                let label = self.loops.last().unwrap().break_label.clone();
+                self.line(format!("br {}", label));
+            }
+            Stmt::Continue { .. } => {
+                let label = self.loops.last().unwrap().continue_label.clone();
+                self.line(format!("br {}", label));
+            }
+            // Emitted at the top level of the module
+            Stmt::Fn { .. } | Stmt::Struct { .. } => (),
+            Stmt::Return { ref val, .. } => {
+                if let Some(ref val) = *val {
+                    self.expr(val);
+                }
+                self.ins("return");
+            }
+        }
+    }
+
+    /// Pushes the address of an element of an array, checking it's in bounds
+    fn element(&mut self, base: &Expr, index: &Expr, span: Span) {
+        self.expr(base);
+        self.expr(index);
+        self.loc(span);
+        self.ins("call $index");
+    }
+
+    fn call(&mut self, callee: &Ident, args: &[Expr], span: Span) {
+        // User defined functions shadow builtins
+        let decl = self.bindings.get(&callee.span);
+        if let Some(name) = decl.and_then(|decl| self.fns.get(decl)).cloned() {
+            for arg in args {
+                self.expr(arg);
+            }
+            let msg = self.string(&format!("Stack overflow calling `{}`", callee.name));
+            self.line(format!("i32.const {}", msg));
+            self.loc(span);
+            self.ins("call $enter");
+            self.line(format!("call {}", name));
+            self.ins("call $leave");
+            return;
+        }
+        match &callee.name[..] {
+            name @ "print" | name @ "println" => {
+                // Every argument is evaluated before anything is printed
+                let mut vals = Vec::new();
+                for arg in args {
+                    let ty = self.ty(arg);
+                    self.expr(arg);
+                    if args.len() > 1 {
+                        vals.push((Some(self.temp(wasm_type(ty))), ty));
+                    } else {
+                        vals.push((None, ty));
+                    }
+                }
+                for (val, ty) in vals {
+                    if let Some(val) = val {
+                        self.line(format!("local.get {}", val));
+                    }
+                    self.ins(match *ty {
+                        Type::Int => "call $print_int",
+                        Type::Float => "call $print_float",
+                        Type::Bool => "call $print_bool",
+                        Type::String => "call $print_str",
+                        ref ty => panic!("Printing {}", ty.name()),
+                    });
+                }
+                if name == "println" {
+                    self.ins("call $println");
+                }
+            }
+            "len" => {
+                self.expr(&args[0]);
+                self.ins("i64.load32_u");
+            }
+            name => panic!("Undefined function `{}`", name),
+        }
+    }
+
+    /// Applies a binary operator to the two values on the stack
+    fn binary_op(&mut self, op: BinOp, operand_ty: &Type, op_span: Span) {
+        let ins = match (operand_ty, op) {
+            (&Type::Int, _) => match op {
+                BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod => {
+                    self.loc(op_span);
+                    match op {
+                        BinOp::Add => "call $add",
+                        BinOp::Sub => "call $sub",
+                        BinOp::Mul => "call $mul",
+                        BinOp::Div => "call $div",
+                        _ => "call $mod",
+                    }
+                }
+                BinOp::Eq => "i64.eq",
+                BinOp::Ne => "i64.ne",
+                BinOp::Gt => "i64.gt_s",
+                BinOp::Lt => "i64.lt_s",
+                BinOp::Ge => "i64.ge_s",
+                BinOp::Le => "i64.le_s",
+                BinOp::And | BinOp::Or => panic!("Operator `{}` on ints", op.symbol()),
+            },
+            (&Type::Float, _) => match op {
+                BinOp::Add => "f64.add",
+                BinOp::Sub => "f64.sub",
+                BinOp::Mul => "f64.mul",
+                BinOp::Div => "f64.div",
+                BinOp::Mod => "call $fmod",
+                BinOp::Eq => "f64.eq",
+                BinOp::Ne => "f64.ne",
+                BinOp::Gt => "f64.gt",
+                BinOp::Lt => "f64.lt",
+                BinOp::Ge => "f64.ge",
+                BinOp::Le => "f64.le",
+                BinOp::And | BinOp::Or => panic!("Operator `{}` on floats", op.symbol()),
+            },
+            (&Type::String, BinOp::Add) => "call $str_concat",
+            (&Type::String, BinOp::Eq) => "call $str_eq",
+            (&Type::String, BinOp::Ne) => {
+                self.ins("call $str_eq");
+                "i32.eqz"
+            }
+            (&Type::Bool, BinOp::Eq) => "i32.eq",
+            (&Type::Bool, BinOp::Ne) => "i32.ne",
+            _ => panic!("Operator `{}` on {}", op.symbol(), operand_ty.name()),
+        };
+        self.ins(ins);
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match *expr {
+            Expr::Var { ref name } => {
+                let var = self.var(name);
+                self.line(format!("local.get {}", var));
+            }
+            Expr::Lit { ref val, .. } => match *val {
+                Lit::Int(i) => self.line(format!("i64.const {}", i)),
+                Lit::Float(x) => self.line(format!("f64.const {}", wasm_float(x))),
+                Lit::Str(ref s) => {
+                    let addr = self.string(s);
+                    self.line(format!("i32.const {}", addr));
+                }
+                Lit::Bool(b) => self.line(format!("i32.const {}", b as u8)),
+            },
+            Expr::Unary { op, op_span, ref operand, .. } => {
+                self.expr(operand);
+                match (op, self.ty(operand)) {
+                    (UnOp::Neg, &Type::Int) => {
+                        self.loc(op_span);
+                        self.ins("call $neg");
+                    }
+                    (UnOp::Neg, _) => self.ins("f64.neg"),
+                    (UnOp::Not, _) => self.ins("i32.eqz"),
+                }
+            }
+            // && and || only evaluate rhs if lhs doesn't decide the result
+            Expr::Binary { op: op @ BinOp::And, ref lhs, ref rhs, .. } |
+            Expr::Binary { op: op @ BinOp::Or, ref lhs, ref rhs, .. } => {
+                self.expr(lhs);
+                self.ins("if (result i32)");
+                self.indent += 1;
+                if op == BinOp::And { self.expr(rhs) } else { self.ins("i32.const 1") }
+                self.indent -= 1;
+                self.ins("else");
+                self.indent += 1;
+                if op == BinOp::And { self.ins("i32.const 0") } else { self.expr(rhs) }
+                self.indent -= 1;
+                self.ins("end");
+            }
+            Expr::Binary { op, op_span, ref lhs, ref rhs, .. } => {
+                self.expr(lhs);
+                self.expr(rhs);
+                let operand_ty = self.ty(lhs);
+                self.binary_op(op, operand_ty, op_span);
+            }
+            Expr::Call { ref callee, ref args, span } => self.call(callee, args, span),
+            Expr::Array { ref elems, .. } => {
+                self.line(format!("i32.const {}", 8 + 8 * elems.len()));
+                self.ins("call $alloc");
+                let arr = self.temp("i32");
+                self.line(format!("local.get {}", arr));
+                self.line(format!("i32.const {}", elems.len()));
+                self.ins("i32.store");
+                for (i, elem) in elems.iter().enumerate() {
+                    self.line(format!("local.get {}", arr));
+                    self.expr(elem);
+                    let ty = self.ty(elem);
+                    self.memory_op("store", ty, 8 + 8 * i);
+                }
+                self.line(format!("local.get {}", arr));
+            }
+            Expr::Index { ref base, ref index, span } => {
+                self.element(base, index, span);
+                let ty = self.ty(expr);
+                self.memory_op("load", ty, 0);
+            }
+            Expr::StructLit { ref name, ref fields, .. } => {
+                let def = self.structs[&name.name[..]];
+                self.line(format!("i32.const {}", 8 * def.fields.len()));
+                self.ins("call $alloc");
+                let ptr = self.temp("i32");
+                // Fields are evaluated in source order
+                for (field, val) in fields {
+                    let offset = 8 * def.fields.iter()
+                        .position(|f| f.name.name == field.name).unwrap();
+                    self.line(format!("local.get {}", ptr));
+                    self.expr(val);
+                    let ty = self.ty(val);
+                    self.memory_op("store", ty, offset);
+                }
+                self.line(format!("local.get {}", ptr));
+            }
+            Expr::Field { ref base, ref field, .. } => {
+                self.expr(base);
+                let offset = self.field_offset(base, field);
+                let ty = self.ty(expr);
+                self.memory_op("load", ty, offset);
+            }
+        }
+    }
+
+    /// Emits a function, or the top level of the program as `$main` if def is
+    /// None
+    fn function(&mut self, def: Option<&FnDef>, body: &Block) {
+        self.vars.clear();
+        self.locals.clear();
+        self.next_temp = 0;
+        let mut signature = match def {
+            Some(def) => self.fns[&def.name.span].clone(),
+            None => "$main (export \"main\")".to_owned(),
+        };
+        let params = def.map_or(&[][..], |def| &def.params[..]);
+        for param in params {
+            let name = self.declare(&param.name);
+            write!(signature, " (param {} {})", name, wasm_type(&param.ty)).unwrap();
+        }
+        let ret = def.map_or(&Type::Void, |def| &def.ret);
+        if *ret != Type::Void {
+            write!(signature, " (result {})", wasm_type(ret)).unwrap();
+        }
+
+        // Locals must be declared before the body, so emit it first
+        let out = mem::take(&mut self.out);
+        self.block(body);
+        // Every path returns, but validation doesn't know that
+        if *ret != Type::Void {
+            match body.last() {
+                Some(&Stmt::Return { .. }) => (),
+                _ => {
+                    self.indent += 1;
+                    self.ins("unreachable");
+                    self.indent -= 1;
+                }
+            }
+        }
+        let body = mem::replace(&mut self.out, out);
+
+        self.out.push('\n');
+        self.line(format!("(func {}", signature));
+        let locals = mem::take(&mut self.locals);
+        for (name, ty) in locals {
+            self.line(format!("  (local {} {})", name, ty));
+        }
+        self.out.push_str(&body);
+        // Close the function on its last line
+        self.out.pop();
+        self.out.push_str(")\n");
+    }
+}
+
+/// Translates a program which has passed type checking to a WebAssembly text
+/// module. The top level of the program becomes the exported function `main`.
+/// The host provides printing and error reporting, as described in
+/// `runtime/hl2_rt.wat`, and reads strings from the exported `memory`.
+pub fn emit(program: &Block, bindings: &Bindings, types: &Types, filename: &str,
+            map: &SourceMap) -> String {
+    let mut defs = Vec::new();
+    let mut structs = HashMap::new();
+    for stmt in program {
+        match *stmt {
+            Stmt::Fn { ref def } => defs.push(def),
+            Stmt::Struct { ref def } => { structs.insert(&def.name.name[..], def); }
+            _ => (),
+        }
+    }
+    let mut emitter = Emitter {
+        bindings, types, filename, map, structs,
+        fns: defs.iter()
+            .map(|def| (def.name.span, format!("${}", c_ident("f_", &def.name.name))))
+            .collect(),
+        vars: VarNames::default(),
+        locals: Vec::new(),
+        next_temp: 0,
+        next_label: 0,
+        loops: Vec::new(),
+        strings: HashMap::new(),
+        data: Vec::new(),
+        next_addr: DATA_START,
+        out: String::new(),
+        indent: 1,
+    };
+    let messages: Vec<_> = MESSAGES.iter().map(|&(name, msg)| (name, emitter.string(msg)))
+        .collect();
+    for def in &defs {
+        emitter.function(Some(def), &def.body);
+    }
+    emitter.function(None, program);
+    let code = mem::take(&mut emitter.out);
+
+    // The heap starts after the data segment
+    let heap = (emitter.next_addr + 7) & !7;
+    let pages = ((heap + 0xffff) >> 16).max(1);
+    emitter.indent = 0;
+    emitter.line(format!(";; Compiled from {} by the hl2 compiler",
+                         filename.replace('\n', " ")));
+    emitter.ins("(module");
+    emitter.out.push_str(RUNTIME);
+    emitter.indent = 1;
+    emitter.out.push('\n');
+    emitter.line(format!("(memory (export \"memory\") {})", pages));
+    emitter.line(format!("(global $heap (mut i32) (i32.const {}))", heap));
+    emitter.ins("(global $depth (mut i32) (i32.const 0))");
+    emitter.line(format!("(global $max_depth i32 (i32.const {}))", MAX_CALL_DEPTH));
+    for (name, addr) in messages {
+        emitter.line(format!("(global ${} i32 (i32.const {}))", name, addr));
+    }
+    let data = mem::take(&mut emitter.data);
+    for (addr, bytes) in data {
+        emitter.line(format!("(data (i32.const {}) {})", addr, wasm_string(&bytes)));
+    }
+    emitter.out.push_str(&code);
+    // Close the module on the last function's line
+    emitter.out.pop();
+    emitter.out.push_str(")\n");
+    emitter.out
+}
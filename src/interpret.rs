@@ -0,0 +1,511 @@
+use ast::{BinOp, Block, Expr, FnDef, Ident, Lit, Stmt, StructDef, UnOp};
+use diagnostic::Diagnostic;
+use token::Span;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::mem;
+use std::panic;
+use std::rc::Rc;
+use std::thread;
+
+type EvalRes = Result<Value, RuntimeErr>;
+type ExecRes = Result<Flow, RuntimeErr>;
+
+/// The elements of an array. Arrays are shared by reference, so assigning to
+/// an element is visible through every variable holding the array.
+pub type ArrayRef = Rc<RefCell<Vec<Value>>>;
+
+/// An instance of a struct. Like arrays, structs are shared by reference.
+#[derive(PartialEq, Debug)]
+pub struct StructVal {
+    pub name: String,
+    /// Field names and values, in the order the struct declares them
+    pub fields: RefCell<Vec<(String, Value)>>,
+}
+
+impl StructVal {
+    /// The index of a field, which the type checker has made sure exists
+    pub fn field_index(&self, field: &str, span: Span) -> Result<usize, RuntimeErr> {
+        self.fields.borrow().iter().position(|f| f.0 == field).ok_or_else(
+            || RuntimeErr::Point(format!("No field `{}` on struct `{}`", field, self.name), span))
+    }
+}
+
+/// How deep calls can nest before we give up and report a stack overflow
+pub const MAX_CALL_DEPTH: usize = 512;
+
+/// Native stack set aside for each hl2 call. Calls recurse through several
+/// Rust frames per enclosing block and expression: in a debug build, a call
+/// nested in a for, a while and two ifs takes about 25 KiB. This leaves room
+/// for much deeper nesting.
+const STACK_PER_CALL: usize = 256 << 10;
+
+/// The native stack the interpreter runs on, enough for MAX_CALL_DEPTH calls.
+/// Pages are only committed as the stack grows, so most of it costs nothing.
+pub const STACK_SIZE: usize = MAX_CALL_DEPTH * STACK_PER_CALL;
+
+/// Runs f on a thread with a STACK_SIZE stack, so the interpreter reports
+/// deep recursion as a stack overflow error rather than crashing
+pub fn with_stack<T: Send, F: FnOnce() -> T + Send>(f: F) -> T {
+    thread::scope(|scope| {
+        thread::Builder::new().stack_size(STACK_SIZE).spawn_scoped(scope, f)
+            .expect("Failed to spawn the interpreter thread")
+            .join()
+            .unwrap_or_else(|e| panic::resume_unwind(e))
+    })
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum RuntimeErr {
+    /// Just an err message
+    Raw(String),
+    /// Err message with the span of the code that caused the error
+    Point(String, Span),
+}
+
+impl RuntimeErr {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        match *self {
+            RuntimeErr::Raw(ref s) => Diagnostic::error(s.clone()),
+            RuntimeErr::Point(ref s, span) => Diagnostic::spanned(s.clone(), span),
+        }
+    }
+}
+
+/// A runtime value
+#[derive(PartialEq, Debug, Clone)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    Array(ArrayRef),
+    Struct(Rc<StructVal>),
+    /// The result of calling a function which doesn't return anything
+    Void,
+}
+
+impl Value {
+    /// The name of this value's type, for error messages
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "string",
+            Value::Bool(_) => "bool",
+            Value::Array(_) => "array",
+            Value::Struct(_) => "struct",
+            Value::Void => "void",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Str(ref s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Array(ref elems) => {
+                write!(f, "[")?;
+                for (i, elem) in elems.borrow().iter().enumerate() {
+                    if i > 0 { write!(f, ", ")?; }
+                    write!(f, "{}", elem)?;
+                }
+                write!(f, "]")
+            }
+            Value::Struct(ref s) => {
+                write!(f, "{} {{", s.name)?;
+                for (i, (name, val)) in s.fields.borrow().iter().enumerate() {
+                    write!(f, "{} {}: {}", if i > 0 { "," } else { "" }, name, val)?;
+                }
+                write!(f, " }}")
+            }
+            Value::Void => write!(f, "void"),
+        }
+    }
+}
+
+/// What to do after executing a statement
+#[derive(PartialEq, Debug, Clone)]
+enum Flow {
+    /// Carry on with the next statement
+    Next,
+    /// Unwind to the enclosing function call
+    Return(Value),
+    /// Unwind to the enclosing loop, and leave it
+    Break,
+    /// Unwind to the enclosing loop, and start its next iteration
+    Continue,
+}
+
+/// Walks the AST, executing it as it goes.
+pub struct Interpreter<'a, W: Write> {
+    out: W,
+    /// Innermost scope last. Only contains the scopes of the current function.
+    scopes: Vec<HashMap<String, Value>>,
+    functions: HashMap<&'a str, &'a FnDef>,
+    structs: HashMap<&'a str, &'a StructDef>,
+    call_depth: usize,
+}
+
+impl<'a, W: Write> Interpreter<'a, W> {
+    pub fn new(out: W) -> Interpreter<'a, W> {
+        Interpreter { out, scopes: Vec::new(), functions: HashMap::new(),
+                      structs: HashMap::new(), call_depth: 0 }
+    }
+
+    fn lookup(&mut self, name: &Ident) -> Result<&mut Value, RuntimeErr> {
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some(v) = scope.get_mut(&name.name) {
+                return Ok(v);
+            }
+        }
+        Err(RuntimeErr::Point(format!("Undefined variable `{}`", name.name), name.span))
+    }
+
+    /// Executes a block in a new scope
+    fn exec_block(&mut self, block: &'a Block) -> ExecRes {
+        self.scopes.push(HashMap::new());
+        let mut res = Ok(Flow::Next);
+        for stmt in block {
+            res = self.exec(stmt);
+            if res != Ok(Flow::Next) { break; }
+        }
+        self.scopes.pop();
+        res
+    }
+
+    fn exec(&mut self, stmt: &'a Stmt) -> ExecRes {
+        match *stmt {
+            Stmt::Declare { ref name, ref init, .. } => {
+                let val = self.eval(init)?;
+                self.scopes.last_mut().unwrap().insert(name.name.clone(), val);
+                Ok(Flow::Next)
+            }
+            Stmt::Assign { ref target, ref val, .. } => {
+                let val = self.eval(val)?;
+                let var = match *target {
+                    Expr::Var { ref name } => self.lookup(name)?,
+                    Expr::Index { ref base, ref index, span } => {
+                        let (elems, ix) = self.eval_index(base, index, span)?;
+                        elems.borrow_mut()[ix] = val;
+                        return Ok(Flow::Next);
+                    }
+                    Expr::Field { ref base, ref field, .. } => {
+                        let s = self.eval_struct(base)?;
+                        let ix = s.field_index(&field.name, field.span)?;
+                        s.fields.borrow_mut()[ix].1 = val;
+                        return Ok(Flow::Next);
+                    }
+                    _ => panic!("Invalid assignment target {:?}", target),
+                };
+                if var.type_name() != val.type_name() {
+                    return Err(RuntimeErr::Point(
+                        format!("Cannot assign {} to variable of type {}",
+                                val.type_name(), var.type_name()), target.span()));
+                }
+                *var = val;
+                Ok(Flow::Next)
+            }
+            Stmt::Expr { ref expr, .. } => self.eval(expr).map(|_| Flow::Next),
+            Stmt::If { ref arms, ref else_body, .. } => {
+                for arm in arms {
+                    if self.eval_cond(&arm.cond)? {
+                        return self.exec_block(&arm.body);
+                    }
+                }
+                match *else_body {
+                    Some(ref else_body) => self.exec_block(else_body),
+                    None => Ok(Flow::Next),
+                }
+            }
+            Stmt::While { ref cond, ref body, .. } => {
+                while self.eval_cond(cond)? {
+                    match self.exec_block(body)? {
+                        Flow::Next | Flow::Continue => (),
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+                }
+                Ok(Flow::Next)
+            }
+            Stmt::For { ref init, ref cond, ref step, ref body, .. } => {
+                // The init is scoped to the loop
+                self.scopes.push(HashMap::new());
+                let res = self.exec_for(init, cond, step, body);
+                self.scopes.pop();
+                res
+            }
+            Stmt::Break { .. } => Ok(Flow::Break),
+            Stmt::Continue { .. } => Ok(Flow::Continue),
+            // Functions and structs are registered before the program runs
+            Stmt::Fn { .. } | Stmt::Struct { .. } => Ok(Flow::Next),
+            Stmt::Return { ref val, .. } => Ok(Flow::Return(match *val {
+                Some(ref val) => self.eval(val)?,
+                None => Value::Void,
+            })),
+        }
+    }
+
+    /// Runs a for loop, in a scope already pushed for its init
+    fn exec_for(&mut self, init: &'a Option<Box<Stmt>>, cond: &'a Option<Expr>,
+                step: &'a Option<Box<Stmt>>, body: &'a Block) -> ExecRes {
+        if let Some(ref init) = *init {
+            self.exec(init)?;
+        }
+        loop {
+            if let Some(ref cond) = *cond {
+                if !self.eval_cond(cond)? { break; }
+            }
+            match self.exec_block(body)? {
+                Flow::Next | Flow::Continue => (),
+                Flow::Break => break,
+                flow => return Ok(flow),
+            }
+            if let Some(ref step) = *step {
+                self.exec(step)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Evaluates the condition of an if or while, which must be a bool
+    fn eval_cond(&mut self, cond: &'a Expr) -> Result<bool, RuntimeErr> {
+        match self.eval(cond)? {
+            Value::Bool(b) => Ok(b),
+            v => Err(RuntimeErr::Point(
+                format!("Condition must be a bool, got {}", v.type_name()), cond.span())),
+        }
+    }
+
+    /// Evaluates the array and index of `base[index]`, checking the index is
+    /// in bounds
+    fn eval_index(&mut self, base: &'a Expr, index: &'a Expr, span: Span)
+                  -> Result<(ArrayRef, usize), RuntimeErr> {
+        let elems = match self.eval(base)? {
+            Value::Array(elems) => elems,
+            v => return Err(RuntimeErr::Point(
+                format!("Cannot index into {}", v.type_name()), base.span())),
+        };
+        let ix = match self.eval(index)? {
+            Value::Int(ix) => ix,
+            v => return Err(RuntimeErr::Point(
+                format!("Index must be an int, got {}", v.type_name()), index.span())),
+        };
+        let ix = check_bounds(&elems, ix, span)?;
+        Ok((elems, ix))
+    }
+
+    /// Evaluates the base of a field access, which must be a struct
+    fn eval_struct(&mut self, base: &'a Expr) -> Result<Rc<StructVal>, RuntimeErr> {
+        match self.eval(base)? {
+            Value::Struct(s) => Ok(s),
+            v => Err(RuntimeErr::Point(
+                format!("Cannot access a field of {}", v.type_name()), base.span())),
+        }
+    }
+
+    fn call(&mut self, callee: &Ident, args: &'a [Expr], span: Span) -> EvalRes {
+        let mut vals = Vec::new();
+        for arg in args {
+            vals.push(self.eval(arg)?);
+        }
+        if let Some(&def) = self.functions.get(&callee.name[..]) {
+            return self.call_fn(def, vals, span);
+        }
+        match &callee.name[..] {
+            name @ "print" | name @ "println" => {
+                let res = vals.iter()
+                    .try_for_each(|a| write!(self.out, "{}", a))
+                    .and_then(|_| if name == "println" { writeln!(self.out) } else { Ok(()) });
+                res.map_err(|e| RuntimeErr::Raw(format!("Failed to write output: {}", e)))?;
+                Ok(Value::Void)
+            }
+            "len" => match vals.first() {
+                Some(Value::Array(elems)) => Ok(Value::Int(elems.borrow().len() as i64)),
+                _ => Err(RuntimeErr::Point("`len` expects an array".to_owned(), span)),
+            },
+            name => Err(RuntimeErr::Point(format!("Undefined function `{}`", name), callee.span)),
+        }
+    }
+
+    fn call_fn(&mut self, def: &'a FnDef, args: Vec<Value>, span: Span) -> EvalRes {
+        if self.call_depth == MAX_CALL_DEPTH {
+            return Err(RuntimeErr::Point(
+                format!("Stack overflow calling `{}`", def.name.name), span));
+        }
+        let mut frame = HashMap::new();
+        for (param, arg) in def.params.iter().zip(args) {
+            frame.insert(param.name.name.clone(), arg);
+        }
+        let caller_scopes = mem::replace(&mut self.scopes, vec![frame]);
+        self.call_depth += 1;
+        let res = self.exec_block(&def.body);
+        self.call_depth -= 1;
+        self.scopes = caller_scopes;
+        match res? {
+            Flow::Return(val) => Ok(val),
+            // break and continue can't escape a loop, checked by the resolver
+            _ => Ok(Value::Void),
+        }
+    }
+
+    fn eval(&mut self, expr: &'a Expr) -> EvalRes {
+        match *expr {
+            Expr::Var { ref name } => self.lookup(name).map(|v| v.clone()),
+            Expr::Lit { ref val, .. } => Ok(match *val {
+                Lit::Int(i) => Value::Int(i),
+                Lit::Float(x) => Value::Float(x),
+                Lit::Str(ref s) => Value::Str(s.clone()),
+                Lit::Bool(b) => Value::Bool(b),
+            }),
+            Expr::Unary { op, op_span, ref operand, .. } => {
+                let operand = self.eval(operand)?;
+                unary_op(op, op_span, operand)
+            }
+            Expr::Binary { op, op_span, ref lhs, ref rhs, .. } => {
+                let lhs = self.eval(lhs)?;
+                // && and || short circuit, only evaluating rhs if lhs doesn't
+                // decide the result
+                match (op, &lhs) {
+                    (BinOp::And, &Value::Bool(false)) => return Ok(Value::Bool(false)),
+                    (BinOp::Or, &Value::Bool(true)) => return Ok(Value::Bool(true)),
+                    _ => (),
+                }
+                let rhs = self.eval(rhs)?;
+                binary_op(lhs, op, op_span, rhs)
+            }
+            Expr::Call { ref callee, ref args, span } => self.call(callee, args, span),
+            Expr::Array { ref elems, .. } => {
+                let mut vals = Vec::new();
+                for elem in elems {
+                    vals.push(self.eval(elem)?);
+                }
+                Ok(Value::Array(Rc::new(RefCell::new(vals))))
+            }
+            Expr::Index { ref base, ref index, span } => {
+                let (elems, ix) = self.eval_index(base, index, span)?;
+                let elem = elems.borrow()[ix].clone();
+                Ok(elem)
+            }
+            Expr::StructLit { ref name, ref fields, .. } => {
+                // Fields are evaluated in the order they're written, then laid
+                // out in the order the struct declares them
+                let mut vals = Vec::new();
+                for (field, val) in fields {
+                    vals.push((&field.name, self.eval(val)?));
+                }
+                let def = self.structs.get(&name.name[..]).ok_or_else(
+                    || RuntimeErr::Point(format!("Undefined struct `{}`", name.name), name.span))?;
+                let mut laid_out = Vec::new();
+                for f in &def.fields {
+                    match vals.iter().position(|v| *v.0 == f.name.name) {
+                        Some(ix) => laid_out.push((f.name.name.clone(), vals.swap_remove(ix).1)),
+                        None => return Err(RuntimeErr::Point(
+                            format!("Missing field `{}`", f.name.name), expr.span())),
+                    }
+                }
+                Ok(Value::Struct(Rc::new(StructVal {
+                    name: name.name.clone(),
+                    fields: RefCell::new(laid_out),
+                })))
+            }
+            Expr::Field { ref base, ref field, .. } => {
+                let s = self.eval_struct(base)?;
+                let ix = s.field_index(&field.name, field.span)?;
+                let val = s.fields.borrow()[ix].1.clone();
+                Ok(val)
+            }
+        }
+    }
+
+    /// Runs a whole program
+    pub fn run(&mut self, program: &'a Block) -> Result<(), RuntimeErr> {
+        for stmt in program {
+            match *stmt {
+                Stmt::Fn { ref def } => { self.functions.insert(&def.name.name, def); }
+                Stmt::Struct { ref def } => { self.structs.insert(&def.name.name, def); }
+                _ => (),
+            }
+        }
+        self.exec_block(program).map(|_| ())
+    }
+}
+
+/// Checks an index is in bounds for an array, converting it to a usize
+pub fn check_bounds(elems: &ArrayRef, ix: i64, span: Span) -> Result<usize, RuntimeErr> {
+    let len = elems.borrow().len();
+    if ix < 0 || ix as usize >= len {
+        return Err(RuntimeErr::Point(
+            format!("Index {} is out of bounds for an array of length {}", ix, len), span));
+    }
+    Ok(ix as usize)
+}
+
+pub fn unary_op(op: UnOp, op_span: Span, operand: Value) -> EvalRes {
+    match (op, operand) {
+        (UnOp::Neg, Value::Int(a)) => a.checked_neg().map(Value::Int).ok_or_else(
+            || RuntimeErr::Point("Integer overflow in `-`".to_owned(), op_span)),
+        (UnOp::Neg, Value::Float(a)) => Ok(Value::Float(-a)),
+        (UnOp::Not, Value::Bool(a)) => Ok(Value::Bool(!a)),
+        (op, a) => Err(RuntimeErr::Point(format!("Operator `{}` cannot be applied to {}",
+                                                 op.symbol(), a.type_name()), op_span)),
+    }
+}
+
+pub fn binary_op(lhs: Value, op: BinOp, op_span: Span, rhs: Value) -> EvalRes {
+    let err = |msg: String| RuntimeErr::Point(msg, op_span);
+    match (lhs, rhs) {
+        (Value::Int(a), Value::Int(b)) => {
+            let overflow = || err(format!("Integer overflow in `{}`", op.symbol()));
+            match op {
+                BinOp::Add => a.checked_add(b).map(Value::Int).ok_or_else(overflow),
+                BinOp::Sub => a.checked_sub(b).map(Value::Int).ok_or_else(overflow),
+                BinOp::Mul => a.checked_mul(b).map(Value::Int).ok_or_else(overflow),
+                BinOp::Div | BinOp::Mod if b == 0 => Err(err("Division by zero".to_owned())),
+                BinOp::Div => a.checked_div(b).map(Value::Int).ok_or_else(overflow),
+                BinOp::Mod => a.checked_rem(b).map(Value::Int).ok_or_else(overflow),
+                BinOp::Eq => Ok(Value::Bool(a == b)),
+                BinOp::Ne => Ok(Value::Bool(a != b)),
+                BinOp::Gt => Ok(Value::Bool(a > b)),
+                BinOp::Lt => Ok(Value::Bool(a < b)),
+                BinOp::Ge => Ok(Value::Bool(a >= b)),
+                BinOp::Le => Ok(Value::Bool(a <= b)),
+                _ => Err(err(format!("Operator `{}` cannot be applied to ints", op.symbol()))),
+            }
+        }
+        (Value::Float(a), Value::Float(b)) => match op {
+            BinOp::Add => Ok(Value::Float(a + b)),
+            BinOp::Sub => Ok(Value::Float(a - b)),
+            BinOp::Mul => Ok(Value::Float(a * b)),
+            BinOp::Div => Ok(Value::Float(a / b)),
+            BinOp::Mod => Ok(Value::Float(a % b)),
+            BinOp::Eq => Ok(Value::Bool(a == b)),
+            BinOp::Ne => Ok(Value::Bool(a != b)),
+            BinOp::Gt => Ok(Value::Bool(a > b)),
+            BinOp::Lt => Ok(Value::Bool(a < b)),
+            BinOp::Ge => Ok(Value::Bool(a >= b)),
+            BinOp::Le => Ok(Value::Bool(a <= b)),
+            _ => Err(err(format!("Operator `{}` cannot be applied to floats", op.symbol()))),
+        },
+        (Value::Str(a), Value::Str(b)) => match op {
+            BinOp::Add => Ok(Value::Str(a + &b)),
+            BinOp::Eq => Ok(Value::Bool(a == b)),
+            BinOp::Ne => Ok(Value::Bool(a != b)),
+            _ => Err(err(format!("Operator `{}` cannot be applied to strings", op.symbol()))),
+        },
+        (Value::Bool(a), Value::Bool(b)) => match op {
+            BinOp::And => Ok(Value::Bool(a && b)),
+            BinOp::Or => Ok(Value::Bool(a || b)),
+            BinOp::Eq => Ok(Value::Bool(a == b)),
+            BinOp::Ne => Ok(Value::Bool(a != b)),
+            _ => Err(err(format!("Operator `{}` cannot be applied to bools", op.symbol()))),
+        },
+        (a, b) => Err(err(format!("Operator `{}` cannot be applied to {} and {}",
+                                  op.symbol(), a.type_name(), b.type_name()))),
+    }
+}
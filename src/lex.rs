@@ -1,27 +1,34 @@
-use token::Token;
+use token::{Point, Span, Token, TokenType};
+use diagnostic::Diagnostic;
 use std::str::CharIndices;
 
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub enum LexErr {
     /// Just an err message
     Raw(String),
-    /// Msg, file, line num
-    Point(String, String, usize),
+    /// Err message with the span of the offending source
+    Point(String, Span),
 }
 
 impl LexErr {
-    fn into_point(self, file: String, line_num: usize) -> Self {
+    fn into_point(self, span: Span) -> Self {
         match self {
-            LexErr::Raw(s) => LexErr::Point(s, file, line_num),
+            LexErr::Raw(s) => LexErr::Point(s, span),
             _ => panic!("Trying to convert a LexErr::Point into a LexErr::Point!"),
         }
     }
 
-    /// Print this error
-    pub fn print_formatted(&self) {
+    pub fn span(&self) -> Option<Span> {
         match *self {
-            LexErr::Raw(ref s) => error_raw!("{}", s),
-            LexErr::Point(ref s, ref f, ref l) => error_point!(s, f, l),
+            LexErr::Raw(_) => None,
+            LexErr::Point(_, span) => Some(span),
+        }
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        match *self {
+            LexErr::Raw(ref s) => Diagnostic::error(s.clone()),
+            LexErr::Point(ref s, span) => Diagnostic::spanned(s.clone(), span),
         }
     }
 }
@@ -31,7 +38,7 @@ impl LexErr {
 pub fn try_punc(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
     let (ix, c) = cix.clone().next().unwrap();
     match c {
-        ';' | '(' | ')' | '{' | '}' | ',' => {
+        ';' | '(' | ')' | '{' | '}' | '[' | ']' | ',' | '.' | ':' => {
             cix.next().unwrap();
             Ok(Some(Token::new_punc(ix, ix + 1)))
         }
@@ -39,13 +46,66 @@ pub fn try_punc(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
     }
 }
 
+/// Advances cix up to (but not including) the char at byte offset end
+fn advance_to(cix: &mut CharIndices, end: usize) {
+    while cix.clone().next().is_some_and(|(ix, _)| ix < end) {
+        cix.next();
+    }
+}
+
+/// Lexes a `// line` comment, up to but not including the newline, or a
+/// `/* block */` comment. Block comments nest.
+pub fn try_comment(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
+    let start = cix.clone().next().unwrap().0;
+    let as_str = cix.as_str();
+    if as_str.starts_with("//") {
+        let len = as_str.find('\n').unwrap_or(as_str.len());
+        advance_to(cix, start + len);
+        Ok(Some(Token::new_comment(start, start + len)))
+    } else if as_str.starts_with("/*") {
+        // Delimiters are ascii, so it's fine to scan bytes
+        let bytes = as_str.as_bytes();
+        let mut depth = 0;
+        let mut ix = 0;
+        while ix + 1 < bytes.len() {
+            match (bytes[ix], bytes[ix + 1]) {
+                (b'/', b'*') => { depth += 1; ix += 2; }
+                (b'*', b'/') => {
+                    depth -= 1;
+                    ix += 2;
+                    if depth == 0 {
+                        advance_to(cix, start + ix);
+                        return Ok(Some(Token::new_comment(start, start + ix)));
+                    }
+                }
+                _ => ix += 1,
+            }
+        }
+        advance_to(cix, start + as_str.len());
+        Err(LexErr::Raw("Unterminated block comment".to_owned()))
+    } else { Ok(None) }
+}
+
 pub fn try_op(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
     let mut clone = cix.clone();
     let (ix, c) = clone.next().unwrap();
     let (tok, consumed) = match c {
-        '*' | '/' | '+' | '-' | '%' => {
+        '*' | '/' | '+' | '%' => {
             (Token::new_op(ix, ix+1), 1)
         }
+        '!' => {
+            match clone.next() {
+                Some((_, '=')) => (Token::new_op(ix, ix+2), 2),
+                _ => (Token::new_op(ix, ix+1), 1)
+            }
+        }
+        '-' => {
+            match clone.next() {
+                // Return type arrow
+                Some((_, '>')) => (Token::new_op(ix, ix+2), 2),
+                _ => (Token::new_op(ix, ix+1), 1)
+            }
+        }
         '=' | '>' | '<' => {
             match clone.next() {
                 None => return Err(LexErr::Raw(format!("Unexpected EOF at operator `{}`.", c))),
@@ -62,9 +122,9 @@ pub fn try_op(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
         }
         '|' => {
             match clone.next() {
-                None => return Err(LexErr::Raw("Unexpected EOF at operator `{}`.".to_owned())),
+                None => return Err(LexErr::Raw(format!("Unexpected EOF at operator `{}`.", c))),
                 Some((_, '|')) => (Token::new_op(ix, ix+2), 2),
-                _ => return Err(LexErr::Raw(format!("Bitwise | operator is not supported."))),
+                _ => return Err(LexErr::Raw("Bitwise | operator is not supported.".to_owned())),
             }
         }
         _ => return Ok(None)
@@ -73,49 +133,13 @@ pub fn try_op(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
     Ok(Some(tok))
 }
 
-pub fn try_key(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
-    if cix.as_str().starts_with("if") {
-        match cix.clone().skip(2).next() {
-            None => Err(LexErr::Raw(format!("Unexpected EOF after `if`"))),
-            Some((end, c)) => {
-                if c.is_whitespace() || c == '(' {
-                    for _ in 0..2 { cix.next(); } // Consume the 'if'
-                    Ok(Some(Token::new_key(end - 2, end)))
-                } else { Ok(None) }
-            }
-        }
-    } else if cix.as_str().starts_with("else") {
-        match cix.clone().skip(4).next() {
-            None => Err(LexErr::Raw(format!("Unexpected EOF after `else`"))),
-            Some((end, c)) => {
-                if c.is_whitespace() || c == '(' {
-                    for _ in 0..4 { cix.next(); } // Consume the 'else'
-                    Ok(Some(Token::new_key(end - 4, end)))
-                } else { Ok(None) }
-            }
-        }
-    } else if cix.as_str().starts_with("while") {
-        match cix.clone().skip(5).next() {
-            None => Err(LexErr::Raw(format!("Unexpected EOF after `while`"))),
-            Some((end, c)) => {
-                if c.is_whitespace() || c == '(' {
-                    for _ in 0..5 { cix.next(); } // Consume the 'while'
-                    Ok(Some(Token::new_key(end - 5, end)))
-                } else { Ok(None) }
-            }
-        }
-    } else {
-        Ok(None)
-    }
-}
-
 pub fn try_string_lit(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
     if cix.clone().next().unwrap().1 == '"' {
         let (start, _) = cix.next().unwrap();
         // Keep consuming until we hit another unescaped "
         let mut escaped = false;
         let mut end = None;
-        while let Some((ix, c)) = cix.next() {
+        for (ix, c) in cix.by_ref() {
             if escaped {
                 escaped = false;
                 continue;
@@ -134,129 +158,241 @@ pub fn try_string_lit(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
     } else { Ok(None) }
 }
 
-pub fn try_num_lit(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
-    let mut clone = cix.clone();
-    let (start, first) = clone.next().unwrap();
-    if first.is_digit(10) || first == '-' {
-        // Consume until we hit a non-digit
-        let mut num_consumed = 1;
-        let mut consumed_decimal_point = false;
-        let mut end = start;
-        while let Some((ix, c)) = clone.next() {
-            if c.is_alphabetic() {
-                return Err(LexErr::Raw("Identifier cannot start with a number".to_owned()));
-            } else if c == '.' && consumed_decimal_point {
-                return Err(LexErr::Raw("Error: num literal contains more than 1 decimal place".to_owned()));
-            } else if !c.is_digit(10) && c != '.' {
-                break;
-            } else if c == '.' {
-                consumed_decimal_point = true;
-                continue;
-            }
-            num_consumed += 1;
-            end = ix;
+/// Decodes the escapes in a string literal, given its raw source including
+/// the quotes, and the offset of the literal in the source (for locating
+/// errors).
+pub fn decode_string_lit(raw: &str, offset: usize) -> Result<String, LexErr> {
+    let inner = &raw[1..raw.len() - 1];
+    let mut decoded = String::with_capacity(inner.len());
+    let mut chars = inner.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
         }
-        for _ in 0..num_consumed { cix.next(); } // Advance the iterator
-        Ok(Some(Token::new_num_lit(start, end)))
-    } else { Ok(None) }
+        // Span of the escape so far, for errors. +1 for the opening quote.
+        let span_to = |end: usize| Span { start: Point(offset + 1 + start),
+                                          end: Point(offset + 1 + end) };
+        let (esc_ix, esc) = chars.next().expect("String literal ends in a backslash");
+        let mut end = esc_ix + esc.len_utf8();
+        decoded.push(match esc {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            'u' => {
+                if chars.peek().map(|&(_, c)| c) != Some('{') {
+                    return Err(LexErr::Point(
+                        "Unicode escape must be of the form `\\u{XXXX}`".to_owned(), span_to(end)));
+                }
+                chars.next();
+                let mut hex = String::new();
+                loop {
+                    match chars.next() {
+                        Some((ix, '}')) => { end = ix + 1; break; }
+                        Some((ix, c)) => { hex.push(c); end = ix + c.len_utf8(); }
+                        None => return Err(LexErr::Point(
+                            "Unterminated unicode escape".to_owned(), span_to(end))),
+                    }
+                }
+                let code = if hex.is_empty() || hex.len() > 6 { None } else {
+                    u32::from_str_radix(&hex, 16).ok()
+                };
+                match code.and_then(std::char::from_u32) {
+                    Some(c) => c,
+                    None => return Err(LexErr::Point(
+                        format!("`{}` is not a valid unicode character", hex), span_to(end))),
+                }
+            }
+            c => return Err(LexErr::Point(format!("Unknown escape `\\{}`", c), span_to(end))),
+        });
+    }
+    Ok(decoded)
 }
 
-pub fn try_core_type(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
+/// Lexes an int or float literal. Ints can also be written in hex (`0x`),
+/// binary (`0b`) or octal (`0o`), floats can have an exponent (`1.5e-3`), and
+/// any digits can be separated with `_`.
+pub fn try_num_lit(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
+    let rest = cix.as_str();
+    let bytes = rest.as_bytes();
     let start = cix.clone().next().unwrap().0;
-    let as_str = cix.as_str();
-    if let Some((tok, to_consume)) = if as_str.starts_with("string") {
-        let len = "string".len();
-        Some((Token::new_core_type(start, start + len), len))
-    } else if as_str.starts_with("float") {
-        let len = "float".len();
-        Some((Token::new_core_type(start, start + len), len))
-    } else if as_str.starts_with("bool") {
-        let len = "bool".len();
-        Some((Token::new_core_type(start, start + len), len))
-    } else if as_str.starts_with("int") {
-        let len = "int".len();
-        Some((Token::new_core_type(start, start + len), len))
-    } else { None } {
-        for _ in 0..to_consume { cix.next(); }
-        Ok(Some(tok))
+    if !bytes[0].is_ascii_digit() {
+        return Ok(None);
+    }
+    let mut len = 0;
+    // The end of a run of digits (and separators) starting at from
+    let digits_end = |from: usize| from + bytes[from..].iter()
+        .take_while(|&&b| b == b'_' || b.is_ascii_digit()).count();
+
+    let radix = match rest.get(..2) {
+        Some("0x") => 16,
+        Some("0b") => 2,
+        Some("0o") => 8,
+        _ => 10,
+    };
+    let mut is_float = false;
+    if radix != 10 {
+        // Take every alphanumeric, so bad digits are reported rather than lexed
+        // as the start of an identifier
+        let digits_start = 2;
+        len = digits_start + bytes[digits_start..].iter()
+            .take_while(|&&b| b == b'_' || b.is_ascii_alphanumeric()).count();
+        let digits = &rest[digits_start..len];
+        if let Some(c) = digits.chars().find(|&c| c != '_' && !c.is_digit(radix)) {
+            return Err(LexErr::Raw(format!("Invalid digit `{}` in base {} literal", c, radix)));
+        }
+        if digits.chars().all(|c| c == '_') {
+            return Err(LexErr::Raw(format!("Expected digits after `{}`",
+                                           &rest[digits_start - 2..digits_start])));
+        }
     } else {
-        Ok(None)
+        len = digits_end(len);
+        // A `.` is only a decimal point if a digit follows it
+        if bytes.get(len) == Some(&b'.') && bytes.get(len + 1).is_some_and(u8::is_ascii_digit) {
+            is_float = true;
+            len = digits_end(len + 1);
+        }
+        if let Some(b'e') | Some(b'E') = bytes.get(len) {
+            let mut exp_start = len + 1;
+            if let Some(b'+') | Some(b'-') = bytes.get(exp_start) {
+                exp_start += 1;
+            }
+            if !bytes.get(exp_start).is_some_and(u8::is_ascii_digit) {
+                return Err(LexErr::Raw("Expected digits in exponent".to_owned()));
+            }
+            is_float = true;
+            len = digits_end(exp_start);
+        }
+    }
+    if let Some(c) = rest[len..].chars().next().filter(|&c| c.is_alphanumeric() || c == '_') {
+        return Err(LexErr::Raw(format!("Unexpected `{}` after number literal", c)));
     }
-}
 
-pub fn try_bool_lit(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
-    let start = cix.clone().next().unwrap().0;
-    let as_str = cix.as_str();
-    if let Some((tok, to_consume)) = if as_str.starts_with("true") {
-        let len = "true".len();
-        Some((Token::new_bool_lit(start, start + len), len))
-    } else if as_str.starts_with("false") {
-        let len = "false".len();
-        Some((Token::new_bool_lit(start, start + len), len))
-    } else { None } {
-        for _ in 0..to_consume { cix.next(); }
-        Ok(Some(tok))
+    advance_to(cix, start + len);
+    Ok(Some(if is_float {
+        Token::new_float_lit(start, start + len)
     } else {
-        Ok(None)
+        Token::new_int_lit(start, start + len)
+    }))
+}
+
+/// Decodes an int literal, given its source and its offset in the source (for
+/// locating errors). The literal must fit in an int, i.e. 64 bits.
+pub fn decode_int_lit(raw: &str, offset: usize) -> Result<i64, LexErr> {
+    let (radix, digits) = match raw.get(..2) {
+        Some("0x") => (16, &raw[2..]),
+        Some("0b") => (2, &raw[2..]),
+        Some("0o") => (8, &raw[2..]),
+        _ => (10, raw),
+    };
+    i64::from_str_radix(&digits.replace('_', ""), radix).map_err(|_| LexErr::Point(
+        format!("Integer literal out of range: the largest int is {}", i64::MAX),
+        Span { start: Point(offset), end: Point(offset + raw.len()) }))
+}
+
+/// Decodes a float literal. See decode_int_lit.
+pub fn decode_float_lit(raw: &str, offset: usize) -> Result<f64, LexErr> {
+    match raw.replace('_', "").parse::<f64>() {
+        Ok(val) if val.is_finite() => Ok(val),
+        _ => Err(LexErr::Point("Float literal out of range".to_owned(),
+                               Span { start: Point(offset), end: Point(offset + raw.len()) })),
     }
 }
 
-pub fn try_ident(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
+/// Makes a token from its start and end
+type TokenCtor = fn(usize, usize) -> Token;
+
+/// Words which can't be used as identifiers, and how to make their tokens
+const RESERVED_WORDS: &[(&str, TokenCtor)] = &[
+    ("if", Token::new_key),
+    ("else", Token::new_key),
+    ("while", Token::new_key),
+    ("fn", Token::new_key),
+    ("struct", Token::new_key),
+    ("return", Token::new_key),
+    ("for", Token::new_key),
+    ("break", Token::new_key),
+    ("continue", Token::new_key),
+    ("string", Token::new_core_type),
+    ("int", Token::new_core_type),
+    ("float", Token::new_core_type),
+    ("bool", Token::new_core_type),
+    ("true", Token::new_bool_lit),
+    ("false", Token::new_bool_lit),
+];
+
+/// Lexes a whole word, then checks it against the reserved words, so that
+/// e.g. `integer` is an identifier rather than `int` followed by `eger`.
+pub fn try_word(cix: &mut CharIndices) -> Result<Option<Token>, LexErr> {
+    let rest = cix.as_str();
     let mut clone = cix.clone();
     let (start, first) = clone.next().unwrap();
-    if first.is_alphabetic() {
+    if first.is_alphabetic() || first == '_' {
         // Consume until we hit a non-alphanumeric
         let mut num_consumed = 1;
-        let mut end = start + 1;
-        while let Some((ix, c)) = clone.next() {
-            if !c.is_alphanumeric() { break; }
+        let mut end = start + first.len_utf8();
+        for (ix, c) in clone.by_ref() {
+            if !c.is_alphanumeric() && c != '_' { break; }
             num_consumed += 1;
-            end = ix + 1;
+            end = ix + c.len_utf8();
         }
         for _ in 0..num_consumed { cix.next(); } // Advance the iterator
-        Ok(Some(Token::new_ident(start, end)))
-
+        let word = &rest[..end - start];
+        Ok(Some(match RESERVED_WORDS.iter().find(|&&(w, _)| w == word) {
+            Some(&(_, new_token)) => new_token(start, end),
+            None => Token::new_ident(start, end),
+        }))
     } else { Ok(None) }
 }
 
 pub fn lex_token(cix: &mut CharIndices) -> Result<Token, LexErr> {
     if let Some(tok) = try_punc(cix)? {
         Ok(tok)
+    } else if let Some(tok) = try_comment(cix)? {
+        Ok(tok)
     } else if let Some(tok) = try_num_lit(cix)? {
         Ok(tok)
     } else if let Some(tok) = try_op(cix)? {
         Ok(tok)
-    } else if let Some(tok) = try_bool_lit(cix)? {
-        Ok(tok)
-    } else if let Some(tok) = try_core_type(cix)? {
-        Ok(tok)
-    } else if let Some(tok) = try_key(cix)? {
-        Ok(tok)
     } else if let Some(tok) = try_string_lit(cix)? {
         Ok(tok)
-    } else if let Some(tok) = try_ident(cix)? {
+    } else if let Some(tok) = try_word(cix)? {
         Ok(tok)
     } else {
-        return Err(LexErr::Raw("Unknown token".to_owned()));
+        Err(LexErr::Raw("Unknown token".to_owned()))
     }
 }
 
-pub fn lex(src: &str, file: &str) -> Result<Vec<Token>, LexErr> {
+/// Returns true if c ends a bad token, so lexing can carry on after an error
+fn is_sync_point(c: char) -> bool {
+    c.is_whitespace() || ";(){}[],".contains(c)
+}
+
+/// Lexes the whole source, dropping comments. See lex_with_trivia.
+pub fn lex(src: &str) -> (Vec<Token>, Vec<LexErr>) {
+    let (mut tokens, errs) = lex_with_trivia(src);
+    tokens.retain(|t| t.token_type != TokenType::Comment);
+    (tokens, errs)
+}
+
+/// Lexes the whole source, keeping comments as Comment tokens so tools like
+/// formatters can recover them. On an error, an Error token is recorded, the
+/// bad input is skipped up to the next whitespace or punctuation, and lexing
+/// carries on, so every error in the file is returned along with the tokens.
+pub fn lex_with_trivia(src: &str) -> (Vec<Token>, Vec<LexErr>) {
     if src.is_empty() {
-        return Err(LexErr::Raw("File is empty.".to_owned()));
+        return (Vec::new(), vec![LexErr::Point("File is empty.".to_owned(), Span::eof(src))]);
     }
 
     let mut tokens = Vec::new();
+    let mut errs = Vec::new();
     let mut char_ix = src.char_indices();
-    let mut line_num = 0;
 
-    while !char_ix.as_str().is_empty() {
-        // Check if this is a newline, and increment line_num
-        if char_ix.clone().next().unwrap().1 == '\n' {
-            char_ix.next();
-            line_num += 1;
-            continue;
-        } else if char_ix.clone().next().unwrap().1.is_whitespace() {
+    while let Some((start, c)) = char_ix.clone().next() {
+        if c.is_whitespace() {
             // Just consume whitespace
             char_ix.next();
             continue;
@@ -264,10 +400,33 @@ pub fn lex(src: &str, file: &str) -> Result<Vec<Token>, LexErr> {
 
         // Try lex a token
         match lex_token(&mut char_ix) {
-            Ok(tok) => tokens.push(tok),
-            Err(e) => return Err(e.into_point(file.to_string(), line_num)),
+            Ok(tok) => {
+                let (val, offset) = (tok.val(src), tok.start.0);
+                let decoded = match tok.token_type {
+                    TokenType::StringLit => decode_string_lit(val, offset).map(|_| ()),
+                    TokenType::IntLit => decode_int_lit(val, offset).map(|_| ()),
+                    TokenType::FloatLit => decode_float_lit(val, offset).map(|_| ()),
+                    _ => Ok(()),
+                };
+                if let Err(e) = decoded {
+                    errs.push(e);
+                }
+                tokens.push(tok)
+            }
+            Err(e) => {
+                // Skip at least the first char, then up to the next sync point
+                if char_ix.clone().next().map(|(ix, _)| ix) == Some(start) {
+                    char_ix.next();
+                }
+                while char_ix.clone().next().is_some_and(|(_, c)| !is_sync_point(c)) {
+                    char_ix.next();
+                }
+                let end = char_ix.clone().next().map_or(src.len(), |(ix, _)| ix);
+                tokens.push(Token::new_error(start, end));
+                errs.push(e.into_point(Span { start: Point(start), end: Point(end) }));
+            }
         }
     }
 
-    Ok(tokens)
+    (tokens, errs)
 }
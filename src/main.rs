@@ -2,51 +2,152 @@
 
 extern crate clap;
 extern crate colored;
+extern crate atty;
+#[cfg(test)]
+extern crate wasmparser;
+#[cfg(test)]
+extern crate wat;
 
 #[macro_use]
 mod output;
 mod token;
+mod source;
+mod diagnostic;
 mod lex;
 mod parse;
+mod ast;
+mod resolve;
+mod typeck;
+mod interpret;
+mod bytecode;
+mod compile;
+mod vm;
+mod emit_c;
+mod emit_asm;
+mod emit_wat;
 
-use std::io::{ErrorKind};
+use interpret::RuntimeErr;
+use std::io::{self, ErrorKind, Write};
+use std::process;
 use clap::{App, Arg};
 use colored::*;
 
 fn main() {
+    diagnostic::init_colour();
     let matches = App::new("hl2 compiler")
         .version("0.1")
         .about("Compiles .hl2 files")
         .arg(Arg::with_name("input-file")
                 .help("The file to compile")
-                .index(1).required(true))
+                .index(1).required_unless("asm-runtime"))
+        .arg(Arg::with_name("vm")
+                .long("vm")
+                .help("Runs the program on the bytecode VM instead of the tree walking interpreter"))
+        .arg(Arg::with_name("emit")
+                .long("emit")
+                .takes_value(true)
+                .possible_values(&["bytecode", "c", "asm", "wat"])
+                .help("Prints the compiled program instead of running it"))
+        .arg(Arg::with_name("asm-runtime")
+                .long("asm-runtime")
+                .help("Prints the C runtime to link with the output of `--emit asm`"))
         .get_matches();
+    if matches.is_present("asm-runtime") {
+        return print!("{}", emit_asm::runtime());
+    }
     let filename = matches.args.get("input-file").unwrap().vals[0].clone().into_string().unwrap();
+    if !run_file(&filename, matches.value_of("emit"), matches.is_present("vm"), io::stdout()) {
+        process::exit(1);
+    }
+}
 
-    match std::fs::read_to_string(&filename) {
-        Ok(source) => {
-            let tokens = lex::lex(&source, &filename);
-            if tokens.is_err() {
-                tokens.unwrap_err().print_formatted();
-                return;
-            }
-            let tokens = tokens.unwrap();
-            println!("Tokens: {:?}", tokens);
-            let parse_tree = parse::parse(&tokens[..], &source);
-            if parse_tree.is_err() {
-                parse_tree.unwrap_err().print_formatted(&filename, &source);
-                return;
+/// Compiles a file, then runs it or writes it out in the format given by
+/// emit. Returns false if any error was reported.
+fn run_file<W: Write + Send>(filename: &str, emit: Option<&str>, vm: bool, mut out: W) -> bool {
+    let source = match std::fs::read_to_string(filename) {
+        Ok(source) => source,
+        Err(error) => {
+            match error.kind() {
+                ErrorKind::NotFound => error_raw!("File `{}` not found", filename.blue()),
+                ErrorKind::PermissionDenied =>
+                    error_raw!("No read permissions for `{}`", filename.blue()),
+                _ => error_raw!("Unknown error when reading `{}`", filename.blue()),
             }
-            let parse_tree = parse_tree.unwrap();
-            println!("Parse tree: {:?}", parse_tree);
+            return false;
         }
-        Err(error) => match error.kind() {
-            ErrorKind::NotFound => error_raw!("File `{}` not found", filename.blue()),
-            ErrorKind::PermissionDenied => error_raw!("No read permissions for `{}`", filename.blue()),
-            _ => error_raw!("Unknown error when reading `{}`", filename.blue()),
+    };
+    let map = source::SourceMap::new(&source);
+    let (tokens, lex_errs) = lex::lex(&source);
+    for e in &lex_errs {
+        e.to_diagnostic().emit(filename, &map);
+    }
+    if tokens.is_empty() {
+        return lex_errs.is_empty();
+    }
+    // Parse even if lexing failed, to report syntax errors in the rest of the
+    // file
+    let (parse_tree, parse_errs) = parse::parse(&tokens[..], &source);
+    for e in &parse_errs {
+        // Errors at a bad token have already been reported
+        match *e {
+            parse::ParseErr::Point(_, span)
+                if lex_errs.iter().any(|e| e.span() == Some(span)) => (),
+            _ => e.to_diagnostic().emit(filename, &map),
+        }
+    }
+    if !lex_errs.is_empty() || !parse_errs.is_empty() {
+        return false;
+    }
+    let program = match ast::lower(&parse_tree, &source) {
+        Ok(program) => program,
+        Err(e) => {
+            e.to_diagnostic().emit(filename, &map);
+            return false;
+        }
+    };
+    let (bindings, resolve_errs) = resolve::resolve(&program);
+    if !resolve_errs.is_empty() {
+        for e in resolve_errs {
+            e.emit(filename, &map);
+        }
+        return false;
+    }
+    let (types, type_errs) = typeck::check(&program, &bindings);
+    if !type_errs.is_empty() {
+        for e in type_errs {
+            e.emit(filename, &map);
+        }
+        return false;
+    }
+    let emitted = match emit {
+        Some("bytecode") =>
+            Some(bytecode::disassemble(&compile::compile(&program, &bindings), &map)),
+        Some("c") => Some(emit_c::emit(&program, &bindings, &types, filename, &map)),
+        Some("asm") => Some(emit_asm::emit(&program, &bindings, &types, filename, &map)),
+        Some("wat") => Some(emit_wat::emit(&program, &bindings, &types, filename, &map)),
+        _ => None,
+    };
+    let res = if let Some(emitted) = emitted {
+        out.write_all(emitted.as_bytes()).map_err(|e| RuntimeErr::Raw(
+            format!("Failed to write output: {}", e)))
+    } else if vm {
+        let bytecode = compile::compile(&program, &bindings);
+        vm::Vm::new(&bytecode, &mut out).run()
+    } else {
+        interpret::with_stack(|| interpret::Interpreter::new(&mut out).run(&program))
+    };
+    let _ = out.flush();
+    match res {
+        Ok(()) => true,
+        Err(e) => {
+            e.to_diagnostic().emit(filename, &map);
+            false
         }
     }
 }
 
 #[cfg(all(feature = "nightly", test))]
 mod benches;
+
+#[cfg(test)]
+mod tests;
@@ -1,4 +1,5 @@
-use token::{Token, TokenType};
+use token::{Span, Token, TokenType};
+use diagnostic::Diagnostic;
 use std;
 
 type TokenIter<'a> = std::slice::Iter<'a, Token>;
@@ -8,27 +9,15 @@ type ParseRes = Result<Node, ParseErr>;
 pub enum ParseErr {
     /// Just an err message
     Raw(String),
-    /// Err message with token for location
-    Point(String, Token),
+    /// Err message with the span of the offending source
+    Point(String, Span),
 }
 
 impl ParseErr {
-    /// Print this error
-    pub fn print_formatted(&self, filename: &str, src: &str) {
+    pub fn to_diagnostic(&self) -> Diagnostic {
         match *self {
-            ParseErr::Raw(ref s) => error_raw!("{} - {}", filename, s),
-            ParseErr::Point(ref s, ref t) => {
-                // find the line num
-                let mut line_num = 1;
-                for (ix, c) in src.char_indices() {
-                    if ix == t.start.0 { break; }
-                    if c == '\n' {
-                        line_num += 1;
-                    }
-                }
-
-                error_point!(s, filename, line_num);
-            }
+            ParseErr::Raw(ref s) => Diagnostic::error(s.clone()),
+            ParseErr::Point(ref s, span) => Diagnostic::spanned(s.clone(), span),
         }
     }
 }
@@ -39,15 +28,30 @@ pub enum NTermType {
     Stmt,
     Declaration,
     Assignment,
+    Type,
     ParameterList,
     If,
     While,
+    For,
+    FunctionDef,
+    ParamDeclList,
+    StructDef,
+    Return,
+    /// `break` or `continue`
+    LoopControl,
 
     Atom,
     FunctionCall,
+    ArrayLiteral,
+    StructLiteral,
+    Index,
+    Field,
 
     // See grammar.bnf for these
-    Term0, Term1, Term2, Expression,
+    Unary, Term0, Term1, Term2, Term3, Expression,
+
+    /// Tokens skipped while recovering from a syntax error
+    Error,
 }
 
 #[derive(Eq, PartialEq, Debug, Clone)]
@@ -73,6 +77,11 @@ fn term(tok: Token) -> Node {
     }
 }
 
+/// The error for running out of tokens, pointing at the end of the source
+fn eof(src: &str) -> ParseErr {
+    ParseErr::Point("Unexpected EOF".to_owned(), Span::eof(src))
+}
+
 /// Returns the first token as a terminal given that its value matches the given
 /// string. Consumes the token if it matches.
 fn assert_term(tokens: &mut TokenIter, src: &str, expected: &str) -> ParseRes {
@@ -81,26 +90,60 @@ fn assert_term(tokens: &mut TokenIter, src: &str, expected: &str) -> ParseRes {
         Some(tok) => if tok.val(src) == expected {
             Ok(term(*tokens.next().unwrap()))
         } else {
-            Err(ParseErr::Point(format!("Expected {}, got {}", expected, tok.val(src)), *tok))
+            Err(ParseErr::Point(format!("Expected {}, got {}", expected, tok.val(src)), tok.span()))
         }
-        None => Err(ParseErr::Raw(format!("Expected {}, got EOF", expected))),
+        None => Err(ParseErr::Point(format!("Expected {}, got EOF", expected), Span::eof(src))),
     }
 }
 
 /// Returns the first token as a terminal given that its value matches the given
 /// string. Consumes the token if it matches.
-fn assert_term_with_type(tokens: &mut TokenIter, expected: TokenType) -> ParseRes {
+fn assert_term_with_type(tokens: &mut TokenIter, src: &str, expected: TokenType) -> ParseRes {
     let tok = tokens.clone().next();
     match tok {
         Some(tok) => if tok.token_type == expected {
             Ok(term(*tokens.next().unwrap()))
+        } else if expected == TokenType::Ident && is_reserved(tok.token_type) {
+            Err(ParseErr::Point(format!("`{}` is a reserved word and cannot be used as a name",
+                                        tok.val(src)), tok.span()))
         } else {
-            Err(ParseErr::Point(format!("Expected {:?}", expected), *tok))
+            Err(ParseErr::Point(format!("Expected {:?}", expected), tok.span()))
         }
-        None => Err(ParseErr::Raw(format!("Expected {:?}, got EOF", expected))),
+        None => Err(ParseErr::Point(format!("Expected {:?}, got EOF", expected), Span::eof(src))),
+    }
+}
+
+/// Consumes the opening bracket expected, returning its token so the closing
+/// bracket can be checked with assert_closing
+fn assert_opening(tokens: &mut TokenIter, src: &str, expected: &str) -> Result<Token, ParseErr> {
+    let tok = tokens.clone().next().copied();
+    assert_term(tokens, src, expected)?;
+    Ok(tok.unwrap())
+}
+
+/// Consumes the bracket closing open. If it's missing, the error points at open,
+/// since the closing bracket could belong anywhere after it.
+fn assert_closing(tokens: &mut TokenIter, src: &str, open: Token, expected: &str) -> ParseRes {
+    match tokens.clone().next() {
+        Some(tok) if tok.val(src) == expected => Ok(term(*tokens.next().unwrap())),
+        tok => Err(ParseErr::Point(
+            format!("Unclosed `{}`: expected `{}`, got {}", open.val(src), expected,
+                    tok.map_or("EOF".to_owned(), |tok| format!("`{}`", tok.val(src)))),
+            open.span())),
     }
 }
 
+/// Returns true if tokens of this type are lexed from reserved words
+fn is_reserved(token_type: TokenType) -> bool {
+    token_type == TokenType::Key || token_type == TokenType::CoreType ||
+        token_type == TokenType::BoolLit
+}
+
+/// Returns true if val is a unary op (Negation / not)
+fn is_unary_op(val: &str) -> bool {
+    val == "-" || val == "!"
+}
+
 /// Returns true if val is an op0 (Mul / div / mod)
 fn is_op0(val: &str) -> bool {
     val == "*" || val == "/" || val == "%"
@@ -113,110 +156,236 @@ fn is_op1(val: &str) -> bool {
 
 /// Returns true if val is an op2 (Comparison)
 fn is_op2(val: &str) -> bool {
-    val == "==" || val == ">" || val == "<" || val == ">=" || val == "<="
+    val == "==" || val == "!=" || val == ">" || val == "<" || val == ">=" || val == "<="
 }
 
-/// Returns true if val is an op3 (AND or OR)
+/// Returns true if val is an op3 (AND)
 fn is_op3(val: &str) -> bool {
-    val == "&&" || val == "||"
+    val == "&&"
+}
+
+/// Returns true if val is an op4 (OR)
+fn is_op4(val: &str) -> bool {
+    val == "||"
 }
 
 fn parse_function_call(tokens: &mut TokenIter, src: &str) -> ParseRes {
+    let callee = assert_term_with_type(tokens, src, TokenType::Ident)?;
+    let open = *tokens.next().unwrap(); // (
     Ok(Node {
         node_type: NodeType::NTerm(NTermType::FunctionCall),
         children: vec![
-            assert_term_with_type(tokens, TokenType::Ident)?,
-            assert_term(tokens, src, "(")?,
-            parse_parameter_list(tokens, src)?,
-            assert_term(tokens, src, ")")?],
+            callee,
+            term(open),
+            parse_parameter_list(tokens, src, ")")?,
+            assert_closing(tokens, src, open, ")")?],
     })
 }
 
+/// Parses any `[index]`s and `.field`s following base, nesting to the left so
+/// `a[i].x` is `(a[i]).x`
+fn parse_postfix(tokens: &mut TokenIter, src: &str, mut base: Node) -> ParseRes {
+    while let Some(&tok) = tokens.clone().next() {
+        base = match tok.val(src) {
+            "[" => {
+                tokens.next();
+                Node {
+                    node_type: NodeType::NTerm(NTermType::Index),
+                    children: vec![
+                        base,
+                        term(tok),
+                        parse_expression(tokens, src)?,
+                        assert_closing(tokens, src, tok, "]")?],
+                }
+            }
+            "." => {
+                tokens.next();
+                Node {
+                    node_type: NodeType::NTerm(NTermType::Field),
+                    children: vec![
+                        base,
+                        term(tok),
+                        assert_term_with_type(tokens, src, TokenType::Ident)?],
+                }
+            }
+            _ => break,
+        };
+    }
+    Ok(base)
+}
+
 fn parse_atom(tokens: &mut TokenIter, src: &str) -> ParseRes {
+    let atom = parse_primary(tokens, src)?;
+    parse_postfix(tokens, src, atom)
+}
+
+/// Parses a struct literal, e.g. `Point { x: 1, y: 2 }`
+fn parse_struct_literal(tokens: &mut TokenIter, src: &str) -> ParseRes {
+    let mut children = vec![term(*tokens.next().unwrap())]; // Struct name
+    let open = *tokens.next().unwrap(); // {
+    children.push(term(open));
+    // `field: value` pairs, separated by commas
+    while tokens.clone().next().is_some_and(|tok| tok.token_type == TokenType::Ident) {
+        children.push(term(*tokens.next().unwrap()));
+        children.push(assert_term(tokens, src, ":")?);
+        children.push(parse_expression(tokens, src)?);
+        match tokens.clone().next() {
+            Some(tok) if tok.val(src) == "," => children.push(term(*tokens.next().unwrap())),
+            _ => break,
+        }
+    }
+    children.push(assert_closing(tokens, src, open, "}")?);
+    Ok(Node {
+        node_type: NodeType::NTerm(NTermType::StructLiteral),
+        children,
+    })
+}
+
+/// Parses an atom without any indexing or field access
+fn parse_primary(tokens: &mut TokenIter, src: &str) -> ParseRes {
     let mut clone = tokens.clone();
+    match clone.clone().next() {
+        // Parenthesised expression
+        Some(&open) if open.val(src) == "(" => {
+            tokens.next();
+            return Ok(Node {
+                node_type: NodeType::NTerm(NTermType::Atom),
+                children: vec![
+                    term(open),
+                    parse_expression(tokens, src)?,
+                    assert_closing(tokens, src, open, ")")?],
+            });
+        }
+        Some(&open) if open.val(src) == "[" => {
+            tokens.next();
+            return Ok(Node {
+                node_type: NodeType::NTerm(NTermType::ArrayLiteral),
+                children: vec![
+                    term(open),
+                    parse_parameter_list(tokens, src, "]")?,
+                    assert_closing(tokens, src, open, "]")?],
+            });
+        }
+        _ => (),
+    }
     Ok(Node {
         node_type: NodeType::NTerm(NTermType::Atom),
         children: vec![
-            match clone.next().ok_or(ParseErr::Raw("Unexpected EOF".to_owned()))? {
-                // Either ident or function call
+            match clone.next().ok_or_else(|| eof(src))? {
+                // Either ident, function call or struct literal
                 tok if tok.token_type == TokenType::Ident => {
                     match clone.next() {
                         // Function call
                         Some(tok) if tok.val(src) == "(" => parse_function_call(tokens, src)?,
+                        Some(tok) if tok.val(src) == "{" => parse_struct_literal(tokens, src)?,
                         // Ident
                         _ => term(*tokens.next().unwrap())
                     }
                 }
                 // Literals
-                tok if tok.token_type == TokenType::NumLit => term(*tokens.next().unwrap()),
+                tok if tok.token_type == TokenType::IntLit => term(*tokens.next().unwrap()),
+                tok if tok.token_type == TokenType::FloatLit => term(*tokens.next().unwrap()),
                 tok if tok.token_type == TokenType::StringLit => term(*tokens.next().unwrap()),
                 tok if tok.token_type == TokenType::BoolLit => term(*tokens.next().unwrap()),
-                tok => return Err(ParseErr::Point("Expected identifier or literal".to_owned(), *tok))
+                // Already reported by the lexer
+                tok if tok.token_type == TokenType::Error => term(*tokens.next().unwrap()),
+                tok => return Err(ParseErr::Point(
+                    "Expected identifier, literal, `(` or `[`".to_owned(), tok.span()))
             }]
     })
 }
 
-fn parse_term0(tokens: &mut TokenIter, src: &str) -> ParseRes {
-    let atom = parse_atom(tokens, src)?;
+fn parse_unary(tokens: &mut TokenIter, src: &str) -> ParseRes {
     Ok(Node {
-        node_type: NodeType::NTerm(NTermType::Term0),
+        node_type: NodeType::NTerm(NTermType::Unary),
         children: match tokens.clone().next() {
-            Some(tok) if is_op0(tok.val(src)) =>
-                vec![atom, term(*tokens.next().unwrap()), parse_term0(tokens, src)?],
-            _ => vec![atom],
+            Some(tok) if is_unary_op(tok.val(src)) =>
+                vec![term(*tokens.next().unwrap()), parse_unary(tokens, src)?],
+            _ => vec![parse_atom(tokens, src)?],
         }
     })
 }
 
+/// Parses one precedence level of binary operators, `operand (op operand)*`.
+/// The result nests to the left, so `a - b - c` is `(a - b) - c`.
+fn parse_binary_level(tokens: &mut TokenIter, src: &str, level: NTermType,
+                      is_op: fn(&str) -> bool,
+                      parse_operand: fn(&mut TokenIter, &str) -> ParseRes) -> ParseRes {
+    let mut node = Node {
+        node_type: NodeType::NTerm(level.clone()),
+        children: vec![parse_operand(tokens, src)?],
+    };
+    while let Some(&op) = tokens.clone().next().filter(|tok| is_op(tok.val(src))) {
+        tokens.next();
+        let rhs = parse_operand(tokens, src)?;
+        let lhs = if node.children.len() == 1 { node.children.pop().unwrap() } else { node };
+        node = Node {
+            node_type: NodeType::NTerm(level.clone()),
+            children: vec![lhs, term(op), rhs],
+        };
+    }
+    Ok(node)
+}
+
+fn parse_term0(tokens: &mut TokenIter, src: &str) -> ParseRes {
+    parse_binary_level(tokens, src, NTermType::Term0, is_op0, parse_unary)
+}
+
 fn parse_term1(tokens: &mut TokenIter, src: &str) -> ParseRes {
-    let term0 = parse_term0(tokens, src)?;
-    Ok(Node {
-        node_type: NodeType::NTerm(NTermType::Term1),
-        children: match tokens.clone().next() {
-            Some(tok) if is_op1(tok.val(src)) =>
-                vec![term0, term(*tokens.next().unwrap()), parse_term1(tokens, src)?],
-            _ => vec![term0],
-        }
-    })
+    parse_binary_level(tokens, src, NTermType::Term1, is_op1, parse_term0)
 }
 
 fn parse_term2(tokens: &mut TokenIter, src: &str) -> ParseRes {
-    let term1 = parse_term1(tokens, src)?;
-    Ok(Node {
-        node_type: NodeType::NTerm(NTermType::Term2),
-        children: match tokens.clone().next() {
-            Some(tok) if is_op2(tok.val(src)) =>
-                vec![term1, term(*tokens.next().unwrap()), parse_term2(tokens, src)?],
-            _ => vec![term1],
-        }
-    })
+    parse_binary_level(tokens, src, NTermType::Term2, is_op2, parse_term1)
+}
+
+fn parse_term3(tokens: &mut TokenIter, src: &str) -> ParseRes {
+    parse_binary_level(tokens, src, NTermType::Term3, is_op3, parse_term2)
 }
 
 fn parse_expression(tokens: &mut TokenIter, src: &str) -> ParseRes {
-    let term2 = parse_term2(tokens, src)?;
-    Ok(Node {
-        node_type: NodeType::NTerm(NTermType::Expression),
-        children: match tokens.clone().next() {
-            Some(tok) if is_op3(tok.val(src)) =>
-                vec![term2, term(*tokens.next().unwrap()), parse_expression(tokens, src)?],
-            _ => vec![term2],
-        }
-    })
+    parse_binary_level(tokens, src, NTermType::Expression, is_op4, parse_term3)
 }
 
-fn parse_parameter_list(tokens: &mut TokenIter, src: &str) -> ParseRes {
+/// Parses comma separated expressions up to the token end, i.e. function call
+/// arguments or array literal elements
+fn parse_parameter_list(tokens: &mut TokenIter, src: &str, end: &str) -> ParseRes {
     let mut children = Vec::new();
+    // Stops at anything other than a comma after an argument, so the caller
+    // can report the missing end
     loop {
         match tokens.clone().next() {
-            Some(tok) if tok.val(src) == ")" => break,
-            Some(tok) if tok.val(src) == "," => children.push(term(*tokens.next().unwrap())),
+            Some(tok) if tok.val(src) == end => break,
             Some(_) => children.push(parse_expression(tokens, src)?),
-            None => return Err(ParseErr::Raw("Unexpected EOF in parameter list".to_owned())),
+            None => return Err(ParseErr::Point("Unexpected EOF in parameter list".to_owned(),
+                                              Span::eof(src))),
+        }
+        match tokens.clone().next() {
+            Some(tok) if tok.val(src) == "," => children.push(term(*tokens.next().unwrap())),
+            _ => break,
         }
     }
     Ok(Node {
         node_type: NodeType::NTerm(NTermType::ParameterList),
-        children: children,
+        children,
+    })
+}
+
+/// Parses a core type or struct name, followed by a `[]` for each level of
+/// array nesting
+fn parse_type(tokens: &mut TokenIter, src: &str) -> ParseRes {
+    let mut children = vec![match tokens.clone().next() {
+        Some(tok) if tok.token_type == TokenType::Ident => term(*tokens.next().unwrap()),
+        _ => assert_term_with_type(tokens, src, TokenType::CoreType)?,
+    }];
+    while let Some(&open) = tokens.clone().next().filter(|tok| tok.val(src) == "[") {
+        tokens.next();
+        children.push(term(open));
+        children.push(assert_closing(tokens, src, open, "]")?);
+    }
+    Ok(Node {
+        node_type: NodeType::NTerm(NTermType::Type),
+        children,
     })
 }
 
@@ -224,41 +393,66 @@ fn parse_declaration(tokens: &mut TokenIter, src: &str) -> ParseRes {
     Ok(Node {
         node_type: NodeType::NTerm(NTermType::Declaration),
         children: vec![
-            assert_term_with_type(tokens, TokenType::CoreType)?,
-            assert_term_with_type(tokens, TokenType::Ident)?,
+            parse_type(tokens, src)?,
+            assert_term_with_type(tokens, src, TokenType::Ident)?,
             assert_term(tokens, src, "=")?,
             parse_expression(tokens, src)?],
     })
 }
 
 fn parse_assignment(tokens: &mut TokenIter, src: &str) -> ParseRes {
+    // The target is a variable, or an element of an array or struct
+    let name = assert_term_with_type(tokens, src, TokenType::Ident)?;
     Ok(Node {
         node_type: NodeType::NTerm(NTermType::Assignment),
         children: vec![
-            assert_term_with_type(tokens, TokenType::Ident)?,
+            parse_postfix(tokens, src, name)?,
             assert_term(tokens, src, "=")?,
             parse_expression(tokens, src)?],
     })
 }
 
+fn parse_return(tokens: &mut TokenIter, src: &str) -> ParseRes {
+    let mut children = vec![term(*tokens.next().unwrap())]; // return
+    match tokens.clone().next() {
+        Some(tok) if tok.val(src) == ";" => (),
+        _ => children.push(parse_expression(tokens, src)?),
+    }
+    Ok(Node {
+        node_type: NodeType::NTerm(NTermType::Return),
+        children,
+    })
+}
+
 fn parse_stmt(tokens: &mut TokenIter, src: &str) -> ParseRes {
     let mut clone = tokens.clone();
-    let child = match clone.next().ok_or(ParseErr::Raw("Unexpected EOF".to_owned()))? {
-        // Assignment or function call
+    let child = match clone.next().ok_or_else(|| eof(src))? {
+        // Assignment, function call, or declaration with a struct type
         tok if tok.token_type == TokenType::Ident =>
-            match clone.next().ok_or(ParseErr::Raw("Unexpected EOF".to_owned()))? {
+            match clone.next().ok_or_else(|| eof(src))? {
                 // Function call
                 tok if tok.val(src) == "(" => parse_function_call(tokens, src)?,
+                // Decl, e.g. `Point p` or `Point[] ps`
+                tok if tok.token_type == TokenType::Ident => parse_declaration(tokens, src)?,
+                tok if tok.val(src) == "[" && clone.next().is_some_and(|t| t.val(src) == "]") =>
+                    parse_declaration(tokens, src)?,
                 // Assignment
-                tok if tok.val(src) == "=" => parse_assignment(tokens, src)?,
-                tok => return Err(ParseErr::Point("Expected '(' or '='".to_owned(), *tok))
+                tok if tok.val(src) == "=" || tok.val(src) == "[" || tok.val(src) == "." =>
+                    parse_assignment(tokens, src)?,
+                tok => return Err(ParseErr::Point("Expected `(`, `[`, `.`, `=` or a name"
+                                                  .to_owned(), tok.span()))
             },
         // Decl
         tok if tok.token_type == TokenType::CoreType =>
             parse_declaration(tokens, src)?,
+        tok if tok.val(src) == "return" => parse_return(tokens, src)?,
+        tok if tok.val(src) == "break" || tok.val(src) == "continue" => Node {
+            node_type: NodeType::NTerm(NTermType::LoopControl),
+            children: vec![term(*tokens.next().unwrap())],
+        },
         tok => return Err(
-            ParseErr::Point("Expected declaration, assignment, or function call."
-                            .to_owned(), *tok))
+            ParseErr::Point("Expected declaration, assignment, function call, return, break \
+                             or continue.".to_owned(), tok.span()))
     };
     Ok(Node {
         node_type: NodeType::NTerm(NTermType::Stmt),
@@ -266,71 +460,251 @@ fn parse_stmt(tokens: &mut TokenIter, src: &str) -> ParseRes {
     })
 }
 
-fn parse_if_control(tokens: &mut TokenIter, src: &str) -> ParseRes {
+fn parse_if_control(tokens: &mut TokenIter, src: &str, errs: &mut Vec<ParseErr>) -> ParseRes {
+    let if_tok = *tokens.next().unwrap();
+    let open = assert_opening(tokens, src, "(")?;
     let mut children = vec![
-        term(*tokens.next().unwrap()), // if
-        assert_term(tokens, src, "(")?,
+        term(if_tok),
+        term(open),
         parse_expression(tokens, src)?,
-        assert_term(tokens, src, ")")?,
+        assert_closing(tokens, src, open, ")")?,
         assert_term(tokens, src, "{")?,
-        parse_program(tokens, src)?,
+        parse_program(tokens, src, errs),
         assert_term(tokens, src, "}")?,
     ];
-    match tokens.clone().next() {
-        Some(tok) if tok.val(src) == "else" => {
-            children.push(term(*tokens.next().unwrap())); // else
-            children.push(assert_term(tokens, src, "{")?);
-            children.push(parse_program(tokens, src)?);
-            children.push(assert_term(tokens, src, "}")?);
+    while let Some(&tok) = tokens.clone().next().filter(|tok| tok.val(src) == "else") {
+        tokens.next();
+        children.push(term(tok)); // else
+        match tokens.clone().next() {
+            // Another arm of the chain
+            Some(tok) if tok.val(src) == "if" => {
+                children.push(term(*tokens.next().unwrap())); // if
+                let open = assert_opening(tokens, src, "(")?;
+                children.push(term(open));
+                children.push(parse_expression(tokens, src)?);
+                children.push(assert_closing(tokens, src, open, ")")?);
+                children.push(assert_term(tokens, src, "{")?);
+                children.push(parse_program(tokens, src, errs));
+                children.push(assert_term(tokens, src, "}")?);
+            }
+            _ => {
+                children.push(assert_term(tokens, src, "{")?);
+                children.push(parse_program(tokens, src, errs));
+                children.push(assert_term(tokens, src, "}")?);
+                break;
+            }
         }
-        _ => (),
     }
 
     Ok(Node {
         node_type: NodeType::NTerm(NTermType::If),
-        children: children
+        children
     })
 }
 
-fn parse_while_control(tokens: &mut TokenIter, src: &str) -> ParseRes {
+fn parse_while_control(tokens: &mut TokenIter, src: &str, errs: &mut Vec<ParseErr>) -> ParseRes {
+    let while_tok = *tokens.next().unwrap();
+    let open = assert_opening(tokens, src, "(")?;
     Ok(Node {
         node_type: NodeType::NTerm(NTermType::While),
         children: vec![
-            term(*tokens.next().unwrap()), // while
-            assert_term(tokens, src, "(")?,
+            term(while_tok),
+            term(open),
             parse_expression(tokens, src)?,
-            assert_term(tokens, src, ")")?,
+            assert_closing(tokens, src, open, ")")?,
             assert_term(tokens, src, "{")?,
-            parse_program(tokens, src)?,
+            parse_program(tokens, src, errs),
             assert_term(tokens, src, "}")?,
         ]
     })
 }
 
+/// Parses the init or step of a for loop header, if there is one before the
+/// token `end`. Only the init may be a declaration.
+fn parse_for_clause(tokens: &mut TokenIter, src: &str, end: &str, init: bool)
+                    -> Result<Option<Node>, ParseErr> {
+    let expected = if init { "declaration, assignment or function call" } else {
+        "assignment or function call"
+    };
+    let before = tokens.as_slice();
+    match tokens.clone().next() {
+        Some(tok) if tok.val(src) == end => return Ok(None),
+        Some(tok) if tok.token_type == TokenType::Key => return Err(ParseErr::Point(
+            format!("Expected {}", expected), tok.span())),
+        _ => (),
+    }
+    let stmt = parse_stmt(tokens, src)?;
+    if !init && stmt.children[0].node_type == NodeType::NTerm(NTermType::Declaration) {
+        let decl = &before[..before.len() - tokens.len()];
+        return Err(ParseErr::Point(format!("Expected {}, got a declaration", expected),
+                                   decl[0].span().to(decl[decl.len() - 1].span())));
+    }
+    Ok(Some(stmt))
+}
+
+fn parse_for_control(tokens: &mut TokenIter, src: &str, errs: &mut Vec<ParseErr>) -> ParseRes {
+    let for_tok = *tokens.next().unwrap();
+    let open = assert_opening(tokens, src, "(")?;
+    let mut children = vec![term(for_tok), term(open)];
+    children.extend(parse_for_clause(tokens, src, ";", true)?);
+    children.push(assert_term(tokens, src, ";")?);
+    match tokens.clone().next() {
+        Some(tok) if tok.val(src) == ";" => (),
+        _ => children.push(parse_expression(tokens, src)?),
+    }
+    children.push(assert_term(tokens, src, ";")?);
+    children.extend(parse_for_clause(tokens, src, ")", false)?);
+    children.push(assert_closing(tokens, src, open, ")")?);
+    children.push(assert_term(tokens, src, "{")?);
+    children.push(parse_program(tokens, src, errs));
+    children.push(assert_term(tokens, src, "}")?);
+    Ok(Node {
+        node_type: NodeType::NTerm(NTermType::For),
+        children
+    })
+}
+
+/// Parses `struct Name { Type field; ... }`
+fn parse_struct_def(tokens: &mut TokenIter, src: &str) -> ParseRes {
+    let mut children = vec![
+        term(*tokens.next().unwrap()), // struct
+        assert_term_with_type(tokens, src, TokenType::Ident)?,
+        assert_term(tokens, src, "{")?,
+    ];
+    // `Type field;` for each field
+    while tokens.clone().next().is_some_and(|tok| tok.val(src) != "}") {
+        children.push(parse_type(tokens, src)?);
+        children.push(assert_term_with_type(tokens, src, TokenType::Ident)?);
+        children.push(assert_term(tokens, src, ";")?);
+    }
+    children.push(assert_term(tokens, src, "}")?);
+    Ok(Node {
+        node_type: NodeType::NTerm(NTermType::StructDef),
+        children,
+    })
+}
 
-fn parse_program(tokens: &mut TokenIter, src: &str) -> ParseRes {
-    // Check if this is a control or stmt
+fn parse_param_decl_list(tokens: &mut TokenIter, src: &str) -> ParseRes {
+    let mut children = Vec::new();
+    // Stops at anything other than a comma after a parameter, so the caller
+    // can report the missing `)`
+    loop {
+        match tokens.clone().next() {
+            Some(tok) if tok.val(src) == ")" => break,
+            Some(tok) if !children.is_empty() && tok.val(src) != "," => break,
+            Some(_) => {
+                if !children.is_empty() {
+                    children.push(assert_term(tokens, src, ",")?);
+                }
+                children.push(parse_type(tokens, src)?);
+                children.push(assert_term_with_type(tokens, src, TokenType::Ident)?);
+            }
+            None => return Err(ParseErr::Point("Unexpected EOF in parameter list".to_owned(),
+                                              Span::eof(src))),
+        }
+    }
+    Ok(Node {
+        node_type: NodeType::NTerm(NTermType::ParamDeclList),
+        children,
+    })
+}
+
+fn parse_function_def(tokens: &mut TokenIter, src: &str, errs: &mut Vec<ParseErr>) -> ParseRes {
+    let fn_tok = *tokens.next().unwrap();
+    let name = assert_term_with_type(tokens, src, TokenType::Ident)?;
+    let open = assert_opening(tokens, src, "(")?;
+    let mut children = vec![
+        term(fn_tok),
+        name,
+        term(open),
+        parse_param_decl_list(tokens, src)?,
+        assert_closing(tokens, src, open, ")")?,
+    ];
+    match tokens.clone().next() {
+        Some(tok) if tok.val(src) == "->" => {
+            children.push(term(*tokens.next().unwrap())); // ->
+            children.push(parse_type(tokens, src)?);
+        }
+        _ => (),
+    }
+    children.push(assert_term(tokens, src, "{")?);
+    children.push(parse_program(tokens, src, errs));
+    children.push(assert_term(tokens, src, "}")?);
+
+    Ok(Node {
+        node_type: NodeType::NTerm(NTermType::FunctionDef),
+        children,
+    })
+}
+
+/// Skips tokens after a syntax error, up to and including the next `;` or the
+/// `}` closing a block opened while skipping. Stops before a `}` closing the
+/// enclosing block. Returns the skipped tokens as an Error node.
+fn synchronise(tokens: &mut TokenIter, src: &str) -> Node {
     let mut children = Vec::new();
+    let mut depth = 0;
     while let Some(tok) = tokens.clone().next() {
         match tok.val(src) {
+            "}" if depth == 0 => break,
+            "{" => depth += 1,
+            "}" => depth -= 1,
+            _ => (),
+        }
+        children.push(term(*tokens.next().unwrap()));
+        if depth == 0 && (tok.val(src) == ";" || tok.val(src) == "}") {
+            break;
+        }
+    }
+    Node {
+        node_type: NodeType::NTerm(NTermType::Error),
+        children,
+    }
+}
+
+/// Parses statements until a `}` or EOF. Syntax errors are added to errs, and
+/// the bad statement is replaced with an Error node.
+fn parse_program(tokens: &mut TokenIter, src: &str, errs: &mut Vec<ParseErr>) -> Node {
+    // Check if this is a control or stmt
+    let mut children = Vec::new();
+    while let Some(tok) = tokens.clone().next() {
+        let res = match tok.val(src) {
             "}" => { break }
-            "if" => children.push(parse_if_control(tokens, src)?),
-            "while" => children.push(parse_while_control(tokens, src)?),
-            _ => {
-                children.push(parse_stmt(tokens, src)?);
-                // Just ignore ; for convenience in AST gen. Potentially
-                // annoying errors generated, but probs worth in the long run.
-                assert_term(tokens, src, ";")?;
+            "if" => parse_if_control(tokens, src, errs),
+            "while" => parse_while_control(tokens, src, errs),
+            "for" => parse_for_control(tokens, src, errs),
+            "fn" => parse_function_def(tokens, src, errs),
+            "struct" => parse_struct_def(tokens, src),
+            // Just ignore ; for convenience in AST gen. Potentially
+            // annoying errors generated, but probs worth in the long run.
+            _ => parse_stmt(tokens, src)
+                .and_then(|stmt| assert_term(tokens, src, ";").map(|_| stmt)),
+        };
+        match res {
+            Ok(node) => children.push(node),
+            Err(e) => {
+                errs.push(e);
+                children.push(synchronise(tokens, src));
             }
         }
     }
-    Ok(Node {
+    Node {
         node_type: NodeType::NTerm(NTermType::Program),
-        children: children
-    })
+        children
+    }
 }
 
-pub fn parse(tokens: &[Token], src: &str) -> ParseRes {
+/// Parses a whole file, returning the (possibly partial) parse tree and every
+/// syntax error found
+pub fn parse(tokens: &[Token], src: &str) -> (Node, Vec<ParseErr>) {
     debug_assert!(!tokens.is_empty());
-    parse_program(&mut tokens.iter(), src)
+    let mut tokens = tokens.iter();
+    let mut errs = Vec::new();
+    let mut program = parse_program(&mut tokens, src, &mut errs);
+    // parse_program only stops early at an unmatched }
+    while let Some(tok) = tokens.next() {
+        errs.push(ParseErr::Point("Unmatched `}`".to_owned(), tok.span()));
+        let rest = parse_program(&mut tokens, src, &mut errs);
+        program.children.extend(rest.children);
+    }
+    (program, errs)
 }
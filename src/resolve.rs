@@ -0,0 +1,321 @@
+use ast::{Block, Expr, FnDef, Ident, Stmt, StructDef, Type};
+use diagnostic::{self, Diagnostic};
+use token::Span;
+use std::collections::HashMap;
+use std::mem;
+
+/// Maps the span of every variable use and function call to the span of the
+/// name in its declaration
+pub type Bindings = HashMap<Span, Span>;
+
+/// Functions provided by the runtime
+const BUILTINS: &[&str] = &["print", "println", "len"];
+
+/// A lexical scope, i.e. a block
+struct Scope {
+    /// Names declared so far in this block, mapped to their declaration
+    declared: HashMap<String, Span>,
+    /// Names declared anywhere in this block, so that uses before the
+    /// declaration can be told apart from undefined names
+    all: HashMap<String, Span>,
+}
+
+struct Resolver {
+    /// Innermost scope last
+    scopes: Vec<Scope>,
+    /// Every function defined at the top level, mapped to its declaration
+    functions: HashMap<String, Span>,
+    /// Every struct defined at the top level, mapped to its declaration
+    structs: HashMap<String, Span>,
+    /// Whether we're inside a function body
+    in_fn: bool,
+    /// How many loops we're inside, in the current function
+    loop_depth: usize,
+    bindings: Bindings,
+    errs: Vec<Diagnostic>,
+}
+
+impl Resolver {
+    fn error(&mut self, diagnostic: Diagnostic) {
+        self.errs.push(diagnostic);
+    }
+
+    fn resolve_block(&mut self, block: &Block) {
+        let mut all = HashMap::new();
+        for stmt in block {
+            if let Stmt::Declare { ref name, .. } = *stmt {
+                all.entry(name.name.clone()).or_insert(name.span);
+            }
+        }
+        self.scopes.push(Scope { declared: HashMap::new(), all });
+        for stmt in block {
+            self.resolve_stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn declare(&mut self, name: &Ident) {
+        if let Some(&prev) = self.scopes.last().unwrap().declared.get(&name.name) {
+            self.error(Diagnostic::spanned(
+                format!("Duplicate declaration of `{}` in the same scope", name.name), name.span)
+                .label(prev, "first declared here".to_owned()));
+        } else {
+            self.scopes.last_mut().unwrap().declared.insert(name.name.clone(), name.span);
+        }
+    }
+
+    fn use_var(&mut self, name: &Ident) {
+        if let Some(decl) = self.scopes.iter().rev()
+            .filter_map(|s| s.declared.get(&name.name)).next().cloned() {
+            self.bindings.insert(name.span, decl);
+        } else if let Some(&decl) = self.scopes.iter().rev()
+            .filter_map(|s| s.all.get(&name.name)).next() {
+            self.error(Diagnostic::spanned(
+                format!("Variable `{}` used before its declaration", name.name), name.span)
+                .label(decl, "declared here".to_owned()));
+        } else {
+            let mut err = Diagnostic::spanned(
+                format!("Undefined variable `{}`", name.name), name.span);
+            if self.in_fn {
+                err = err.note("functions can only use their parameters and local variables"
+                               .to_owned());
+            }
+            self.error(err);
+        }
+    }
+
+    /// Collects every function and struct defined at the top level, so they
+    /// can be used before their definition
+    fn collect_items(&mut self, program: &Block) {
+        for stmt in program {
+            match *stmt {
+                Stmt::Fn { ref def } => {
+                    let name = &def.name;
+                    if BUILTINS.contains(&&name.name[..]) {
+                        self.error(Diagnostic::spanned(
+                            format!("Cannot redefine builtin function `{}`", name.name),
+                            name.span));
+                    } else if let Some(&prev) = self.functions.get(&name.name) {
+                        self.error(Diagnostic::spanned(
+                            format!("Duplicate definition of function `{}`", name.name),
+                            name.span)
+                            .label(prev, "first defined here".to_owned()));
+                    } else {
+                        self.functions.insert(name.name.clone(), name.span);
+                    }
+                }
+                Stmt::Struct { ref def } => {
+                    let name = &def.name;
+                    if let Some(&prev) = self.structs.get(&name.name) {
+                        self.error(Diagnostic::spanned(
+                            format!("Duplicate definition of struct `{}`", name.name), name.span)
+                            .label(prev, "first defined here".to_owned()));
+                    } else {
+                        self.structs.insert(name.name.clone(), name.span);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Reports an error suggesting a similarly named struct, if there is one
+    fn unknown_struct(&mut self, mut err: Diagnostic, name: &str) {
+        let mut names: Vec<_> = self.structs.keys().map(|s| &s[..]).collect();
+        names.sort();
+        if let Some(similar) = diagnostic::closest_match(name, names) {
+            err = err.help(format!("a struct with a similar name exists: `{}`", similar));
+        }
+        self.error(err);
+    }
+
+    /// Checks every struct named in ty exists. what describes what has the
+    /// type, and span points at it.
+    fn check_type(&mut self, ty: &Type, what: &str, span: Span) {
+        match *ty {
+            Type::Array(ref elem) => self.check_type(elem, what, span),
+            Type::Struct(ref name) if !self.structs.contains_key(name) => {
+                let err = Diagnostic::spanned(
+                    format!("Unknown type `{}` for {}", name, what), span);
+                self.unknown_struct(err, name);
+            }
+            _ => (),
+        }
+    }
+
+    fn resolve_struct(&mut self, def: &StructDef) {
+        if self.in_fn || self.scopes.len() > 1 {
+            self.error(Diagnostic::spanned(
+                "Structs can only be defined at the top level".to_owned(), def.name.span));
+        }
+        let mut fields: HashMap<&str, Span> = HashMap::new();
+        for field in &def.fields {
+            let name = &field.name;
+            self.check_type(&field.ty, &format!("field `{}`", name.name), name.span);
+            if let Some(&prev) = fields.get(&name.name[..]) {
+                self.error(Diagnostic::spanned(
+                    format!("Duplicate field `{}` in struct `{}`", name.name, def.name.name),
+                    name.span)
+                    .label(prev, "first declared here".to_owned()));
+            } else {
+                fields.insert(&name.name, name.span);
+            }
+        }
+    }
+
+    fn resolve_fn(&mut self, def: &FnDef) {
+        if self.in_fn || self.scopes.len() > 1 {
+            self.error(Diagnostic::spanned(
+                "Functions can only be defined at the top level".to_owned(), def.name.span)
+                .help("move this function out of the enclosing block".to_owned()));
+        }
+        for param in &def.params {
+            self.check_type(&param.ty, &format!("`{}`", param.name.name), param.name.span);
+        }
+        self.check_type(&def.ret, &format!("the return value of `{}`", def.name.name),
+                        def.name.span);
+        // Function bodies can't see the variables of the enclosing code
+        let outer_scopes = mem::take(&mut self.scopes);
+        let outer_in_fn = mem::replace(&mut self.in_fn, true);
+        let outer_loop_depth = mem::replace(&mut self.loop_depth, 0);
+        self.scopes.push(Scope { declared: HashMap::new(), all: HashMap::new() });
+        for param in &def.params {
+            self.declare(&param.name);
+        }
+        self.resolve_block(&def.body);
+        self.scopes = outer_scopes;
+        self.in_fn = outer_in_fn;
+        self.loop_depth = outer_loop_depth;
+    }
+
+    fn check_in_loop(&mut self, keyword: &str, span: Span) {
+        if self.loop_depth == 0 {
+            self.error(Diagnostic::spanned(format!("`{}` outside of a loop", keyword), span));
+        }
+    }
+
+    fn resolve_loop_body(&mut self, body: &Block) {
+        self.loop_depth += 1;
+        self.resolve_block(body);
+        self.loop_depth -= 1;
+    }
+
+    fn resolve_stmt(&mut self, stmt: &Stmt) {
+        match *stmt {
+            Stmt::Declare { ref ty, ref name, ref init, .. } => {
+                self.check_type(ty, &format!("`{}`", name.name), name.span);
+                // Resolve the initialiser first, so `int x = x;` is an error
+                self.resolve_expr(init);
+                self.declare(name);
+            }
+            Stmt::Assign { ref target, ref val, .. } => {
+                self.resolve_expr(val);
+                self.resolve_expr(target);
+            }
+            Stmt::Expr { ref expr, .. } => self.resolve_expr(expr),
+            Stmt::If { ref arms, ref else_body, .. } => {
+                for arm in arms {
+                    self.resolve_expr(&arm.cond);
+                    self.resolve_block(&arm.body);
+                }
+                if let Some(ref else_body) = *else_body {
+                    self.resolve_block(else_body);
+                }
+            }
+            Stmt::While { ref cond, ref body, .. } => {
+                self.resolve_expr(cond);
+                self.resolve_loop_body(body);
+            }
+            Stmt::For { ref init, ref cond, ref step, ref body, .. } => {
+                // The header gets its own scope, so the init is only visible
+                // to the loop
+                self.scopes.push(Scope { declared: HashMap::new(), all: HashMap::new() });
+                if let Some(ref init) = *init {
+                    self.resolve_stmt(init);
+                }
+                if let Some(ref cond) = *cond {
+                    self.resolve_expr(cond);
+                }
+                if let Some(ref step) = *step {
+                    self.resolve_stmt(step);
+                }
+                self.resolve_loop_body(body);
+                self.scopes.pop();
+            }
+            Stmt::Break { span } => self.check_in_loop("break", span),
+            Stmt::Continue { span } => self.check_in_loop("continue", span),
+            Stmt::Fn { ref def } => self.resolve_fn(def),
+            Stmt::Struct { ref def } => self.resolve_struct(def),
+            Stmt::Return { ref val, span } => {
+                if !self.in_fn {
+                    self.error(Diagnostic::spanned(
+                        "`return` outside of a function".to_owned(), span));
+                }
+                if let Some(ref val) = *val {
+                    self.resolve_expr(val);
+                }
+            }
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr) {
+        match *expr {
+            Expr::Var { ref name } => self.use_var(name),
+            Expr::Lit { .. } => (),
+            Expr::Unary { ref operand, .. } => self.resolve_expr(operand),
+            Expr::Binary { ref lhs, ref rhs, .. } => {
+                self.resolve_expr(lhs);
+                self.resolve_expr(rhs);
+            }
+            Expr::Call { ref callee, ref args, .. } => {
+                if let Some(&decl) = self.functions.get(&callee.name) {
+                    self.bindings.insert(callee.span, decl);
+                } else if !BUILTINS.contains(&&callee.name[..]) {
+                    self.error(Diagnostic::spanned(
+                        format!("Undefined function `{}`", callee.name), callee.span));
+                }
+                for arg in args {
+                    self.resolve_expr(arg);
+                }
+            }
+            Expr::Array { ref elems, .. } => {
+                for elem in elems {
+                    self.resolve_expr(elem);
+                }
+            }
+            Expr::Index { ref base, ref index, .. } => {
+                self.resolve_expr(base);
+                self.resolve_expr(index);
+            }
+            Expr::StructLit { ref name, ref fields, .. } => {
+                if !self.structs.contains_key(&name.name) {
+                    let err = Diagnostic::spanned(
+                        format!("Unknown struct `{}`", name.name), name.span);
+                    self.unknown_struct(err, &name.name);
+                }
+                for (_, val) in fields {
+                    self.resolve_expr(val);
+                }
+            }
+            // Fields are checked along with types
+            Expr::Field { ref base, .. } => self.resolve_expr(base),
+        }
+    }
+}
+
+/// Resolves every name in a program to its declaration, returning the
+/// bindings and every error found
+pub fn resolve(program: &Block) -> (Bindings, Vec<Diagnostic>) {
+    let mut resolver = Resolver {
+        scopes: Vec::new(),
+        functions: HashMap::new(),
+        structs: HashMap::new(),
+        in_fn: false,
+        loop_depth: 0,
+        bindings: HashMap::new(),
+        errs: Vec::new(),
+    };
+    resolver.collect_items(program);
+    resolver.resolve_block(program);
+    (resolver.bindings, resolver.errs)
+}
@@ -0,0 +1,198 @@
+/* The hl2 runtime, pasted at the top of every C file emitted by the hl2
+ * compiler. Plain C99 with no dependencies beyond the standard library, so the
+ * output builds with any C compiler.
+ *
+ * Strings, arrays and structs are allocated on the heap and never freed.
+ * Functions are inline so the ones a program doesn't use don't cause
+ * warnings. */
+
+#include <inttypes.h>
+#include <math.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* HL2_MAX_CALL_DEPTH, how deep calls can nest before we report a stack
+ * overflow, is defined by the compiler before this header so it matches the
+ * interpreter. */
+
+/* An immutable string. May contain NUL bytes. */
+typedef struct {
+    const char *ptr;
+    int64_t len;
+} hl2_str;
+
+/* An array, shared by reference. Elements are cast to their type when
+ * accessed. */
+typedef struct {
+    int64_t len;
+    void *data;
+} hl2_array;
+
+static int hl2_call_depth = 0;
+
+/* Reports a runtime error at loc, a "file:line:col" string, and exits */
+static inline void hl2_panic(const char *msg, const char *loc) {
+    fflush(stdout);
+    fprintf(stderr, "error: %s\n --> %s\n", msg, loc);
+    exit(1);
+}
+
+static inline void *hl2_alloc(size_t size) {
+    void *p = malloc(size ? size : 1);
+    if (!p) {
+        fflush(stdout);
+        fputs("error: Out of memory\n", stderr);
+        exit(1);
+    }
+    return p;
+}
+
+static inline void hl2_enter(const char *name, const char *loc) {
+    char msg[256];
+    if (hl2_call_depth == HL2_MAX_CALL_DEPTH) {
+        snprintf(msg, sizeof msg, "Stack overflow calling `%s`", name);
+        hl2_panic(msg, loc);
+    }
+    hl2_call_depth++;
+}
+
+static inline void hl2_leave(void) {
+    hl2_call_depth--;
+}
+
+/* Checked integer arithmetic, reporting overflow like the interpreter */
+
+static inline void hl2_overflow(const char *op, const char *loc) {
+    char msg[64];
+    snprintf(msg, sizeof msg, "Integer overflow in `%s`", op);
+    hl2_panic(msg, loc);
+}
+
+static inline int64_t hl2_add(int64_t a, int64_t b, const char *loc) {
+    if ((b > 0 && a > INT64_MAX - b) || (b < 0 && a < INT64_MIN - b)) hl2_overflow("+", loc);
+    return a + b;
+}
+
+static inline int64_t hl2_sub(int64_t a, int64_t b, const char *loc) {
+    if ((b < 0 && a > INT64_MAX + b) || (b > 0 && a < INT64_MIN + b)) hl2_overflow("-", loc);
+    return a - b;
+}
+
+static inline int64_t hl2_mul(int64_t a, int64_t b, const char *loc) {
+    bool overflow;
+    if (a > 0) {
+        overflow = b > 0 ? a > INT64_MAX / b : b < INT64_MIN / a;
+    } else {
+        overflow = b > 0 ? a < INT64_MIN / b : a != 0 && b < INT64_MAX / a;
+    }
+    if (overflow) hl2_overflow("*", loc);
+    return a * b;
+}
+
+static inline int64_t hl2_div(int64_t a, int64_t b, const char *loc) {
+    if (b == 0) hl2_panic("Division by zero", loc);
+    if (a == INT64_MIN && b == -1) hl2_overflow("/", loc);
+    return a / b;
+}
+
+static inline int64_t hl2_mod(int64_t a, int64_t b, const char *loc) {
+    if (b == 0) hl2_panic("Division by zero", loc);
+    if (a == INT64_MIN && b == -1) hl2_overflow("%", loc);
+    return a % b;
+}
+
+static inline int64_t hl2_neg(int64_t a, const char *loc) {
+    if (a == INT64_MIN) hl2_overflow("-", loc);
+    return -a;
+}
+
+/* The remainder of a / b, truncated towards zero like C's fmod. Written out
+ * so the output doesn't need linking with libm. Each subtraction is exact. */
+static inline double hl2_fmod(double a, double b) {
+    double r = a < 0 ? -a : a, m = b < 0 ? -b : b, d;
+    if (isnan(a) || isnan(b) || isinf(a) || m == 0) return NAN;
+    if (r < m) return a;
+    d = m;
+    while (d <= r / 2) d *= 2;
+    for (; d >= m; d /= 2) {
+        if (r >= d) r -= d;
+    }
+    return a < 0 ? -r : r;
+}
+
+static inline hl2_str hl2_str_concat(hl2_str a, hl2_str b) {
+    char *ptr = hl2_alloc((size_t)(a.len + b.len));
+    memcpy(ptr, a.ptr, (size_t)a.len);
+    memcpy(ptr + a.len, b.ptr, (size_t)b.len);
+    return (hl2_str){ ptr, a.len + b.len };
+}
+
+static inline bool hl2_str_eq(hl2_str a, hl2_str b) {
+    return a.len == b.len && memcmp(a.ptr, b.ptr, (size_t)a.len) == 0;
+}
+
+static inline hl2_array *hl2_array_new(int64_t len, size_t elem_size) {
+    hl2_array *arr = hl2_alloc(sizeof(hl2_array));
+    arr->len = len;
+    arr->data = hl2_alloc((size_t)len * elem_size);
+    return arr;
+}
+
+/* Checks an index is in bounds, returning it */
+static inline int64_t hl2_index(hl2_array *arr, int64_t ix, const char *loc) {
+    char msg[128];
+    if (ix < 0 || ix >= arr->len) {
+        snprintf(msg, sizeof msg, "Index %" PRId64 " is out of bounds for an array of length %"
+                 PRId64, ix, arr->len);
+        hl2_panic(msg, loc);
+    }
+    return ix;
+}
+
+static inline void hl2_print_int(int64_t i) {
+    printf("%" PRId64, i);
+}
+
+/* Prints the shortest decimal which reads back as x, never in exponent form,
+ * to match how the interpreter prints floats */
+static inline void hl2_print_float(double x) {
+    char buf[32], digits[20];
+    const char *p;
+    int prec, ndigits = 0, exp, i;
+    if (isnan(x)) { fputs("NaN", stdout); return; }
+    if (isinf(x)) { fputs(x < 0 ? "-inf" : "inf", stdout); return; }
+    for (prec = 1; prec <= 17; prec++) {
+        snprintf(buf, sizeof buf, "%.*e", prec - 1, x);
+        if (strtod(buf, NULL) == x) break;
+    }
+    p = buf;
+    if (*p == '-') { putchar('-'); p++; }
+    for (; *p != 'e'; p++) {
+        if (*p != '.') digits[ndigits++] = *p;
+    }
+    exp = atoi(p + 1);
+    while (ndigits > 1 && digits[ndigits - 1] == '0') ndigits--;
+    if (exp >= ndigits - 1) {
+        fwrite(digits, 1, (size_t)ndigits, stdout);
+        for (i = 0; i < exp - (ndigits - 1); i++) putchar('0');
+    } else if (exp >= 0) {
+        fwrite(digits, 1, (size_t)exp + 1, stdout);
+        putchar('.');
+        fwrite(digits + exp + 1, 1, (size_t)(ndigits - exp - 1), stdout);
+    } else {
+        fputs("0.", stdout);
+        for (i = 0; i < -exp - 1; i++) putchar('0');
+        fwrite(digits, 1, (size_t)ndigits, stdout);
+    }
+}
+
+static inline void hl2_print_str(hl2_str s) {
+    fwrite(s.ptr, 1, (size_t)s.len, stdout);
+}
+
+static inline void hl2_print_bool(bool b) {
+    fputs(b ? "true" : "false", stdout);
+}
@@ -0,0 +1,88 @@
+/* The runtime linked with assembly emitted by the hl2 compiler. Every hl2
+ * value is 8 bytes: ints, floats and bools are stored directly, and strings,
+ * arrays and structs are pointers to the heap or read only data.
+ *
+ * A string points to its length, followed by its bytes. An array points to
+ * its length, followed by its elements. */
+
+#include "hl2.h"
+
+typedef struct {
+    int64_t len;
+    char bytes[];
+} hl2_rt_str;
+
+static hl2_str hl2_rt_to_str(const hl2_rt_str *s) {
+    return (hl2_str){ s->bytes, s->len };
+}
+
+void hl2_rt_print_int(int64_t i) {
+    hl2_print_int(i);
+}
+
+void hl2_rt_print_float(double x) {
+    hl2_print_float(x);
+}
+
+void hl2_rt_print_str(const hl2_rt_str *s) {
+    hl2_print_str(hl2_rt_to_str(s));
+}
+
+void hl2_rt_print_bool(int64_t b) {
+    hl2_print_bool(b != 0);
+}
+
+void hl2_rt_newline(void) {
+    putchar('\n');
+}
+
+const hl2_rt_str *hl2_rt_str_concat(const hl2_rt_str *a, const hl2_rt_str *b) {
+    hl2_rt_str *s = hl2_alloc(sizeof(hl2_rt_str) + (size_t)(a->len + b->len));
+    s->len = a->len + b->len;
+    memcpy(s->bytes, a->bytes, (size_t)a->len);
+    memcpy(s->bytes + a->len, b->bytes, (size_t)b->len);
+    return s;
+}
+
+int64_t hl2_rt_str_eq(const hl2_rt_str *a, const hl2_rt_str *b) {
+    return hl2_str_eq(hl2_rt_to_str(a), hl2_rt_to_str(b));
+}
+
+int64_t hl2_rt_div(int64_t a, int64_t b, const char *loc) {
+    return hl2_div(a, b, loc);
+}
+
+int64_t hl2_rt_mod(int64_t a, int64_t b, const char *loc) {
+    return hl2_mod(a, b, loc);
+}
+
+double hl2_rt_fmod(double a, double b) {
+    return hl2_fmod(a, b);
+}
+
+void hl2_rt_overflow(const char *op, const char *loc) {
+    hl2_overflow(op, loc);
+}
+
+void *hl2_rt_alloc(int64_t size) {
+    return hl2_alloc((size_t)size);
+}
+
+int64_t *hl2_rt_array_new(int64_t len) {
+    int64_t *arr = hl2_alloc(sizeof(int64_t) * (size_t)(len + 1));
+    arr[0] = len;
+    return arr;
+}
+
+void hl2_rt_index_error(int64_t ix, int64_t len, const char *loc) {
+    char msg[128];
+    snprintf(msg, sizeof msg, "Index %" PRId64 " is out of bounds for an array of length %"
+             PRId64, ix, len);
+    hl2_panic(msg, loc);
+}
+
+void hl2_rt_stack_overflow(const char *name, const char *loc) {
+    char msg[256];
+    snprintf(msg, sizeof msg, "Stack overflow calling `%s`", name);
+    hl2_panic(msg, loc);
+}
@@ -0,0 +1,57 @@
+use token::Point;
+use std::fmt;
+
+/// A 1-based line and column in some source code. Columns count chars, not
+/// bytes.
+#[derive(Ord, Eq, PartialEq, PartialOrd, Debug, Clone, Copy, Hash)]
+pub struct Location {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// Converts byte offsets into source code to line / column locations. Built
+/// once per file, so every stage of the compiler agrees on line numbering.
+pub struct SourceMap<'a> {
+    src: &'a str,
+    /// The byte offset at which each line starts
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(src: &'a str) -> SourceMap<'a> {
+        let mut line_starts = vec![0];
+        line_starts.extend(src.match_indices('\n').map(|(ix, _)| ix + 1));
+        SourceMap { src, line_starts }
+    }
+
+    /// Returns the 0-based index of the line containing point
+    fn line_ix(&self, point: Point) -> usize {
+        match self.line_starts.binary_search(&point.0) {
+            Ok(ix) => ix,
+            Err(ix) => ix - 1,
+        }
+    }
+
+    /// Returns the text of a 1-based line, without the line ending
+    pub fn line(&self, line: usize) -> &'a str {
+        let start = self.line_starts[line - 1];
+        let end = self.line_starts.get(line).map_or(self.src.len(), |&next| next - 1);
+        self.src[start..end].trim_end_matches('\r')
+    }
+
+    pub fn location(&self, point: Point) -> Location {
+        let line_ix = self.line_ix(point);
+        let line_start = self.line_starts[line_ix];
+        let end = std::cmp::min(point.0, self.src.len());
+        Location {
+            line: line_ix + 1,
+            col: self.src[line_start..end].chars().count() + 1,
+        }
+    }
+}
@@ -0,0 +1,590 @@
+;; Compiled from golden.hl2 by the hl2 compiler
+(module
+  ;; The runtime pasted into every module emitted by the hl2 compiler.
+  ;;
+  ;; Ints are i64s, floats f64s and bools i32s. Strings, arrays and structs are
+  ;; i32 pointers into the exported memory, allocated from $heap and never
+  ;; freed. A string points to its i32 length, followed by its bytes. An array
+  ;; points to its i32 length, followed by 8 bytes per element from offset 8.
+  ;; A struct has 8 bytes per field, in the order they're declared.
+  ;;
+  ;; The host provides printing, float remainder and error reporting. Floats
+  ;; must be printed like the interpreter prints them. panic and index_error
+  ;; report an error at loc, a string pointer to "file:line:col" or 0 for
+  ;; nowhere, and must not return.
+  (import "env" "print_int" (func $print_int (param i64)))
+  (import "env" "print_float" (func $print_float (param f64)))
+  (import "env" "print_bool" (func $print_bool (param i32)))
+  (import "env" "print_str" (func $print_str (param i32)))
+  (import "env" "println" (func $println))
+  (import "env" "fmod" (func $fmod (param f64 f64) (result f64)))
+  (import "env" "panic" (func $panic (param $msg i32) (param $loc i32)))
+  (import "env" "index_error" (func $index_error (param $ix i64) (param $len i64) (param $loc i32)))
+
+  ;; Allocates size bytes, 8 byte aligned, growing memory as needed
+  (func $alloc (param $size i32) (result i32)
+    (local $ptr i32)
+    global.get $heap
+    local.set $ptr
+    global.get $heap
+    local.get $size
+    i32.add
+    i32.const 7
+    i32.add
+    i32.const -8
+    i32.and
+    global.set $heap
+    block $ok
+      global.get $heap
+      memory.size
+      i32.const 16
+      i32.shl
+      i32.le_u
+      br_if $ok
+      global.get $heap
+      memory.size
+      i32.const 16
+      i32.shl
+      i32.sub
+      i32.const 65535
+      i32.add
+      i32.const 16
+      i32.shr_u
+      memory.grow
+      i32.const -1
+      i32.ne
+      br_if $ok
+      global.get $msg_out_of_memory
+      i32.const 0
+      call $panic
+      unreachable
+    end
+    local.get $ptr)
+
+  ;; Copies len bytes from src to dst
+  (func $copy (param $dst i32) (param $src i32) (param $len i32)
+    block $done
+      loop $next
+        local.get $len
+        i32.eqz
+        br_if $done
+        local.get $dst
+        local.get $src
+        i32.load8_u
+        i32.store8
+        local.get $dst
+        i32.const 1
+        i32.add
+        local.set $dst
+        local.get $src
+        i32.const 1
+        i32.add
+        local.set $src
+        local.get $len
+        i32.const 1
+        i32.sub
+        local.set $len
+        br $next
+      end
+    end)
+
+  (func $str_concat (param $a i32) (param $b i32) (result i32)
+    (local $len_a i32) (local $len_b i32) (local $s i32)
+    local.get $a
+    i32.load
+    local.set $len_a
+    local.get $b
+    i32.load
+    local.set $len_b
+    local.get $len_a
+    local.get $len_b
+    i32.add
+    i32.const 4
+    i32.add
+    call $alloc
+    local.tee $s
+    local.get $len_a
+    local.get $len_b
+    i32.add
+    i32.store
+    local.get $s
+    i32.const 4
+    i32.add
+    local.get $a
+    i32.const 4
+    i32.add
+    local.get $len_a
+    call $copy
+    local.get $s
+    i32.const 4
+    i32.add
+    local.get $len_a
+    i32.add
+    local.get $b
+    i32.const 4
+    i32.add
+    local.get $len_b
+    call $copy
+    local.get $s)
+
+  (func $str_eq (param $a i32) (param $b i32) (result i32)
+    (local $len i32)
+    local.get $a
+    i32.load
+    local.tee $len
+    local.get $b
+    i32.load
+    i32.ne
+    if
+      i32.const 0
+      return
+    end
+    block $done
+      loop $next
+        local.get $len
+        i32.eqz
+        br_if $done
+        local.get $a
+        i32.load8_u offset=4
+        local.get $b
+        i32.load8_u offset=4
+        i32.ne
+        if
+          i32.const 0
+          return
+        end
+        local.get $a
+        i32.const 1
+        i32.add
+        local.set $a
+        local.get $b
+        i32.const 1
+        i32.add
+        local.set $b
+        local.get $len
+        i32.const 1
+        i32.sub
+        local.set $len
+        br $next
+      end
+    end
+    i32.const 1)
+
+  ;; Checked integer arithmetic, reporting overflow like the interpreter
+
+  (func $add (param $a i64) (param $b i64) (param $loc i32) (result i64)
+    (local $r i64)
+    local.get $a
+    local.get $b
+    i64.add
+    local.set $r
+    ;; Overflowed if both operands have a different sign to the result
+    local.get $a
+    local.get $r
+    i64.xor
+    local.get $b
+    local.get $r
+    i64.xor
+    i64.and
+    i64.const 0
+    i64.lt_s
+    if
+      global.get $msg_overflow_add
+      local.get $loc
+      call $panic
+      unreachable
+    end
+    local.get $r)
+
+  (func $sub (param $a i64) (param $b i64) (param $loc i32) (result i64)
+    (local $r i64)
+    local.get $a
+    local.get $b
+    i64.sub
+    local.set $r
+    ;; Overflowed if the operands have different signs, and the result has a
+    ;; different sign to a
+    local.get $a
+    local.get $b
+    i64.xor
+    local.get $a
+    local.get $r
+    i64.xor
+    i64.and
+    i64.const 0
+    i64.lt_s
+    if
+      global.get $msg_overflow_sub
+      local.get $loc
+      call $panic
+      unreachable
+    end
+    local.get $r)
+
+  (func $mul (param $a i64) (param $b i64) (param $loc i32) (result i64)
+    (local $r i64)
+    local.get $a
+    local.get $b
+    i64.mul
+    local.set $r
+    block $ok
+      local.get $a
+      i64.eqz
+      br_if $ok
+      local.get $a
+      i64.const -1
+      i64.eq
+      if
+        local.get $b
+        i64.const -9223372036854775808
+        i64.ne
+        br_if $ok
+      else
+        local.get $r
+        local.get $a
+        i64.div_s
+        local.get $b
+        i64.eq
+        br_if $ok
+      end
+      global.get $msg_overflow_mul
+      local.get $loc
+      call $panic
+      unreachable
+    end
+    local.get $r)
+
+  ;; Checks the operands of / or %, reporting overflow with msg
+  (func $check_div (param $a i64) (param $b i64) (param $msg i32) (param $loc i32)
+    local.get $b
+    i64.eqz
+    if
+      global.get $msg_div_zero
+      local.get $loc
+      call $panic
+      unreachable
+    end
+    local.get $a
+    i64.const -9223372036854775808
+    i64.eq
+    local.get $b
+    i64.const -1
+    i64.eq
+    i32.and
+    if
+      local.get $msg
+      local.get $loc
+      call $panic
+      unreachable
+    end)
+
+  (func $div (param $a i64) (param $b i64) (param $loc i32) (result i64)
+    local.get $a
+    local.get $b
+    global.get $msg_overflow_div
+    local.get $loc
+    call $check_div
+    local.get $a
+    local.get $b
+    i64.div_s)
+
+  (func $mod (param $a i64) (param $b i64) (param $loc i32) (result i64)
+    local.get $a
+    local.get $b
+    global.get $msg_overflow_mod
+    local.get $loc
+    call $check_div
+    local.get $a
+    local.get $b
+    i64.rem_s)
+
+  (func $neg (param $a i64) (param $loc i32) (result i64)
+    local.get $a
+    i64.const -9223372036854775808
+    i64.eq
+    if
+      global.get $msg_overflow_sub
+      local.get $loc
+      call $panic
+      unreachable
+    end
+    i64.const 0
+    local.get $a
+    i64.sub)
+
+  ;; Called before each call, reporting a stack overflow with msg once calls
+  ;; are nested $max_depth deep, the interpreter's limit
+  (func $enter (param $msg i32) (param $loc i32)
+    global.get $depth
+    global.get $max_depth
+    i32.eq
+    if
+      local.get $msg
+      local.get $loc
+      call $panic
+      unreachable
+    end
+    global.get $depth
+    i32.const 1
+    i32.add
+    global.set $depth)
+
+  ;; Called after each call
+  (func $leave
+    global.get $depth
+    i32.const 1
+    i32.sub
+    global.set $depth)
+
+  ;; Checks an index is in bounds, returning the address of the element
+  (func $index (param $arr i32) (param $ix i64) (param $loc i32) (result i32)
+    ;; Negative indices are huge when compared unsigned
+    local.get $ix
+    local.get $arr
+    i64.load32_u
+    i64.ge_u
+    if
+      local.get $ix
+      local.get $arr
+      i64.load32_u
+      local.get $loc
+      call $index_error
+      unreachable
+    end
+    local.get $arr
+    local.get $ix
+    i32.wrap_i64
+    i32.const 8
+    i32.mul
+    i32.add
+    i32.const 8
+    i32.add)
+
+  (memory (export "memory") 1)
+  (global $heap (mut i32) (i32.const 440))
+  (global $depth (mut i32) (i32.const 0))
+  (global $max_depth i32 (i32.const 512))
+  (global $msg_out_of_memory i32 (i32.const 8))
+  (global $msg_div_zero i32 (i32.const 28))
+  (global $msg_overflow_add i32 (i32.const 48))
+  (global $msg_overflow_sub i32 (i32.const 76))
+  (global $msg_overflow_mul i32 (i32.const 104))
+  (global $msg_overflow_div i32 (i32.const 132))
+  (global $msg_overflow_mod i32 (i32.const 160))
+  (data (i32.const 8) "\0d\00\00\00Out of memory")
+  (data (i32.const 28) "\10\00\00\00Division by zero")
+  (data (i32.const 48) "\17\00\00\00Integer overflow in `+`")
+  (data (i32.const 76) "\17\00\00\00Integer overflow in `-`")
+  (data (i32.const 104) "\17\00\00\00Integer overflow in `*`")
+  (data (i32.const 132) "\17\00\00\00Integer overflow in `/`")
+  (data (i32.const 160) "\17\00\00\00Integer overflow in `%`")
+  (data (i32.const 188) "\0f\00\00\00golden.hl2:1:32")
+  (data (i32.const 208) "\03\00\00\00one")
+  (data (i32.const 216) "\1b\00\00\00Stack overflow calling `sq`")
+  (data (i32.const 248) "\0f\00\00\00golden.hl2:4:52")
+  (data (i32.const 268) "\0f\00\00\00golden.hl2:5:11")
+  (data (i32.const 288) "\10\00\00\00golden.hl2:13:14")
+  (data (i32.const 308) "\0f\00\00\00golden.hl2:13:5")
+  (data (i32.const 328) "\10\00\00\00golden.hl2:14:15")
+  (data (i32.const 348) "\10\00\00\00golden.hl2:10:30")
+  (data (i32.const 368) "\0f\00\00\00golden.hl2:16:9")
+  (data (i32.const 388) "\10\00\00\00golden.hl2:16:15")
+  (data (i32.const 408) "\10\00\00\00golden.hl2:16:21")
+  (data (i32.const 428) "\01\00\00\00 ")
+
+  (func $f_sq (param $v_x i64) (result i64)
+    local.get $v_x
+    local.get $v_x
+    i32.const 188
+    call $mul
+    return)
+
+  (func $main (export "main")
+    (local $v_i i64)
+    (local $t0 i64)
+    (local $t1 f64)
+    (local $t2 i32)
+    (local $v_p i32)
+    (local $t3 i32)
+    (local $v_a i32)
+    (local $v_j i64)
+    (local $t4 i64)
+    (local $t5 i64)
+    (local $t6 i64)
+    (local $t7 i64)
+    (local $t8 i64)
+    (local $t9 i32)
+    (local $t10 i64)
+    (local $t11 i64)
+    i64.const 0
+    local.set $v_i
+    block $break1
+      loop $continue2
+        local.get $v_i
+        i64.const 3
+        i64.lt_s
+        i32.eqz
+        br_if $break1
+        block $if3
+          block
+            local.get $v_i
+            i64.const 1
+            i64.eq
+            i32.eqz
+            br_if 0
+            i32.const 208
+            call $print_str
+            call $println
+            br $if3
+          end
+          local.get $v_i
+          i32.const 216
+          i32.const 248
+          call $enter
+          call $f_sq
+          call $leave
+          local.set $t0
+          f64.const 0.5
+          local.set $t1
+          local.get $t0
+          call $print_int
+          local.get $t1
+          call $print_float
+          call $println
+        end
+        local.get $v_i
+        i64.const 1
+        i32.const 268
+        call $add
+        local.set $v_i
+        br $continue2
+      end
+    end
+    i32.const 16
+    call $alloc
+    local.set $t2
+    local.get $t2
+    i64.const 1
+    i64.store
+    local.get $t2
+    i64.const 2
+    i64.store offset=8
+    local.get $t2
+    local.set $v_p
+    i32.const 32
+    call $alloc
+    local.set $t3
+    local.get $t3
+    i32.const 3
+    i32.store
+    local.get $t3
+    i64.const 0
+    i64.store offset=8
+    local.get $t3
+    i64.const 0
+    i64.store offset=16
+    local.get $t3
+    i64.const 0
+    i64.store offset=24
+    local.get $t3
+    local.set $v_a
+    i64.const 0
+    local.set $v_j
+    block $break4
+      loop $loop5
+        local.get $v_j
+        i64.const 5
+        i64.lt_s
+        i32.eqz
+        br_if $break4
+        block $continue6
+          block $if7
+            block
+              local.get $v_j
+              i64.const 1
+              i64.eq
+              i32.eqz
+              br_if 0
+              br $continue6
+              br $if7
+            end
+          end
+          block $if8
+            block
+              local.get $v_j
+              i64.const 3
+              i64.eq
+              i32.eqz
+              br_if 0
+              br $break4
+              br $if8
+            end
+          end
+          local.get $v_j
+          i64.const 1
+          i32.const 288
+          call $add
+          local.set $t4
+          local.get $v_a
+          local.get $v_j
+          i32.const 308
+          call $index
+          local.get $t4
+          i64.store
+          local.get $v_p
+          i64.load offset=8
+          local.get $v_j
+          i32.const 328
+          call $add
+          local.set $t5
+          local.get $v_p
+          local.get $t5
+          i64.store offset=8
+        end
+        local.get $v_j
+        i64.const 1
+        i32.const 348
+        call $add
+        local.set $v_j
+        br $loop5
+      end
+    end
+    local.get $v_a
+    i64.const 0
+    i32.const 368
+    call $index
+    i64.load
+    local.set $t6
+    local.get $v_a
+    i64.const 1
+    i32.const 388
+    call $index
+    i64.load
+    local.set $t7
+    local.get $v_a
+    i64.const 2
+    i32.const 408
+    call $index
+    i64.load
+    local.set $t8
+    i32.const 428
+    local.set $t9
+    local.get $v_p
+    i64.load
+    local.set $t10
+    local.get $v_p
+    i64.load offset=8
+    local.set $t11
+    local.get $t6
+    call $print_int
+    local.get $t7
+    call $print_int
+    local.get $t8
+    call $print_int
+    local.get $t9
+    call $print_str
+    local.get $t10
+    call $print_int
+    local.get $t11
+    call $print_int
+    call $println))
@@ -0,0 +1,1068 @@
+use ast::{self, Expr, Lit, Stmt};
+use bytecode;
+use compile;
+use diagnostic::Diagnostic;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use emit_asm;
+use emit_c;
+use emit_wat;
+use interpret::{self, Interpreter, RuntimeErr, MAX_CALL_DEPTH};
+use lex::{self, LexErr};
+use parse::{self, NodeType, NTermType, ParseErr};
+use resolve::{self, Bindings};
+use source::SourceMap;
+use token::{Point, Span, Token, TokenType};
+use typeck::{self, Types};
+use vm::Vm;
+use run_file;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Lexes, parses and lowers a whole program, panicking on any error
+fn lower(src: &str) -> ast::Block {
+    let (tokens, lex_errs) = lex::lex(src);
+    assert_eq!(lex_errs, vec![]);
+    let (tree, parse_errs) = parse::parse(&tokens, src);
+    assert_eq!(parse_errs, vec![]);
+    ast::lower(&tree, src).unwrap()
+}
+
+/// Writes an expression with every operation bracketed, to show how it nested
+fn show(expr: &Expr) -> String {
+    match *expr {
+        Expr::Var { ref name } => name.name.clone(),
+        Expr::Lit { val: Lit::Int(i), .. } => i.to_string(),
+        Expr::Lit { val: Lit::Bool(b), .. } => b.to_string(),
+        Expr::Lit { ref val, .. } => format!("{:?}", val),
+        Expr::Unary { op, ref operand, .. } => format!("({}{})", op.symbol(), show(operand)),
+        Expr::Binary { op, ref lhs, ref rhs, .. } =>
+            format!("({} {} {})", show(lhs), op.symbol(), show(rhs)),
+        Expr::Call { ref callee, ref args, .. } =>
+            format!("{}({})", callee.name, args.iter().map(show).collect::<Vec<_>>().join(", ")),
+        Expr::Array { ref elems, .. } =>
+            format!("[{}]", elems.iter().map(show).collect::<Vec<_>>().join(", ")),
+        Expr::Index { ref base, ref index, .. } => format!("{}[{}]", show(base), show(index)),
+        Expr::StructLit { ref name, ref fields, .. } =>
+            format!("{} {{{}}}", name.name, fields.iter()
+                    .map(|(f, v)| format!("{}: {}", f.name, show(v)))
+                    .collect::<Vec<_>>().join(", ")),
+        Expr::Field { ref base, ref field, .. } => format!("{}.{}", show(base), field.name),
+    }
+}
+
+/// Parses src as the initialiser of a declaration and shows how it nested
+fn parse_expr(src: &str) -> String {
+    let program = lower(&format!("int x = {};", src));
+    match program[0] {
+        Stmt::Declare { ref init, .. } => show(init),
+        ref stmt => panic!("Expected a declaration, got {:?}", stmt),
+    }
+}
+
+/// Runs every stage up to type checking, returning None if there are any
+/// errors
+fn check(src: &str) -> Option<(ast::Block, Bindings, Types)> {
+    let (tokens, lex_errs) = lex::lex(src);
+    let (tree, parse_errs) = parse::parse(&tokens, src);
+    if !lex_errs.is_empty() || !parse_errs.is_empty() {
+        return None;
+    }
+    let program = ast::lower(&tree, src).ok()?;
+    let (bindings, resolve_errs) = resolve::resolve(&program);
+    if !resolve_errs.is_empty() {
+        return None;
+    }
+    let (types, type_errs) = typeck::check(&program, &bindings);
+    if !type_errs.is_empty() {
+        return None;
+    }
+    Some((program, bindings, types))
+}
+
+/// Compiles a program to bytecode, returning None if it has any errors
+fn compile(src: &str) -> Option<bytecode::Program> {
+    check(src).map(|(program, bindings, _)| compile::compile(&program, &bindings))
+}
+
+/// A directory for a test's build files, removed when dropped, even if the
+/// test fails
+struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates the directory. name must be unique between tests.
+    fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("hl2-test-{}-{}", ::std::process::id(), name));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Compiles a program to C, builds it with the system C compiler and runs it,
+/// returning what it printed. name must be unique between tests.
+fn run_c(src: &str, name: &str) -> String {
+    let (program, bindings, types) = check(src).expect("Program has errors");
+    let c = emit_c::emit(&program, &bindings, &types, name, &SourceMap::new(src));
+    let dir = TempDir::new(&format!("c-{}", name));
+    let c_path = dir.0.join(format!("{}.c", name));
+    let exe_path = dir.0.join(name);
+    fs::write(&c_path, c).unwrap();
+    let cc = Command::new("cc").arg("-std=c99").arg("-o").arg(&exe_path).arg(&c_path)
+        .output().expect("Failed to run cc");
+    assert!(cc.status.success(), "cc failed:\n{}", String::from_utf8_lossy(&cc.stderr));
+    let out = Command::new(&exe_path).output().unwrap();
+    String::from_utf8(out.stdout).unwrap()
+}
+
+/// Compiles a program to assembly, links it with the runtime and runs it,
+/// returning its exit status, stdout and stderr. The assembly is x86-64 System
+/// V with ELF directives, so this only works on x86-64 Linux.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn run_asm(src: &str, name: &str) -> (bool, String, String) {
+    let (program, bindings, types) = check(src).expect("Program has errors");
+    let asm = emit_asm::emit(&program, &bindings, &types, name, &SourceMap::new(src));
+    let dir = TempDir::new(&format!("asm-{}", name));
+    let asm_path = dir.0.join(format!("{}.s", name));
+    let rt_path = dir.0.join("rt.c");
+    let exe_path = dir.0.join(name);
+    fs::write(&asm_path, asm).unwrap();
+    fs::write(&rt_path, emit_asm::runtime()).unwrap();
+    let cc = Command::new("cc").arg("-o").arg(&exe_path).arg(&asm_path).arg(&rt_path)
+        .output().expect("Failed to run cc");
+    assert!(cc.status.success(), "cc failed:\n{}", String::from_utf8_lossy(&cc.stderr));
+    let out = Command::new(&exe_path).output().unwrap();
+    (out.status.success(), String::from_utf8(out.stdout).unwrap(),
+     String::from_utf8(out.stderr).unwrap())
+}
+
+/// The name and source of every example program in res/, failing if any of
+/// them has errors
+fn examples() -> Vec<(String, String)> {
+    let mut examples: Vec<_> = fs::read_dir("res").unwrap().map(|entry| {
+        let path = entry.unwrap().path();
+        let src = fs::read_to_string(&path).unwrap();
+        assert!(check(&src).is_some(), "{} has errors", path.display());
+        (path.file_stem().unwrap().to_str().unwrap().to_owned(), src)
+    }).collect();
+    examples.sort();
+    examples
+}
+
+/// Runs a program, returning what it printed
+fn run(src: &str) -> String {
+    let program = lower(src);
+    let mut out = Vec::new();
+    interpret::with_stack(|| Interpreter::new(&mut out).run(&program).unwrap());
+    String::from_utf8(out).unwrap()
+}
+
+/// Runs a program on the VM, returning what it printed or the runtime error
+fn run_vm(src: &str) -> Result<String, RuntimeErr> {
+    let program = compile(src).expect("Program has errors");
+    let mut out = Vec::new();
+    Vm::new(&program, &mut out).run()?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+/// Runs a program which should fail at runtime, returning the error
+fn run_err(src: &str) -> RuntimeErr {
+    let program = lower(src);
+    interpret::with_stack(|| Interpreter::new(Vec::new()).run(&program).unwrap_err())
+}
+
+#[test]
+fn examples_run_without_errors() {
+    for (_, src) in examples() {
+        run(&src);
+    }
+}
+
+#[test]
+fn fizzbuzz_prints_the_expected_output() {
+    let expected: String = (1..101).map(|i| match (i % 3, i % 5) {
+        (0, 0) => "FizzBuzz\n".to_owned(),
+        (0, _) => "Fizz\n".to_owned(),
+        (_, 0) => "Buzz\n".to_owned(),
+        _ => format!("{}\n", i),
+    }).collect();
+    assert_eq!(run(&fs::read_to_string("res/fizzbuzz.hl2").unwrap()), expected);
+}
+
+/// Returns each error's message and the line:col it starts at, if it has one
+fn locate_errs(src: &str, errs: Vec<(String, Option<Span>)>) -> Vec<(String, String)> {
+    let map = SourceMap::new(src);
+    errs.into_iter()
+        .map(|(msg, span)| (msg, span.map_or(String::new(), |s| map.location(s.start).to_string())))
+        .collect()
+}
+
+/// Lexes src, returning each error's message and location
+fn lex_errs(src: &str) -> Vec<(String, String)> {
+    locate_errs(src, lex::lex(src).1.into_iter().map(|e| match e {
+        LexErr::Raw(msg) => (msg, None),
+        LexErr::Point(msg, span) => (msg, Some(span)),
+    }).collect())
+}
+
+#[test]
+fn lexer_reports_every_error_in_one_pass() {
+    let src = "int x = 1 @ 2;\nstring s = \"a\" # \"b\";\nbool b = x & y;\nint y = 3$;";
+    assert_eq!(lex_errs(src), vec![
+        ("Unknown token".to_owned(), "1:11".to_owned()),
+        ("Unknown token".to_owned(), "2:16".to_owned()),
+        ("Bitwise & operator is not supported.".to_owned(), "3:12".to_owned()),
+        ("Unknown token".to_owned(), "4:10".to_owned()),
+    ]);
+    // Each error leaves an Error token spanning the skipped input, and lexing
+    // carries on to the end
+    let (tokens, _) = lex::lex(src);
+    let skipped: Vec<_> = tokens.iter()
+        .filter(|t| t.token_type == TokenType::Error)
+        .map(|t| t.val(src))
+        .collect();
+    assert_eq!(skipped, vec!["@", "#", "&", "$"]);
+    assert_eq!(tokens.last().unwrap().val(src), ";");
+}
+
+/// Parses src, returning the tree and each error's message and location
+fn parse_errs(src: &str) -> (parse::Node, Vec<(String, String)>) {
+    let (tokens, _) = lex::lex(src);
+    let (tree, errs) = parse::parse(&tokens, src);
+    (tree, locate_errs(src, errs.into_iter().map(|e| match e {
+        ParseErr::Raw(msg) => (msg, None),
+        ParseErr::Point(msg, span) => (msg, Some(span)),
+    }).collect()))
+}
+
+/// Returns the kind of each child of a parse tree node
+fn kinds(node: &parse::Node) -> Vec<String> {
+    node.children.iter().map(|child| match child.node_type {
+        NodeType::NTerm(ref nterm) => format!("{:?}", nterm),
+        NodeType::Term(tok) => format!("{:?}", tok.token_type),
+    }).collect()
+}
+
+#[test]
+fn parser_synchronises_after_an_error() {
+    let src = "\
+int x = ;
+println(1);
+while (true) {
+    int y = 2 +;
+    x = 1;
+}
+if (x > ) { println(2); }
+int z = 3
+println(z);
+println(4);
+}
+println(5);";
+    let (tree, errs) = parse_errs(src);
+    assert_eq!(errs, vec![
+        ("Expected identifier, literal, `(` or `[`".to_owned(), "1:9".to_owned()),
+        ("Expected identifier, literal, `(` or `[`".to_owned(), "4:16".to_owned()),
+        ("Expected identifier, literal, `(` or `[`".to_owned(), "7:9".to_owned()),
+        ("Expected ;, got println".to_owned(), "9:1".to_owned()),
+        ("Unmatched `}`".to_owned(), "11:1".to_owned()),
+    ]);
+    // Bad statements become Error nodes, and the statements around them are
+    // still parsed, including after a block skipped whole and the unmatched }
+    assert_eq!(kinds(&tree), vec!["Error", "Stmt", "While", "Error", "Error", "Stmt", "Stmt"]);
+    let body = tree.children[2].children.iter()
+        .find(|child| child.node_type == NodeType::NTerm(NTermType::Program))
+        .unwrap();
+    assert_eq!(kinds(body), vec!["Error", "Stmt"]);
+}
+
+/// Returns each token's type and source
+fn token_vals<'a>(tokens: &[Token], src: &'a str) -> Vec<(TokenType, &'a str)> {
+    tokens.iter().map(|t| (t.token_type, t.val(src))).collect()
+}
+
+#[test]
+fn comments_nest_and_are_kept_as_trivia() {
+    use token::TokenType::*;
+    let src = "x = 4 / 2; // line /* not a block\n/* outer /* inner */ still */ x = 1;\n// at eof";
+    let (tokens, errs) = lex::lex_with_trivia(src);
+    assert_eq!(errs, vec![]);
+    assert_eq!(token_vals(&tokens, src), vec![
+        (Ident, "x"), (Op, "="), (IntLit, "4"), (Op, "/"), (IntLit, "2"), (Punc, ";"),
+        (Comment, "// line /* not a block"),
+        (Comment, "/* outer /* inner */ still */"),
+        (Ident, "x"), (Op, "="), (IntLit, "1"), (Punc, ";"),
+        (Comment, "// at eof"),
+    ]);
+    let (tokens, _) = lex::lex(src);
+    assert!(tokens.iter().all(|t| t.token_type != Comment));
+    assert_eq!(tokens.len(), 10);
+}
+
+#[test]
+fn unterminated_comments_are_reported() {
+    let src = "int x;\n/* a /* b */ c";
+    assert_eq!(lex_errs(src), vec![("Unterminated block comment".to_owned(), "2:1".to_owned())]);
+    let (tokens, _) = lex::lex(src);
+    assert_eq!(token_vals(&tokens[2..], src), vec![(TokenType::Punc, ";"),
+                                                   (TokenType::Error, "/* a /* b */ c")]);
+}
+
+#[test]
+fn reserved_words_are_only_whole_words() {
+    use token::TokenType::*;
+    let src = "integer stringBuilder boolean trueCount int if true";
+    assert_eq!(token_vals(&lex::lex(src).0, src), vec![
+        (Ident, "integer"), (Ident, "stringBuilder"), (Ident, "boolean"), (Ident, "trueCount"),
+        (CoreType, "int"), (Key, "if"), (BoolLit, "true"),
+    ]);
+    // A reserved word right at the end of the file still lexes
+    for &src in &["if", "x = iffy"] {
+        let (tokens, errs) = lex::lex(src);
+        assert_eq!(errs, vec![], "{}", src);
+        assert_eq!(tokens.last().unwrap().val(src), src.rsplit(' ').next().unwrap());
+    }
+}
+
+#[test]
+fn reserved_words_cannot_be_names() {
+    let src = "int if = 1;";
+    let (tokens, _) = lex::lex(src);
+    assert_eq!(parse::parse(&tokens, src).1, vec![ParseErr::Point(
+        "`if` is a reserved word and cannot be used as a name".to_owned(),
+        Span { start: Point(4), end: Point(6) })]);
+}
+
+#[test]
+fn string_escapes_are_decoded() {
+    let raw = r#""a\n\t\r\\\"\u{1F600}\u{e9}\0""#;
+    assert_eq!(lex::decode_string_lit(raw, 0), Ok("a\n\t\r\\\"\u{1F600}\u{e9}\0".to_owned()));
+    assert_eq!(lex::decode_string_lit(r#""no escapes""#, 0), Ok("no escapes".to_owned()));
+}
+
+#[test]
+fn bad_escapes_are_reported_at_their_column() {
+    let src = r#"string s = "ok\q";
+println("é\u{110000}");
+println("\u{D800}", "\u1F600");
+println("\u{12");"#;
+    assert_eq!(lex_errs(src), vec![
+        ("Unknown escape `\\q`".to_owned(), "1:15".to_owned()),
+        ("`110000` is not a valid unicode character".to_owned(), "2:11".to_owned()),
+        ("`D800` is not a valid unicode character".to_owned(), "3:10".to_owned()),
+        ("Unicode escape must be of the form `\\u{XXXX}`".to_owned(), "3:22".to_owned()),
+        ("Unterminated unicode escape".to_owned(), "4:10".to_owned()),
+    ]);
+    // The error covers just the escape
+    let errs = lex::lex(src).1;
+    let spans: Vec<_> = errs.iter().map(|e| e.span().unwrap()).collect();
+    let escapes: Vec<_> = spans.iter().map(|s| &src[s.start.0..s.end.0]).collect();
+    assert_eq!(escapes, vec!["\\q", "\\u{110000}", "\\u{D800}", "\\u", "\\u{12"]);
+}
+
+#[test]
+fn number_literals_are_classified_as_int_or_float() {
+    use token::TokenType::*;
+    let src = "1 1.5 1.e3 1e3 2E-3 1.5e+3 0x1F 0b101 0o17 1_000 x.0";
+    let (tokens, errs) = lex::lex(src);
+    assert_eq!(errs, vec![]);
+    assert_eq!(token_vals(&tokens, src), vec![
+        (IntLit, "1"), (FloatLit, "1.5"),
+        // A `.` without a digit after it isn't a decimal point
+        (IntLit, "1"), (Punc, "."), (Ident, "e3"),
+        (FloatLit, "1e3"), (FloatLit, "2E-3"), (FloatLit, "1.5e+3"),
+        (IntLit, "0x1F"), (IntLit, "0b101"), (IntLit, "0o17"), (IntLit, "1_000"),
+        (Ident, "x"), (Punc, "."), (IntLit, "0"),
+    ]);
+}
+
+#[test]
+fn number_literals_are_decoded() {
+    let ints = [("0", 0), ("42", 42), ("0x1F", 31), ("0xff_ff", 0xffff), ("0b101", 5),
+                ("0o17", 15), ("1_000_000", 1_000_000), ("9223372036854775807", i64::MAX),
+                ("0x7FFF_FFFF_FFFF_FFFF", i64::MAX)];
+    for &(raw, val) in &ints {
+        assert_eq!(lex::decode_int_lit(raw, 0), Ok(val), "{}", raw);
+    }
+    let floats = [("1.5", 1.5), ("1e3", 1000.0), ("2E-3", 0.002), ("1.5e+3", 1500.0),
+                  ("1_0.2_5", 10.25), ("1e1_0", 1e10)];
+    for &(raw, val) in &floats {
+        assert_eq!(lex::decode_float_lit(raw, 0), Ok(val), "{}", raw);
+    }
+}
+
+#[test]
+fn bad_number_literals_are_reported() {
+    let src = "int a = 0x;\nint b = 0b102;\nint c = 0o8;\nfloat d = 1e;\nfloat e = 2.5e+;\n\
+               int f = 12abc;\nint g = 0xG;";
+    assert_eq!(lex_errs(src), vec![
+        ("Expected digits after `0x`".to_owned(), "1:9".to_owned()),
+        ("Invalid digit `2` in base 2 literal".to_owned(), "2:9".to_owned()),
+        ("Invalid digit `8` in base 8 literal".to_owned(), "3:9".to_owned()),
+        ("Expected digits in exponent".to_owned(), "4:11".to_owned()),
+        ("Expected digits in exponent".to_owned(), "5:11".to_owned()),
+        ("Unexpected `a` after number literal".to_owned(), "6:9".to_owned()),
+        ("Invalid digit `G` in base 16 literal".to_owned(), "7:9".to_owned()),
+    ]);
+}
+
+#[test]
+fn out_of_range_number_literals_are_reported() {
+    let src = "int a = 9223372036854775808;\nint b = 0x8000_0000_0000_0000;\n\
+               int c = 0b1_0000000000000000000000000000000000000000000000000000000000000000;\n\
+               float d = 1e400;";
+    assert_eq!(lex_errs(src), vec![
+        ("Integer literal out of range: the largest int is 9223372036854775807".to_owned(),
+         "1:9".to_owned()),
+        ("Integer literal out of range: the largest int is 9223372036854775807".to_owned(),
+         "2:9".to_owned()),
+        ("Integer literal out of range: the largest int is 9223372036854775807".to_owned(),
+         "3:9".to_owned()),
+        ("Float literal out of range".to_owned(), "4:11".to_owned()),
+    ]);
+    // The literal is still lexed, so the error covers all of it
+    let (tokens, errs) = lex::lex(src);
+    assert_eq!(errs[0].span(), Some(tokens[3].span()));
+    assert_eq!(tokens[3].token_type, TokenType::IntLit);
+}
+
+#[test]
+fn minus_is_always_an_operator() {
+    use token::TokenType::*;
+    for &src in &["x-1", "1-1", "x - -1"] {
+        let (tokens, errs) = lex::lex(src);
+        assert_eq!(errs, vec![]);
+        let expected = match src {
+            "x-1" => vec![(Ident, "x"), (Op, "-"), (IntLit, "1")],
+            "1-1" => vec![(IntLit, "1"), (Op, "-"), (IntLit, "1")],
+            _ => vec![(Ident, "x"), (Op, "-"), (Op, "-"), (IntLit, "1")],
+        };
+        assert_eq!(token_vals(&tokens, src), expected);
+    }
+    assert_eq!(parse_expr("x-1"), "(x - 1)");
+    assert_eq!(parse_expr("1-1"), "(1 - 1)");
+}
+
+#[test]
+fn unclosed_parens_point_at_the_opening_paren() {
+    let cases = [("int x = (a + b * c;", "`;`", "1:9"), ("f(1, 2;", "`;`", "1:2"),
+                 ("if (x == 1 { }", "`{`", "1:4"), ("while (x == 1 { }", "`{`", "1:7"),
+                 ("for (int i = 0; i < 3; i = i + 1 { }", "`{`", "1:5"),
+                 ("fn f(int x { }", "`{`", "1:5")];
+    for &(src, got, at) in &cases {
+        assert_eq!(parse_errs(src).1,
+                   vec![(format!("Unclosed `(`: expected `)`, got {}", got), at.to_owned())],
+                   "{}", src);
+    }
+}
+
+#[test]
+fn unary_ops_nest_to_the_right() {
+    assert_eq!(parse_expr("- -a"), "(-(-a))");
+    assert_eq!(parse_expr("!!a"), "(!(!a))");
+    assert_eq!(parse_expr("-a * b"), "((-a) * b)");
+}
+
+#[test]
+fn term0_is_left_associative() {
+    assert_eq!(parse_expr("8 / 4 / 2"), "((8 / 4) / 2)");
+    assert_eq!(parse_expr("a * b % c / d"), "(((a * b) % c) / d)");
+}
+
+#[test]
+fn term1_is_left_associative() {
+    assert_eq!(parse_expr("10 - 3 - 2"), "((10 - 3) - 2)");
+    assert_eq!(parse_expr("a + b - c + d"), "(((a + b) - c) + d)");
+}
+
+#[test]
+fn term2_is_left_associative() {
+    assert_eq!(parse_expr("a < b == c"), "((a < b) == c)");
+    assert_eq!(parse_expr("a == b == c"), "((a == b) == c)");
+}
+
+#[test]
+fn term3_is_left_associative() {
+    assert_eq!(parse_expr("a && b && c"), "((a && b) && c)");
+    assert_eq!(parse_expr("a != b && c == d"), "((a != b) && (c == d))");
+}
+
+#[test]
+fn expression_is_left_associative() {
+    assert_eq!(parse_expr("a || b || c"), "((a || b) || c)");
+}
+
+#[test]
+fn and_binds_tighter_than_or() {
+    assert_eq!(parse_expr("a && b || c"), "((a && b) || c)");
+    assert_eq!(parse_expr("a || b && c"), "(a || (b && c))");
+    assert_eq!(parse_expr("!a || b"), "((!a) || b)");
+}
+
+#[test]
+fn higher_precedence_binds_tighter() {
+    assert_eq!(parse_expr("a + b * c"), "(a + (b * c))");
+    assert_eq!(parse_expr("a * b + c * d"), "((a * b) + (c * d))");
+    assert_eq!(parse_expr("a + b < c - d"), "((a + b) < (c - d))");
+    assert_eq!(parse_expr("a < b && c >= d"), "((a < b) && (c >= d))");
+    assert_eq!(parse_expr("-a + !b * c"), "((-a) + ((!b) * c))");
+}
+
+#[test]
+fn parentheses_override_precedence() {
+    assert_eq!(parse_expr("(a + b) * c"), "((a + b) * c)");
+    assert_eq!(parse_expr("a - (b - c)"), "(a - (b - c))");
+}
+
+#[test]
+fn logical_ops_short_circuit() {
+    let src = "
+        fn loud(bool b) -> bool { print(b, \" \"); return b; }
+        println(false && loud(true));
+        println(true || loud(false));
+        println(true && loud(false));
+        println(false || loud(true));
+    ";
+    assert_eq!(run(src), "false\ntrue\nfalse false\ntrue true\n");
+}
+
+#[test]
+fn not_equal_compares_every_type() {
+    assert_eq!(run("println(1 != 2, 1.5 != 1.5, \"a\" != \"b\", true != true);"),
+               "truefalsetruefalse\n");
+}
+
+#[test]
+fn left_associative_arithmetic_evaluates_correctly() {
+    assert_eq!(run("println(10 - 3 - 2, \" \", 8 / 4 / 2, \" \", 2 * 7 % 4);"), "5 1 2\n");
+}
+
+#[test]
+fn else_if_chains_are_one_flat_if() {
+    let program = lower("if (a) { } else if (b) { } else if (c) { } else { }");
+    match program[0] {
+        Stmt::If { ref arms, ref else_body, .. } => {
+            let conds: Vec<_> = arms.iter().map(|arm| show(&arm.cond)).collect();
+            assert_eq!(conds, vec!["a", "b", "c"]);
+            assert!(else_body.is_some());
+        }
+        ref stmt => panic!("Expected an if, got {:?}", stmt),
+    }
+}
+
+#[test]
+fn else_if_runs_the_first_matching_arm() {
+    let src = "
+        fn classify(int i) -> string {
+            if (i < 0) { return \"negative\"; }
+            else if (i == 0) { return \"zero\"; }
+            else if (i < 10) { return \"small\"; }
+            else { return \"large\"; }
+        }
+        println(classify(-1), \" \", classify(0), \" \", classify(5), \" \", classify(50));
+    ";
+    assert_eq!(run(src), "negative zero small large\n");
+}
+
+#[test]
+fn for_loops_support_break_and_continue() {
+    let src = "
+        for (int i = 0; i < 10; i = i + 1) {
+            if (i == 2) { continue; }
+            if (i == 5) { break; }
+            print(i);
+        }
+        int j = 0;
+        for (; j < 3;) { j = j + 1; }
+        println(\" \", j);
+    ";
+    assert_eq!(run(src), "0134 3\n");
+}
+
+#[test]
+fn for_step_cannot_be_a_declaration() {
+    let src = "for (int i = 0; i < 3; int j = i) { println(i); }";
+    let (tokens, _) = lex::lex(src);
+    let (_, errs) = parse::parse(&tokens, src);
+    let start = src.find("int j").unwrap();
+    assert_eq!(errs, vec![parse::ParseErr::Point(
+        "Expected assignment or function call, got a declaration".to_owned(),
+        Span { start: Point(start), end: Point(start + "int j = i".len()) })]);
+}
+
+#[test]
+fn break_outside_a_loop_is_an_error() {
+    let program = lower("break; while (true) { break; } fn f() { continue; }");
+    let (_, errs) = resolve::resolve(&program);
+    assert_eq!(errs.len(), 2);
+}
+
+#[test]
+fn indexing_nests_to_the_left() {
+    assert_eq!(parse_expr("a[i][j]"), "a[i][j]");
+    assert_eq!(parse_expr("-a[i] * [1, 2][0]"), "((-a[i]) * [1, 2][0])");
+}
+
+#[test]
+fn arrays_are_shared_by_reference() {
+    let src = "
+        int[] a = [1, 2, 3];
+        int[] b = a;
+        b[0] = 10;
+        int[][] grid = [[1], [2, 3]];
+        grid[1][0] = a[0];
+        println(a[0], \" \", len(a), \" \", grid[1][0], \" \", len(grid[1]));
+    ";
+    assert_eq!(run(src), "10 3 10 2\n");
+}
+
+#[test]
+fn out_of_bounds_index_is_a_runtime_error() {
+    match run_err("int[] a = [1, 2];\nprintln(a[2]);") {
+        RuntimeErr::Point(msg, _) =>
+            assert_eq!(msg, "Index 2 is out of bounds for an array of length 2"),
+        e => panic!("Expected a located error, got {:?}", e),
+    }
+}
+
+#[test]
+fn field_access_nests_with_indexing() {
+    assert_eq!(parse_expr("a.b[i].c"), "a.b[i].c");
+    assert_eq!(parse_expr("P { x: -p.x, y: 1 }.y"), "P {x: (-p.x), y: 1}.y");
+}
+
+#[test]
+fn structs_are_shared_by_reference() {
+    let src = "
+        struct Point { int x; int y; }
+        struct Line { Point a; Point b; }
+        fn shift(Point p, int dx) { p.x = p.x + dx; }
+        Point p = Point { y: 2, x: 1 };
+        shift(p, 10);
+        Line l = Line { a: p, b: Point { x: 0, y: 0 } };
+        l.a.y = 5;
+        println(p.x, \" \", p.y, \" \", l.b.x);
+    ";
+    assert_eq!(run(src), "11 5 0\n");
+}
+
+/// The span of the nth occurrence of needle in src
+fn span_of(src: &str, needle: &str, nth: usize) -> Span {
+    let start = src.match_indices(needle).nth(nth).unwrap().0;
+    Span { start: Point(start), end: Point(start + needle.len()) }
+}
+
+/// Type checks a program with no scope errors, returning the type errors
+fn type_errs(src: &str) -> Vec<Diagnostic> {
+    let program = lower(src);
+    let (bindings, errs) = resolve::resolve(&program);
+    assert_eq!(errs, vec![]);
+    typeck::check(&program, &bindings).1
+}
+
+#[test]
+fn diagnostics_render_labels_notes_and_help() {
+    ::colored::control::set_override(false);
+    let src = "int x = 1;\nint y = x + true;\n";
+    let diagnostic = Diagnostic::error("Mismatched types".to_owned())
+        .primary(span_of(src, "x + true", 0), "expected int".to_owned())
+        .label(span_of(src, "x", 0), "declared here".to_owned())
+        .note("a note".to_owned())
+        .help("a help".to_owned());
+    assert_eq!(diagnostic.render("test.hl2", &SourceMap::new(src)), "\
+error: Mismatched types
+ --> test.hl2:2:9
+  |
+1 | int x = 1;
+  |     - declared here
+2 | int y = x + true;
+  |         ^^^^^^^^ expected int
+  = note: a note
+  = help: a help
+");
+}
+
+#[test]
+fn diagnostics_render_spans_on_a_last_line_with_no_newline() {
+    ::colored::control::set_override(false);
+    let src = "int x = 1;\n\n\n\n\n\n\n\n\nprintln(yy)";
+    let diagnostic = Diagnostic::spanned("Undefined variable `yy`".to_owned(),
+                                         span_of(src, "yy", 0));
+    assert_eq!(diagnostic.render("test.hl2", &SourceMap::new(src)), "\
+error: Undefined variable `yy`
+  --> test.hl2:10:9
+   |
+10 | println(yy)
+   |         ^^
+");
+}
+
+#[test]
+fn undefined_variables_are_errors() {
+    let src = "int x = 1;\nprintln(x + y);";
+    assert_eq!(resolve::resolve(&lower(src)).1, vec![
+        Diagnostic::spanned("Undefined variable `y`".to_owned(), span_of(src, "y", 0)),
+    ]);
+}
+
+#[test]
+fn duplicate_declarations_in_a_scope_are_errors() {
+    let src = "int x = 1;\nwhile (x < 2) { int x = 2; x = 3; }\nint x = 4;";
+    assert_eq!(resolve::resolve(&lower(src)).1, vec![
+        Diagnostic::spanned("Duplicate declaration of `x` in the same scope".to_owned(),
+                            span_of(src, "x", 4))
+            .label(span_of(src, "x", 0), "first declared here".to_owned()),
+    ]);
+}
+
+#[test]
+fn variables_used_before_their_declaration_are_errors() {
+    let src = "if (true) { println(x); int x = 2; }";
+    assert_eq!(resolve::resolve(&lower(src)).1, vec![
+        Diagnostic::spanned("Variable `x` used before its declaration".to_owned(),
+                            span_of(src, "x", 0))
+            .label(span_of(src, "x", 1), "declared here".to_owned()),
+    ]);
+}
+
+#[test]
+fn declarations_must_match_their_type() {
+    let src = "int x = true;";
+    assert_eq!(type_errs(src), vec![
+        Diagnostic::error("Mismatched types: expected int, found bool".to_owned())
+            .primary(span_of(src, "true", 0), "expected int".to_owned()),
+    ]);
+}
+
+#[test]
+fn conditions_must_be_bool() {
+    let src = "if (1) { }\nwhile (\"a\") { }";
+    assert_eq!(type_errs(src), vec![
+        Diagnostic::error("Mismatched types: expected bool, found int".to_owned())
+            .primary(span_of(src, "1", 0), "expected bool".to_owned()),
+        Diagnostic::error("Mismatched types: expected bool, found string".to_owned())
+            .primary(span_of(src, "\"a\"", 0), "expected bool".to_owned()),
+    ]);
+}
+
+#[test]
+fn arrays_and_structs_cannot_be_compared() {
+    let src = "struct P { int x; }\nint[] a = [1];\nP p = P { x: 1 };\nbool b = a == a;\n\
+               bool c = p != p;";
+    assert_eq!(type_errs(src), vec![
+        Diagnostic::spanned("Operator `==` cannot be applied to int[] and int[]".to_owned(),
+                            span_of(src, "==", 0))
+            .label(span_of(src, "a", 1), "int[]".to_owned())
+            .label(span_of(src, "a", 2), "int[]".to_owned()),
+        Diagnostic::spanned("Operator `!=` cannot be applied to P and P".to_owned(),
+                            span_of(src, "!=", 0))
+            .label(span_of(src, "p", 1), "P".to_owned())
+            .label(span_of(src, "p", 2), "P".to_owned()),
+    ]);
+}
+
+#[test]
+fn unknown_fields_suggest_the_nearest_field() {
+    let program = lower("
+        struct Point { int x; int y; }
+        Point p = Point { x: 1, y: 2 };
+        println(p.yy);
+    ");
+    let (bindings, errs) = resolve::resolve(&program);
+    assert_eq!(errs, vec![]);
+    let field_span = match program[2] {
+        Stmt::Expr { expr: Expr::Call { ref args, .. }, .. } => match args[0] {
+            Expr::Field { ref field, .. } => field.span,
+            ref expr => panic!("Expected a field, got {:?}", expr),
+        },
+        ref stmt => panic!("Expected a call, got {:?}", stmt),
+    };
+    assert_eq!(typeck::check(&program, &bindings).1, vec![
+        Diagnostic::spanned("No field `yy` on struct `Point`".to_owned(), field_span)
+            .help("did you mean `y`?".to_owned()),
+    ]);
+}
+
+#[test]
+fn vm_matches_interpreter_on_examples() {
+    for (name, src) in examples() {
+        assert_eq!(run_vm(&src), Ok(run(&src)), "in {}", name);
+    }
+}
+
+#[test]
+fn vm_runs_control_flow_like_the_interpreter() {
+    let src = "
+        fn loud(bool b) -> bool { print(b, \" \"); return b; }
+        fn fib(int n) -> int { if (n < 2) { return n; } return fib(n - 1) + fib(n - 2); }
+        println(false && loud(true), true || loud(false), true && loud(false));
+        for (int i = 0; i < 10; i = i + 1) {
+            if (i == 2) { continue; } else if (i == 5) { break; }
+            int sq = i * i;
+            print(sq, \" \");
+        }
+        int j = 0;
+        while (true) { j = j + 1; if (j >= 3) { break; } }
+        println(j, \" \", fib(15));
+    ";
+    assert_eq!(run_vm(src), Ok(run(src)));
+}
+
+#[test]
+fn vm_reports_the_same_runtime_errors() {
+    for src in &["int[] a = [1, 2];\nprintln(a[2]);", "println(7 % (3 - 3));",
+                 "int x = -9223372036854775807 - 1;\nprintln(-x);"] {
+        assert_eq!(run_vm(src), Err(run_err(src)));
+    }
+}
+
+#[test]
+fn disassembly_shows_operands_and_lines() {
+    let src = "int x = 1;\nif (x != 2) {\n    println(\"no\");\n}";
+    let expected = "\
+fn <main> (0 params, 1 locals)
+0000    1 const          0           ; 1
+0001    | set_local      0
+0002    2 get_local      0
+0003    | const          1           ; 2
+0004    | binary         !=
+0005    | jump_if_false  0009
+0006    3 const          2           ; \"no\"
+0007    | call_builtin   println 1
+0008    | pop
+0009    2 const          3           ; void
+0010    | return
+";
+    let program = compile(src).unwrap();
+    assert_eq!(bytecode::disassemble(&program, &SourceMap::new(src)), expected);
+}
+
+/// Recurses n calls deep, each call nested inside a for, a while and two ifs
+fn deep_recursion(n: usize) -> String {
+    format!("
+        fn f(int n) -> int {{
+            if (n == 0) {{ return 0; }}
+            for (int i = 0; i < 1; i = i + 1) {{
+                while (true) {{
+                    if (true) {{ return f(n - 1) + 1; }}
+                }}
+            }}
+            return 0;
+        }}
+        println(f({}));
+    ", n - 1)
+}
+
+#[test]
+fn deep_recursion_reaches_the_call_depth_limit() {
+    assert_eq!(run(&deep_recursion(MAX_CALL_DEPTH)), format!("{}\n", MAX_CALL_DEPTH - 1));
+    match run_err(&deep_recursion(MAX_CALL_DEPTH + 1)) {
+        RuntimeErr::Point(msg, _) => assert_eq!(msg, "Stack overflow calling `f`"),
+        e => panic!("Expected a stack overflow, got {:?}", e),
+    }
+}
+
+/// Runs the command line driver on src, returning whether it succeeded and
+/// what it wrote to stdout
+fn run_cli(src: &str, name: &str, emit: Option<&str>, vm: bool) -> (bool, String) {
+    let path = env::temp_dir().join(format!("hl2-test-{}-{}.hl2", ::std::process::id(), name));
+    fs::write(&path, src).unwrap();
+    let mut out = Vec::new();
+    let ok = run_file(path.to_str().unwrap(), emit, vm, &mut out);
+    fs::remove_file(&path).unwrap();
+    (ok, String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn driver_fails_on_any_error() {
+    let modes = [(None, false), (None, true), (Some("bytecode"), false), (Some("c"), false),
+                 (Some("asm"), false), (Some("wat"), false)];
+    let bad = [("lex", "int x = 1 @ 2;"), ("parse", "int x = ;"), ("resolve", "println(y);"),
+               ("type", "int x = \"a\";")];
+    for &(emit, vm) in &modes {
+        for &(name, src) in &bad {
+            assert_eq!(run_cli(src, name, emit, vm), (false, String::new()),
+                       "{} error with {:?}", name, emit);
+        }
+        assert!(run_cli("println(1);", "good", emit, vm).0, "{:?}", emit);
+    }
+    assert_eq!(run_cli("print(1);\nprintln(1 / 0);", "runtime", None, false),
+               (false, "1".to_owned()));
+    assert_eq!(run_cli("print(1);\nprintln(1 / 0);", "runtime_vm", None, true),
+               (false, "1".to_owned()));
+    assert!(!run_file("does/not/exist.hl2", None, false, Vec::new()));
+}
+
+#[test]
+fn eof_errors_point_at_the_end_of_the_source() {
+    let cases = [("if", "Expected (, got EOF"),
+                 ("println(1,", "Unexpected EOF in parameter list"),
+                 ("fn f(int x", "Unexpected EOF in parameter list"),
+                 ("int x =\n", "Unexpected EOF")];
+    for &(src, msg) in &cases {
+        let (tokens, _) = lex::lex(src);
+        let (_, errs) = parse::parse(&tokens, src);
+        assert_eq!(errs, vec![ParseErr::Point(msg.to_owned(), Span::eof(src))], "{}", src);
+    }
+    let map = SourceMap::new("int x =\n");
+    assert_eq!(map.location(Span::eof("int x =\n").start).to_string(), "2:1");
+    assert_eq!(lex::lex(""), (vec![], vec![LexErr::Point("File is empty.".to_owned(),
+                                                         Span::eof(""))]));
+}
+
+#[test]
+fn c_backend_matches_interpreter_on_examples() {
+    for (name, src) in examples() {
+        assert_eq!(run_c(&src, &name), run(&src), "in {}", name);
+    }
+}
+
+/// Shadowing variables must not clash with variables named like their renamed
+/// versions
+const SHADOWING: &str = "
+    fn f(int x_2) -> int { int x = 1; if (true) { int x = x_2; return x; } return x; }
+    int x_2 = 5;
+    int x = 1;
+    if (true) { int x = x_2; println(x, \" \", f(7)); }
+";
+
+#[test]
+fn c_backend_keeps_shadowed_variables_apart() {
+    assert_eq!(run(SHADOWING), "5 7\n");
+    assert_eq!(run_c(SHADOWING, "shadowing"), run(SHADOWING));
+}
+
+#[test]
+fn c_backend_prints_and_evaluates_like_the_interpreter() {
+    let src = "
+        fn loud(bool b) -> bool { print(b, \" \"); return b; }
+        println(false && loud(true), true || loud(false), true && loud(false));
+        println(0.1 + 0.2, \" \", 1.0, \" \", 1e21, \" \", 1.5e-7, \" \", -0.0, \" \", 1.0 / 0.0);
+        println(7.5 % 2.0, \" \", -7.5 % 2.0, \" \", -7 % 3, \" \", 100.0 / 3.0);
+        string s = \"a\\tb\\\"c\\\\??=\\u{e9}\\0\";
+        println(s + \"!\", \" \", s == \"x\", \" \", s != \"x\");
+        int x = 1;
+        if (true) { int x = x + 1; print(x); }
+        println(x);
+    ";
+    assert_eq!(run_c(src, "print_eval"), run(src));
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn asm_backend_matches_interpreter_on_examples() {
+    for (name, src) in examples() {
+        assert_eq!(run_asm(&src, &name), (true, run(&src), String::new()), "in {}", name);
+    }
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn asm_backend_passes_arguments_and_values_like_the_interpreter() {
+    let src = "
+        struct P { float x; int[] ys; string s; }
+        fn many(int a, float b, int c, int d, int e, int f, int g, int h, float i,
+                string j) -> float {
+            println(a, b, c, d, e, f, g, h, i, j);
+            return b + i;
+        }
+        fn fib(int n) -> int { if (n < 2) { return n; } return fib(n - 1) + fib(n - 2); }
+        fn loud(bool b) -> bool { print(b, \" \"); return b; }
+        println(many(1, 2.5, 3, 4, 5, 6, 7, 8, 9.25, \"ten\"), \" \", fib(15));
+        println(false && loud(true), true || loud(false), true && loud(false));
+        println(0.1 + 0.2, \" \", -0.0, \" \", 7.5 % 2.0, \" \", -7 % 3, \" \", 0.0 / 0.0 == 0.0 / 0.0);
+        println(1.0 < 2.0, 2.0 <= 2.0, 1.0 > 2.0, 1.0 != 1.0, \"a\" + \"b\" == \"ab\");
+        P p = P { s: \"s\", ys: [1, 2], x: 0.5 };
+        P q = p;
+        q.ys[1] = -q.ys[0];
+        q.x = q.x * 3.0;
+        println(p.x, \" \", p.ys[1], \" \", len(p.ys), p.s);
+        for (int i = 0; i < 10; i = i + 1) {
+            if (i % 2 == 0) { continue; }
+            if (i > 6) { break; }
+            int sq = i * i;
+            print(sq, \" \");
+        }
+        println();
+    ";
+    assert_eq!(run_asm(src, "args_values"), (true, run(src), String::new()));
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn asm_backend_reports_runtime_errors() {
+    let (ok, out, err) = run_asm("println(1);\nint[] a = [1];\nprintln(a[-1]);", "index_err");
+    assert!(!ok);
+    assert_eq!(out, "1\n");
+    assert_eq!(err, "error: Index -1 is out of bounds for an array of length 1\n \
+                     --> index_err:3:9\n");
+    let (ok, _, err) = run_asm("int x = 9223372036854775807;\nx = x + 1;", "overflow_err");
+    assert!(!ok);
+    assert_eq!(err, "error: Integer overflow in `+`\n --> overflow_err:2:7\n");
+    let (ok, _, err) = run_asm("fn f(int n) -> int { return f(n + 1); }\nf(0);", "stack_err");
+    assert!(!ok);
+    assert!(err.starts_with("error: Stack overflow calling `f`\n"), "{}", err);
+}
+
+/// Emits a checked program as WAT
+fn emit_wat(src: &str, name: &str) -> String {
+    let (program, bindings, types) = check(src).expect("Program has errors");
+    emit_wat::emit(&program, &bindings, &types, name, &SourceMap::new(src))
+}
+
+/// Parses WAT into a binary module and validates it, panicking on errors
+fn validate_wat(wat: &str) {
+    let bytes = wat::parse_str(wat).unwrap_or_else(|e| panic!("Invalid WAT: {}", e));
+    wasmparser::Validator::new().validate_all(&bytes)
+        .unwrap_or_else(|e| panic!("Invalid module: {}", e));
+}
+
+#[test]
+fn wat_output_is_pinned() {
+    let src = "\
+fn sq(int x) -> int { return x * x; }
+int i = 0;
+while (i < 3) {
+    if (i == 1) { println(\"one\"); } else { println(sq(i), 0.5); }
+    i = i + 1;
+}
+struct P { int x; int y; }
+P p = P { x: 1, y: 2 };
+int[] a = [0, 0, 0];
+for (int j = 0; j < 5; j = j + 1) {
+    if (j == 1) { continue; }
+    if (j == 3) { break; }
+    a[j] = j + 1;
+    p.y = p.y + j;
+}
+println(a[0], a[1], a[2], \" \", p.x, p.y);
+";
+    assert_eq!(emit_wat(src, "golden.hl2"), include_str!("golden.wat"));
+}
+
+#[test]
+fn wat_output_validates() {
+    validate_wat(include_str!("golden.wat"));
+    validate_wat(&emit_wat(SHADOWING, "shadowing.hl2"));
+    for (name, src) in examples() {
+        validate_wat(&emit_wat(&src, &name));
+    }
+}
+
+#[test]
+fn wat_output_is_deterministic() {
+    for (name, src) in examples() {
+        let (program, bindings, types) = check(&src).unwrap();
+        let map = SourceMap::new(&src);
+        let wat = emit_wat::emit(&program, &bindings, &types, "x.hl2", &map);
+        // Fresh bindings and types, so hash map iteration order differs
+        let (program, bindings, types) = check(&src).unwrap();
+        assert_eq!(emit_wat::emit(&program, &bindings, &types, "x.hl2", &map), wat,
+                   "in {}", name);
+    }
+}
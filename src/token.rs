@@ -1,10 +1,17 @@
+use std;
+
 /// An index into some source code
 #[derive(Ord, Eq, PartialEq, PartialOrd, Debug, Clone, Copy, Hash)]
 pub struct Point(pub usize);
 
 #[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
 pub enum TokenType {
-    Ident, Punc, Key, Op, NumLit, StringLit, BoolLit, CoreType,
+    Ident, Punc, Key, Op, IntLit, FloatLit, StringLit, BoolLit, CoreType,
+    /// A comment. Only kept when lexing with trivia.
+    Comment,
+    /// Source which failed to lex. Stands in for the bad token so parsing can
+    /// carry on.
+    Error,
 }
 
 #[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
@@ -27,8 +34,11 @@ impl<'a> Token {
     pub fn new_op(start: usize, end: usize) -> Token {
         Token { start: Point(start), end: Point(end), token_type: TokenType::Op }
     }
-    pub fn new_num_lit(start: usize, end: usize) -> Token {
-        Token { start: Point(start), end: Point(end), token_type: TokenType::NumLit }
+    pub fn new_int_lit(start: usize, end: usize) -> Token {
+        Token { start: Point(start), end: Point(end), token_type: TokenType::IntLit }
+    }
+    pub fn new_float_lit(start: usize, end: usize) -> Token {
+        Token { start: Point(start), end: Point(end), token_type: TokenType::FloatLit }
     }
     pub fn new_string_lit(start: usize, end: usize) -> Token {
         Token { start: Point(start), end: Point(end), token_type: TokenType::StringLit }
@@ -39,10 +49,40 @@ impl<'a> Token {
     pub fn new_core_type(start: usize, end: usize) -> Token {
         Token { start: Point(start), end: Point(end), token_type: TokenType::CoreType }
     }
+    pub fn new_comment(start: usize, end: usize) -> Token {
+        Token { start: Point(start), end: Point(end), token_type: TokenType::Comment }
+    }
+    pub fn new_error(start: usize, end: usize) -> Token {
+        Token { start: Point(start), end: Point(end), token_type: TokenType::Error }
+    }
+
+    pub fn span(&self) -> Span {
+        Span { start: self.start, end: self.end }
+    }
+
+    pub fn val(&self, src: &'a str) -> &'a str {
+        &src[self.start.0..self.end.0]
+    }
+}
+
+/// A range in some source code, from start (inclusive) to end (exclusive)
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
+pub struct Span {
+    pub start: Point,
+    pub end: Point,
+}
 
-    pub fn val(&'a self, src: &'a str) -> &'a str {
-        unsafe {
-            src.slice_unchecked(self.start.0, self.end.0)
+impl Span {
+    /// Returns a span covering both self and other
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: std::cmp::min(self.start, other.start),
+            end: std::cmp::max(self.end, other.end),
         }
     }
+
+    /// Returns the empty span at the end of src, where EOF errors point
+    pub fn eof(src: &str) -> Span {
+        Span { start: Point(src.len()), end: Point(src.len()) }
+    }
 }
@@ -0,0 +1,466 @@
+use ast::{BinOp, Block, Expr, FieldDef, Ident, Lit, Stmt, Type, UnOp};
+use resolve::Bindings;
+use diagnostic::{self, Diagnostic};
+use token::Span;
+use std::collections::HashMap;
+
+/// The type of every expression, keyed by its span. Only expressions whose
+/// type could be determined are included.
+pub type Types = HashMap<Span, Type>;
+
+/// Infers the type of every expression and checks it against what's expected.
+/// Types are Option<Type>, where None means the type couldn't be determined
+/// (because an error has already been reported), and suppresses further
+/// errors.
+struct Checker<'a> {
+    bindings: &'a Bindings,
+    /// The type of each declared variable, keyed by the span of its name
+    decl_types: HashMap<Span, Type>,
+    /// The parameter and return types of each function, keyed by the span of
+    /// its name
+    fn_sigs: HashMap<Span, (Vec<Type>, Type)>,
+    /// The fields of each struct, by name
+    structs: HashMap<String, Vec<FieldDef>>,
+    /// The return type of the function being checked, or None at the top level
+    ret_ty: Option<Type>,
+    types: Types,
+    errs: Vec<Diagnostic>,
+}
+
+impl<'a> Checker<'a> {
+    fn lookup(&self, name: &Ident) -> Option<Type> {
+        self.bindings.get(&name.span).and_then(|decl| self.decl_types.get(decl)).cloned()
+    }
+
+    fn error(&mut self, diagnostic: Diagnostic) {
+        self.errs.push(diagnostic);
+    }
+
+    /// Returns an error if actual is known and isn't the expected type
+    fn mismatch(expected: &Type, actual: Option<Type>, span: Span) -> Option<Diagnostic> {
+        match actual {
+            Some(ref actual) if actual != expected => Some(Diagnostic::error(
+                format!("Mismatched types: expected {}, found {}",
+                        expected.name(), actual.name()))
+                .primary(span, format!("expected {}", expected.name()))),
+            _ => None,
+        }
+    }
+
+    /// Reports an error if actual is known and isn't the expected type
+    fn expect(&mut self, expected: &Type, actual: Option<Type>, span: Span) {
+        if let Some(err) = Checker::mismatch(expected, actual, span) {
+            self.error(err);
+        }
+    }
+
+    fn check_block(&mut self, block: &Block) {
+        for stmt in block {
+            self.check_stmt(stmt);
+        }
+    }
+
+    fn check_stmt(&mut self, stmt: &Stmt) {
+        match *stmt {
+            Stmt::Declare { ref ty, ref name, ref init, .. } => {
+                let init_ty = self.infer_expecting(init, Some(ty));
+                self.expect(ty, init_ty, init.span());
+                self.decl_types.insert(name.span, ty.clone());
+            }
+            Stmt::Assign { ref target, ref val, .. } => {
+                let target_ty = self.infer(target);
+                let val_ty = self.infer_expecting(val, target_ty.as_ref());
+                let err = match target_ty {
+                    Some(ref target_ty) => Checker::mismatch(target_ty, val_ty, val.span()),
+                    None => None,
+                };
+                if let Some(mut err) = err {
+                    // Point out the declaration when assigning to a variable
+                    if let Expr::Var { ref name } = *target {
+                        let decl = self.bindings[&name.span];
+                        err = err.label(decl, format!("`{}` declared as {} here", name.name,
+                                                      target_ty.unwrap().name()));
+                    }
+                    self.error(err);
+                }
+            }
+            Stmt::Expr { ref expr, .. } => { self.infer(expr); }
+            Stmt::If { ref arms, ref else_body, .. } => {
+                for arm in arms {
+                    let cond_ty = self.infer(&arm.cond);
+                    self.expect(&Type::Bool, cond_ty, arm.cond.span());
+                    self.check_block(&arm.body);
+                }
+                if let Some(ref else_body) = *else_body {
+                    self.check_block(else_body);
+                }
+            }
+            Stmt::While { ref cond, ref body, .. } => {
+                let cond_ty = self.infer(cond);
+                self.expect(&Type::Bool, cond_ty, cond.span());
+                self.check_block(body);
+            }
+            Stmt::For { ref init, ref cond, ref step, ref body, .. } => {
+                if let Some(ref init) = *init {
+                    self.check_stmt(init);
+                }
+                if let Some(ref cond) = *cond {
+                    let cond_ty = self.infer(cond);
+                    self.expect(&Type::Bool, cond_ty, cond.span());
+                }
+                if let Some(ref step) = *step {
+                    self.check_stmt(step);
+                }
+                self.check_block(body);
+            }
+            Stmt::Break { .. } | Stmt::Continue { .. } | Stmt::Struct { .. } => (),
+            Stmt::Fn { ref def } => {
+                for param in &def.params {
+                    self.decl_types.insert(param.name.span, param.ty.clone());
+                }
+                let outer_ret_ty = self.ret_ty.replace(def.ret.clone());
+                self.check_block(&def.body);
+                self.ret_ty = outer_ret_ty;
+                if def.ret != Type::Void && !block_returns(&def.body) {
+                    self.error(Diagnostic::spanned(
+                        format!("Not every path through `{}` returns a value of type {}",
+                                def.name.name, def.ret.name()), def.name.span)
+                        .help("add a `return` at the end of the function".to_owned()));
+                }
+            }
+            Stmt::Return { ref val, span } => {
+                let ret_ty = match self.ret_ty {
+                    Some(ref ret_ty) => ret_ty.clone(),
+                    // Already reported by the resolver
+                    None => return,
+                };
+                match *val {
+                    None if ret_ty != Type::Void => self.error(Diagnostic::spanned(
+                        format!("Expected a return value of type {}", ret_ty.name()), span)),
+                    Some(ref val) if ret_ty == Type::Void => self.error(Diagnostic::spanned(
+                        "Function with no return type cannot return a value".to_owned(),
+                        val.span())
+                        .help("declare a return type with `-> type`".to_owned())),
+                    Some(ref val) => {
+                        let val_ty = self.infer_expecting(val, Some(&ret_ty));
+                        self.expect(&ret_ty, val_ty, val.span());
+                    }
+                    None => (),
+                }
+            }
+        }
+    }
+
+    /// Records the signature of every function, and the fields of every
+    /// struct, defined at the top level
+    fn collect_items(&mut self, program: &Block) {
+        for stmt in program {
+            match *stmt {
+                Stmt::Fn { ref def } => {
+                    let params = def.params.iter().map(|p| p.ty.clone()).collect();
+                    self.fn_sigs.insert(def.name.span, (params, def.ret.clone()));
+                }
+                Stmt::Struct { ref def } => {
+                    self.structs.insert(def.name.name.clone(), def.fields.clone());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Reports a field which isn't in the struct, suggesting the nearest one
+    fn unknown_field(&mut self, struct_name: &str, fields: &[FieldDef], field: &Ident) {
+        let mut err = Diagnostic::spanned(
+            format!("No field `{}` on struct `{}`", field.name, struct_name), field.span);
+        let names = fields.iter().map(|f| &f.name.name[..]);
+        err = match diagnostic::closest_match(&field.name, names.clone()) {
+            Some(similar) => err.help(format!("did you mean `{}`?", similar)),
+            None => err.note(format!("`{}` has fields {}", struct_name,
+                                     names.map(|n| format!("`{}`", n))
+                                     .collect::<Vec<_>>().join(", "))),
+        };
+        self.error(err);
+    }
+
+    /// Checks a struct literal sets every field of the struct exactly once
+    fn infer_struct_lit(&mut self, name: &Ident, fields: &[(Ident, Expr)], span: Span)
+                        -> Option<Type> {
+        // Unknown structs are reported by the resolver
+        let defs = self.structs.get(&name.name).cloned()?;
+        let mut set: HashMap<&str, Span> = HashMap::new();
+        for (field, val) in fields {
+            let field_ty = defs.iter().find(|f| f.name.name == field.name).map(|f| f.ty.clone());
+            let val_ty = self.infer_expecting(val, field_ty.as_ref());
+            match field_ty {
+                Some(ref field_ty) => self.expect(field_ty, val_ty, val.span()),
+                None => self.unknown_field(&name.name, &defs, field),
+            }
+            if let Some(&prev) = set.get(&field.name[..]) {
+                self.error(Diagnostic::spanned(
+                    format!("Field `{}` set more than once", field.name), field.span)
+                    .label(prev, "first set here".to_owned()));
+            } else {
+                set.insert(&field.name, field.span);
+            }
+        }
+        let missing: Vec<_> = defs.iter()
+            .filter(|f| !set.contains_key(&f.name.name[..]))
+            .map(|f| format!("`{}`", f.name.name))
+            .collect();
+        if !missing.is_empty() {
+            self.error(Diagnostic::spanned(
+                format!("Missing {} {} in `{}` literal",
+                        if missing.len() == 1 { "field" } else { "fields" },
+                        missing.join(", "), name.name), span));
+        }
+        Some(Type::Struct(name.name.clone()))
+    }
+
+    /// Checks the arguments to a builtin function, returning its result type
+    fn check_builtin_call(&mut self, callee: &Ident, arg_tys: &[Option<Type>], args: &[Expr],
+                          span: Span) -> Option<Type> {
+        match &callee.name[..] {
+            "print" | "println" => {
+                for (arg_ty, arg) in arg_tys.iter().zip(args) {
+                    match *arg_ty {
+                        Some(Type::Void) => self.error(Diagnostic::spanned(
+                            "Cannot use the result of a function returning nothing"
+                                .to_owned(), arg.span())),
+                        Some(ref ty @ Type::Array(_)) => self.error(Diagnostic::spanned(
+                            format!("Cannot print a value of type {}", ty.name()), arg.span())
+                            .help("print the elements one at a time".to_owned())),
+                        Some(ref ty @ Type::Struct(_)) => self.error(Diagnostic::spanned(
+                            format!("Cannot print a value of type {}", ty.name()), arg.span())
+                            .help("print the fields one at a time".to_owned())),
+                        _ => (),
+                    }
+                }
+                Some(Type::Void)
+            }
+            "len" => {
+                if args.len() != 1 {
+                    self.error(Diagnostic::spanned(
+                        format!("Function `len` takes 1 argument but {} were given",
+                                args.len()), span));
+                } else {
+                    match arg_tys[0] {
+                        Some(Type::Array(_)) | None => (),
+                        Some(ref ty) => self.error(Diagnostic::spanned(
+                            format!("`len` expects an array, found {}", ty.name()),
+                            args[0].span())),
+                    }
+                }
+                Some(Type::Int)
+            }
+            _ => None,
+        }
+    }
+
+    /// Infers the type of expr, using the type it's expected to have to give
+    /// array literals a type even if they're empty
+    fn infer_expecting(&mut self, expr: &Expr, expected: Option<&Type>) -> Option<Type> {
+        let ty = match *expr {
+            Expr::Array { ref elems, span } => self.infer_array(elems, span, expected),
+            _ => self.infer_unrecorded(expr),
+        };
+        if let Some(ref ty) = ty {
+            self.types.insert(expr.span(), ty.clone());
+        }
+        ty
+    }
+
+    /// Infers the type of an array literal. Every element must have the same
+    /// type, which is taken from expected if given, or else the first element.
+    fn infer_array(&mut self, elems: &[Expr], span: Span, expected: Option<&Type>)
+                   -> Option<Type> {
+        let mut elem_ty = match expected {
+            Some(Type::Array(elem_ty)) => Some((**elem_ty).clone()),
+            _ => None,
+        };
+        if elem_ty.is_none() && elems.is_empty() {
+            self.error(Diagnostic::spanned(
+                "Cannot infer the type of an empty array".to_owned(), span)
+                .help("declare it with a type, e.g. `int[] a = [];`".to_owned()));
+            return None;
+        }
+        for elem in elems {
+            let ty = self.infer_expecting(elem, elem_ty.as_ref());
+            match elem_ty {
+                Some(ref elem_ty) => self.expect(elem_ty, ty, elem.span()),
+                None => elem_ty = ty,
+            }
+        }
+        elem_ty.map(|elem_ty| Type::Array(Box::new(elem_ty)))
+    }
+
+    fn infer(&mut self, expr: &Expr) -> Option<Type> {
+        self.infer_expecting(expr, None)
+    }
+
+    /// Infers the type of an expression, without recording it in types
+    fn infer_unrecorded(&mut self, expr: &Expr) -> Option<Type> {
+        match *expr {
+            Expr::Var { ref name } => self.lookup(name),
+            Expr::Lit { ref val, .. } => Some(match *val {
+                Lit::Int(_) => Type::Int,
+                Lit::Float(_) => Type::Float,
+                Lit::Str(_) => Type::String,
+                Lit::Bool(_) => Type::Bool,
+            }),
+            Expr::Unary { op, op_span, ref operand, .. } => {
+                let operand_ty = self.infer(operand)?;
+                let res = unary_op_type(op, &operand_ty);
+                if res.is_none() {
+                    self.error(Diagnostic::spanned(
+                        format!("Operator `{}` cannot be applied to {}",
+                                op.symbol(), operand_ty.name()), op_span)
+                        .label(operand.span(), operand_ty.name()));
+                }
+                res
+            }
+            Expr::Binary { op, op_span, ref lhs, ref rhs, .. } => {
+                let lhs_ty = self.infer(lhs)?;
+                let rhs_ty = self.infer(rhs)?;
+                let res = binary_op_type(op, &lhs_ty, &rhs_ty);
+                if res.is_none() {
+                    self.error(Diagnostic::spanned(
+                        format!("Operator `{}` cannot be applied to {} and {}",
+                                op.symbol(), lhs_ty.name(), rhs_ty.name()), op_span)
+                        .label(lhs.span(), lhs_ty.name())
+                        .label(rhs.span(), rhs_ty.name()));
+                }
+                res
+            }
+            Expr::Call { ref callee, ref args, span } => {
+                let sig = self.bindings.get(&callee.span)
+                    .and_then(|decl| self.fn_sigs.get(decl)).cloned();
+                match sig {
+                    Some((params, ret)) => {
+                        if params.len() != args.len() {
+                            let decl = self.bindings[&callee.span];
+                            self.error(Diagnostic::spanned(
+                                format!("Function `{}` takes {} arguments but {} were given",
+                                        callee.name, params.len(), args.len()), span)
+                                .label(decl, "defined here".to_owned()));
+                        }
+                        for (i, arg) in args.iter().enumerate() {
+                            let param = params.get(i);
+                            let arg_ty = self.infer_expecting(arg, param);
+                            if let Some(param) = param {
+                                self.expect(param, arg_ty, arg.span());
+                            }
+                        }
+                        Some(ret)
+                    }
+                    None => {
+                        let arg_tys: Vec<_> = args.iter().map(|a| self.infer(a)).collect();
+                        self.check_builtin_call(callee, &arg_tys, args, span)
+                    }
+                }
+            }
+            Expr::Array { ref elems, span } => self.infer_array(elems, span, None),
+            Expr::StructLit { ref name, ref fields, span } =>
+                self.infer_struct_lit(name, fields, span),
+            Expr::Field { ref base, ref field, .. } => {
+                let base_ty = self.infer(base)?;
+                let defs = match base_ty {
+                    Type::Struct(ref name) => self.structs.get(name).cloned(),
+                    _ => None,
+                };
+                let defs = match defs {
+                    Some(defs) => defs,
+                    None => {
+                        self.error(Diagnostic::spanned(
+                            format!("Type {} has no fields", base_ty.name()), field.span)
+                            .label(base.span(), base_ty.name()));
+                        return None;
+                    }
+                };
+                match defs.iter().find(|f| f.name.name == field.name) {
+                    Some(def) => Some(def.ty.clone()),
+                    None => {
+                        self.unknown_field(&base_ty.name(), &defs, field);
+                        None
+                    }
+                }
+            }
+            Expr::Index { ref base, ref index, .. } => {
+                let index_ty = self.infer(index);
+                self.expect(&Type::Int, index_ty, index.span());
+                match self.infer(base)? {
+                    Type::Array(elem_ty) => Some(*elem_ty),
+                    base_ty => {
+                        self.error(Diagnostic::spanned(
+                            format!("Cannot index into a value of type {}", base_ty.name()),
+                            base.span()));
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns true if executing block always ends in a return
+fn block_returns(block: &Block) -> bool {
+    block.iter().any(|stmt| match *stmt {
+        Stmt::Return { .. } => true,
+        Stmt::If { ref arms, else_body: Some(ref else_body), .. } =>
+            arms.iter().all(|arm| block_returns(&arm.body)) && block_returns(else_body),
+        _ => false,
+    })
+}
+
+/// Returns the type of `op operand`, or None if op can't be applied to this
+/// type
+fn unary_op_type(op: UnOp, operand: &Type) -> Option<Type> {
+    match (op, operand) {
+        (UnOp::Neg, &Type::Int) | (UnOp::Neg, &Type::Float) | (UnOp::Not, &Type::Bool) =>
+            Some(operand.clone()),
+        _ => None,
+    }
+}
+
+/// Returns the type of `lhs op rhs`, or None if op can't be applied to these
+/// types
+fn binary_op_type(op: BinOp, lhs: &Type, rhs: &Type) -> Option<Type> {
+    if lhs != rhs {
+        return None;
+    }
+    match (op, lhs) {
+        (BinOp::Add, Type::String) => Some(Type::String),
+        (BinOp::Add, Type::Int) | (BinOp::Sub, Type::Int) | (BinOp::Mul, Type::Int) |
+        (BinOp::Div, Type::Int) | (BinOp::Mod, Type::Int) => Some(Type::Int),
+        (BinOp::Add, Type::Float) | (BinOp::Sub, Type::Float) | (BinOp::Mul, Type::Float) |
+        (BinOp::Div, Type::Float) | (BinOp::Mod, Type::Float) => Some(Type::Float),
+        // Arrays and structs are shared by reference, so `==` would be
+        // ambiguous between comparing identity and contents
+        (BinOp::Eq, Type::Void) | (BinOp::Ne, Type::Void) |
+        (BinOp::Eq, Type::Array(_)) | (BinOp::Ne, Type::Array(_)) |
+        (BinOp::Eq, Type::Struct(_)) | (BinOp::Ne, Type::Struct(_)) => None,
+        (BinOp::Eq, _) | (BinOp::Ne, _) => Some(Type::Bool),
+        (BinOp::Gt, Type::Int) | (BinOp::Lt, Type::Int) |
+        (BinOp::Ge, Type::Int) | (BinOp::Le, Type::Int) |
+        (BinOp::Gt, Type::Float) | (BinOp::Lt, Type::Float) |
+        (BinOp::Ge, Type::Float) | (BinOp::Le, Type::Float) => Some(Type::Bool),
+        (BinOp::And, Type::Bool) | (BinOp::Or, Type::Bool) => Some(Type::Bool),
+        _ => None,
+    }
+}
+
+/// Type checks a resolved program, returning the type of every expression and
+/// every error found
+pub fn check(program: &Block, bindings: &Bindings) -> (Types, Vec<Diagnostic>) {
+    let mut checker = Checker {
+        bindings,
+        decl_types: HashMap::new(),
+        fn_sigs: HashMap::new(),
+        structs: HashMap::new(),
+        ret_ty: None,
+        types: HashMap::new(),
+        errs: Vec::new(),
+    };
+    checker.collect_items(program);
+    checker.check_block(program);
+    (checker.types, checker.errs)
+}
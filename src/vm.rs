@@ -0,0 +1,190 @@
+use bytecode::{Builtin, Function, Op, Program};
+use interpret::{self, ArrayRef, RuntimeErr, StructVal, Value, MAX_CALL_DEPTH};
+use token::Span;
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+/// Where to resume a function which made a call
+struct Frame<'a> {
+    f: &'a Function,
+    ip: usize,
+    /// The stack index of the function's first local
+    base: usize,
+}
+
+/// Executes bytecode on a stack of values. Each call's locals live on the
+/// stack, below its temporaries.
+pub struct Vm<'a, W: Write> {
+    out: W,
+    program: &'a Program,
+    stack: Vec<Value>,
+    /// The callers of the running function, innermost last
+    frames: Vec<Frame<'a>>,
+}
+
+impl<'a, W: Write> Vm<'a, W> {
+    pub fn new(program: &'a Program, out: W) -> Vm<'a, W> {
+        Vm { out, program, stack: Vec::new(), frames: Vec::new() }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("Popped an empty stack")
+    }
+
+    /// Pops the last n values, in the order they were pushed
+    fn pop_n(&mut self, n: usize) -> Vec<Value> {
+        let len = self.stack.len();
+        self.stack.split_off(len - n)
+    }
+
+    fn call_builtin(&mut self, builtin: Builtin, args: Vec<Value>) -> Result<Value, String> {
+        match builtin {
+            Builtin::Print | Builtin::Println => {
+                let res = args.iter()
+                    .try_for_each(|a| write!(self.out, "{}", a))
+                    .and_then(|_| if builtin == Builtin::Println { writeln!(self.out) }
+                              else { Ok(()) });
+                res.map_err(|e| format!("Failed to write output: {}", e))?;
+                Ok(Value::Void)
+            }
+            Builtin::Len => match args.first() {
+                Some(Value::Array(elems)) => Ok(Value::Int(elems.borrow().len() as i64)),
+                _ => Err("`len` expects an array".to_owned()),
+            },
+        }
+    }
+
+    /// Runs the program from its first function until it returns
+    pub fn run(&mut self) -> Result<(), RuntimeErr> {
+        let mut f = &self.program.functions[0];
+        let mut ip = 0;
+        let mut base = 0;
+        self.stack.resize(f.locals, Value::Void);
+        loop {
+            let op = f.code[ip];
+            let span = f.spans[ip];
+            let err = |msg: String| RuntimeErr::Point(msg, span);
+            ip += 1;
+            match op {
+                Op::Const(ix) => self.stack.push(f.constants[ix as usize].clone()),
+                Op::Pop => { self.pop(); }
+                Op::Dup => {
+                    let top = self.stack.last().unwrap().clone();
+                    self.stack.push(top);
+                }
+                Op::GetLocal(slot) => {
+                    let val = self.stack[base + slot as usize].clone();
+                    self.stack.push(val);
+                }
+                Op::SetLocal(slot) => {
+                    let val = self.pop();
+                    self.stack[base + slot as usize] = val;
+                }
+                Op::Unary(op) => {
+                    let operand = self.pop();
+                    self.stack.push(interpret::unary_op(op, span, operand)?);
+                }
+                Op::Binary(op) => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    self.stack.push(interpret::binary_op(lhs, op, span, rhs)?);
+                }
+                Op::Jump(to) => ip = to as usize,
+                Op::JumpIfFalse(to) | Op::JumpIfTrue(to) => match self.pop() {
+                    Value::Bool(b) => if b == (op == Op::JumpIfTrue(to)) { ip = to as usize },
+                    v => return Err(err(format!("Condition must be a bool, got {}",
+                                                v.type_name()))),
+                },
+                Op::Call(ix, argc) => {
+                    if self.frames.len() == MAX_CALL_DEPTH {
+                        let callee = &self.program.functions[ix as usize];
+                        return Err(err(format!("Stack overflow calling `{}`", callee.name)));
+                    }
+                    self.frames.push(Frame { f, ip, base });
+                    f = &self.program.functions[ix as usize];
+                    ip = 0;
+                    base = self.stack.len() - argc as usize;
+                    self.stack.resize(base + f.locals, Value::Void);
+                }
+                Op::CallBuiltin(builtin, argc) => {
+                    let args = self.pop_n(argc as usize);
+                    let res = self.call_builtin(builtin, args).map_err(err)?;
+                    self.stack.push(res);
+                }
+                Op::Return => {
+                    let val = self.pop();
+                    self.stack.truncate(base);
+                    match self.frames.pop() {
+                        Some(frame) => {
+                            f = frame.f;
+                            ip = frame.ip;
+                            base = frame.base;
+                            self.stack.push(val);
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                Op::MakeArray(len) => {
+                    let elems = self.pop_n(len as usize);
+                    self.stack.push(Value::Array(Rc::new(RefCell::new(elems))));
+                }
+                Op::Index => {
+                    let (elems, ix) = self.pop_index(span)?;
+                    let elem = elems.borrow()[ix].clone();
+                    self.stack.push(elem);
+                }
+                Op::SetIndex => {
+                    let (elems, ix) = self.pop_index(span)?;
+                    elems.borrow_mut()[ix] = self.pop();
+                }
+                Op::MakeStruct(ix) => {
+                    let layout = &self.program.layouts[ix as usize];
+                    let mut fields: Vec<_> = layout.fields.iter()
+                        .map(|name| (name.clone(), Value::Void)).collect();
+                    for (val, &field) in self.pop_n(layout.order.len()).into_iter()
+                        .zip(&layout.order) {
+                        fields[field].1 = val;
+                    }
+                    self.stack.push(Value::Struct(Rc::new(StructVal {
+                        name: layout.name.clone(),
+                        fields: RefCell::new(fields),
+                    })));
+                }
+                Op::GetField(name) | Op::SetField(name) => {
+                    let s = match self.pop() {
+                        Value::Struct(s) => s,
+                        v => return Err(err(format!("Cannot access a field of {}",
+                                                    v.type_name()))),
+                    };
+                    let name = match f.constants[name as usize] {
+                        Value::Str(ref name) => name,
+                        ref c => panic!("Field name must be a string constant, got {:?}", c),
+                    };
+                    let field = s.field_index(name, span)?;
+                    if let Op::GetField(_) = op {
+                        let val = s.fields.borrow()[field].1.clone();
+                        self.stack.push(val);
+                    } else {
+                        s.fields.borrow_mut()[field].1 = self.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pops an index and an array, checking the index is in bounds
+    fn pop_index(&mut self, span: Span) -> Result<(ArrayRef, usize), RuntimeErr> {
+        let ix = self.pop();
+        let elems = self.pop();
+        match (elems, ix) {
+            (Value::Array(elems), Value::Int(ix)) => {
+                let ix = interpret::check_bounds(&elems, ix, span)?;
+                Ok((elems, ix))
+            }
+            (elems, ix) => Err(RuntimeErr::Point(
+                format!("Cannot index into {} with {}", elems.type_name(), ix.type_name()),
+                span)),
+        }
+    }
+}